@@ -0,0 +1,9 @@
+-- Add down migration script here
+ALTER TABLE receipts 
+DROP COLUMN duplicate_of;
+
+ALTER TABLE receipts 
+DROP COLUMN total;
+
+ALTER TABLE receipts 
+ADD CONSTRAINT unique_paid_at UNIQUE (paid_at);
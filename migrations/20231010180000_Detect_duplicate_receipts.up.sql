@@ -0,0 +1,9 @@
+-- Add up migration script here
+ALTER TABLE receipts 
+DROP CONSTRAINT unique_paid_at;
+
+ALTER TABLE receipts 
+ADD COLUMN total float;
+
+ALTER TABLE receipts 
+ADD COLUMN duplicate_of int references receipts (id) ON DELETE SET NULL;
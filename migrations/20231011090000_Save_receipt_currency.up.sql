@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE receipts 
+ADD COLUMN currency_code text;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE prices 
+DROP COLUMN quantity_defaulted;
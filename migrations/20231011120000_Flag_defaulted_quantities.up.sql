@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE prices 
+ADD COLUMN quantity_defaulted boolean not null default false;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE receipts 
+ADD COLUMN needs_review boolean not null default false;
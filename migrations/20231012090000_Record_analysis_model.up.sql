@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE receipts 
+ADD COLUMN model_id text;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE receipts 
+ADD COLUMN time_precision text not null default 'time';
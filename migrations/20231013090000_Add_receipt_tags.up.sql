@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE receipt_tags (
+    receipt_id int not null,
+    tag text not null,
+    foreign key (receipt_id) references receipts (id) ON DELETE CASCADE,
+    primary key (receipt_id, tag)
+);
+
+CREATE INDEX receipt_tags_tag_idx ON receipt_tags (tag);
@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE tax_lines (
+    id serial primary key,
+    receipt_id int not null,
+    rate float,
+    net_amount float,
+    amount float not null,
+    foreign key (receipt_id) references receipts (id) ON DELETE CASCADE
+);
+
+CREATE INDEX tax_lines_receipt_id_idx ON tax_lines (receipt_id);
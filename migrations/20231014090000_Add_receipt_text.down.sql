@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX receipts_text_search_idx;
+ALTER TABLE receipts DROP COLUMN receipt_text_search;
+ALTER TABLE receipts DROP COLUMN receipt_text;
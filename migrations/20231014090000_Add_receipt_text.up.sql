@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE receipts ADD COLUMN receipt_text text;
+-- Receipts mix Danish and English, so words are indexed as printed rather than stemmed
+ALTER TABLE receipts ADD COLUMN receipt_text_search tsvector GENERATED ALWAYS AS (to_tsvector('simple', coalesce(receipt_text, ''))) STORED;
+
+CREATE INDEX receipts_text_search_idx ON receipts USING GIN (receipt_text_search);
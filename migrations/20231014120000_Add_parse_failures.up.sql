@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE parse_failures (
+    file_sha256 text primary key,
+    field_path text not null,
+    error text not null,
+    response text not null,
+    failed_at timestamptz not null default now()
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DELETE FROM prices WHERE unit_price IS NULL;
+ALTER TABLE prices DROP COLUMN price_missing;
+ALTER TABLE prices ALTER COLUMN unit_price SET NOT NULL;
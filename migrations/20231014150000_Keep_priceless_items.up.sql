@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE prices ALTER COLUMN unit_price DROP NOT NULL;
+ALTER TABLE prices ADD COLUMN price_missing boolean not null default false;
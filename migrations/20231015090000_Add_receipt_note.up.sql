@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE receipts ADD COLUMN note text;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE prices ADD COLUMN quantity_unit text;
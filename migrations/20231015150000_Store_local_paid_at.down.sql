@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE receipts DROP COLUMN time_zone;
+ALTER TABLE receipts DROP COLUMN local_paid_at;
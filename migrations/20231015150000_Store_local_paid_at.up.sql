@@ -0,0 +1,7 @@
+-- Add up migration script here
+ALTER TABLE receipts ADD COLUMN local_paid_at timestamp;
+ALTER TABLE receipts ADD COLUMN time_zone text;
+-- Every receipt so far was localized to Copenhagen time
+UPDATE receipts SET local_paid_at = paid_at AT TIME ZONE 'Europe/Copenhagen', time_zone = 'Europe/Copenhagen';
+ALTER TABLE receipts ALTER COLUMN local_paid_at SET NOT NULL;
+ALTER TABLE receipts ALTER COLUMN time_zone SET NOT NULL;
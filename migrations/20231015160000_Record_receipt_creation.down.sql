@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX receipts_created_at_idx;
+ALTER TABLE receipts DROP COLUMN created_at;
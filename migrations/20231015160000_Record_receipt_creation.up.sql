@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE receipts ADD COLUMN created_at timestamptz not null default now();
+CREATE INDEX receipts_created_at_idx ON receipts (created_at);
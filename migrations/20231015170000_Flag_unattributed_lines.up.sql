@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE prices ADD COLUMN unattributed boolean not null default false;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE tax_lines DROP COLUMN currency_code;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE tax_lines ADD COLUMN currency_code text;
+UPDATE tax_lines SET currency_code = receipts.currency_code FROM receipts WHERE receipts.id = tax_lines.receipt_id;
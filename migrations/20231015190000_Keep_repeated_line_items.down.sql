@@ -0,0 +1,6 @@
+-- Add down migration script here
+DELETE FROM prices USING prices AS kept
+WHERE prices.receipt_id = kept.receipt_id AND prices.product_id = kept.product_id AND prices.line_index > kept.line_index;
+ALTER TABLE prices DROP CONSTRAINT prices_pkey;
+ALTER TABLE prices ADD CONSTRAINT prices_pkey PRIMARY KEY (product_id, receipt_id);
+ALTER TABLE prices DROP COLUMN line_index;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+ALTER TABLE prices ADD COLUMN line_index int;
+UPDATE prices SET line_index = numbered.line_index
+FROM (
+    SELECT receipt_id, product_id, ROW_NUMBER() OVER (PARTITION BY receipt_id ORDER BY product_id) - 1 AS line_index
+    FROM prices
+) numbered
+WHERE prices.receipt_id = numbered.receipt_id AND prices.product_id = numbered.product_id;
+ALTER TABLE prices ALTER COLUMN line_index SET NOT NULL;
+ALTER TABLE prices DROP CONSTRAINT prices_pkey;
+ALTER TABLE prices ADD CONSTRAINT prices_pkey PRIMARY KEY (receipt_id, line_index);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE receipts DROP COLUMN total_tax;
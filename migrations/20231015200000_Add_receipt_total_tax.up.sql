@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE receipts ADD COLUMN total_tax double precision;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE item_regions (
+    id serial primary key,
+    receipt_id int not null,
+    line_index int not null,
+    page_number int not null,
+    polygon jsonb not null,
+    foreign key (receipt_id, line_index) references prices (receipt_id, line_index) ON DELETE CASCADE
+);
+
+CREATE INDEX item_regions_line_idx ON item_regions (receipt_id, line_index);
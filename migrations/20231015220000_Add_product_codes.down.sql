@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX products_product_code_key;
+ALTER TABLE products DROP COLUMN product_code;
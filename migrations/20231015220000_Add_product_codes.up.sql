@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE products ADD COLUMN product_code text;
+CREATE UNIQUE INDEX products_product_code_key ON products(product_code);
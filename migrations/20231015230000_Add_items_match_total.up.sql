@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE receipts ADD COLUMN items_match_total boolean;
+UPDATE receipts SET items_match_total = COALESCE(
+    (SELECT bool_and(prices.unit_price IS NOT NULL) AND abs(receipts.total - SUM(round((prices.count * prices.unit_price)::numeric, 2))::float8) < 0.005 FROM prices WHERE prices.receipt_id = receipts.id),
+    abs(receipts.total) < 0.005
+);
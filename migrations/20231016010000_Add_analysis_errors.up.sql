@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE analysis_errors (
+    id serial primary key,
+    file_sha256 text not null,
+    error text not null,
+    occurred_at timestamptz not null default now()
+);
+CREATE INDEX analysis_errors_occurred_at_idx ON analysis_errors (occurred_at DESC);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX products_name_trgm_idx;
+DROP EXTENSION IF EXISTS pg_trgm;
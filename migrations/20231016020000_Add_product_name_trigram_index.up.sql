@@ -0,0 +1,3 @@
+-- Add up migration script here
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE INDEX products_name_trgm_idx ON products USING gin (name gin_trgm_ops);
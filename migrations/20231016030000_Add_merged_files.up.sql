@@ -0,0 +1,6 @@
+-- Add up migration script here
+CREATE TABLE merged_files (
+    file_sha256 text primary key,
+    receipt_id int not null references receipts (id) ON DELETE CASCADE,
+    merged_at timestamptz not null default now()
+);
@@ -0,0 +1,6 @@
+-- Add up migration script here
+CREATE TABLE google_imports (
+    file_sha256 text primary key,
+    payload bytea not null,
+    imported_at timestamptz not null default now()
+);
@@ -0,0 +1,85 @@
+//! Failures of the background processing of a file, which has no request to answer with its error, kept so they can be looked up without the logs.
+
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use serde::Serialize;
+
+use crate::{
+    file_hash::FileHash,
+    pagination::{Listing, Page, PageParams},
+    AppError, AppState,
+};
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AnalysisError {
+    pub file_hash: FileHash,
+    pub error: String,
+    pub occurred_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Stores why processing the file failed. Failing to store it is only logged, like the error itself used to be.
+pub async fn record(app_state: &AppState, file_hash: &FileHash, err: &AppError) {
+    let res = sqlx::query!(
+        "INSERT INTO analysis_errors(file_sha256, error) VALUES ($1, $2)",
+        file_hash.as_str(),
+        err.to_string()
+    )
+    .execute(&app_state.pool)
+    .await;
+    if let Err(db_err) = res {
+        tracing::error!(
+            "Could not record the failure of file {}: {}",
+            file_hash,
+            db_err
+        );
+    }
+}
+
+/// Lists the failures of background processing, newest first. A file failing repeatedly is listed once per failure.
+pub async fn recent_errors(
+    State(app_state): State<Arc<AppState>>,
+    Query(page): Query<PageParams>,
+) -> Result<axum::Json<Listing<AnalysisError>>, AppError> {
+    let pool = &app_state.pool;
+    let total = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM analysis_errors"#)
+        .fetch_one(pool)
+        .await?;
+    if page.count_only {
+        return Ok(axum::Json(Listing::Count { total }));
+    }
+    let errors = sqlx::query_as!(
+        AnalysisError,
+        r#"SELECT file_sha256 AS "file_hash: FileHash", error, occurred_at FROM analysis_errors ORDER BY occurred_at DESC, id DESC LIMIT $1 OFFSET $2"#,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(axum::Json(Listing::Page(Page::new(errors, total, page))))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn list_error_with_file_hash() {
+        let file_hash = FileHash::of(b"receipt");
+        assert_eq!(
+            serde_json::to_value(AnalysisError {
+                file_hash: file_hash.clone(),
+                error: "Azure failed to analyze the file".into(),
+                occurred_at: chrono::Utc.with_ymd_and_hms(2023, 10, 16, 8, 0, 0).unwrap(),
+            })
+            .unwrap(),
+            serde_json::json!({
+                "file_hash": file_hash.as_str(),
+                "error": "Azure failed to analyze the file",
+                "occurred_at": "2023-10-16T08:00:00Z",
+            })
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Returned when no analysis slot could be obtained; `retry_after` estimates when one is likely to free up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Busy {
+    pub retry_after: Duration,
+}
+
+/// Bounds how many analyses run against Azure at once, letting a limited number of uploads wait for a slot.
+pub struct AnalysisQueue {
+    permits: Arc<Semaphore>,
+    concurrency: usize,
+    waiting: AtomicUsize,
+    max_queue_depth: usize,
+    queue_timeout: Duration,
+    estimated_analysis_time: Duration,
+}
+
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl AnalysisQueue {
+    pub fn new(
+        concurrency: usize,
+        max_queue_depth: usize,
+        queue_timeout: Duration,
+        estimated_analysis_time: Duration,
+    ) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
+            waiting: AtomicUsize::new(0),
+            max_queue_depth,
+            queue_timeout,
+            estimated_analysis_time,
+        }
+    }
+
+    /// Takes a free slot right away, or waits for one if the backlog has room. The permit must be held until the analysis is fully processed.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Busy> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        let ahead = self.waiting.fetch_add(1, Ordering::SeqCst);
+        let _guard = WaitingGuard(&self.waiting);
+        if ahead >= self.max_queue_depth {
+            return Err(self.busy());
+        }
+        match tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(self.busy()),
+        }
+    }
+
+    /// Number of uploads currently waiting for a slot.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    // Called while the rejected upload still counts as waiting, so it is included in the backlog estimate
+    fn busy(&self) -> Busy {
+        let rounds = self.waiting().max(1).div_ceil(self.concurrency.max(1));
+        Busy {
+            retry_after: self.estimated_analysis_time * rounds as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::AnalysisQueue;
+
+    const ANALYSIS_TIME: Duration = Duration::from_secs(30);
+
+    #[tokio::test]
+    async fn reject_immediately_when_saturated_without_backlog() {
+        let queue = AnalysisQueue::new(1, 0, Duration::from_secs(5), ANALYSIS_TIME);
+        let _running = queue.acquire().await.unwrap();
+        let busy = queue.acquire().await.unwrap_err();
+        assert_eq!(busy.retry_after, ANALYSIS_TIME);
+    }
+
+    #[tokio::test]
+    async fn time_out_waiting_in_backlog() {
+        let queue = AnalysisQueue::new(1, 4, Duration::from_millis(20), ANALYSIS_TIME);
+        let _running = queue.acquire().await.unwrap();
+        assert!(queue.acquire().await.is_err());
+        assert_eq!(queue.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_upload_gets_slot_once_freed() {
+        let queue = AnalysisQueue::new(1, 4, Duration::from_secs(5), ANALYSIS_TIME);
+        let running = queue.acquire().await.unwrap();
+        let (queued, _) = tokio::join!(queue.acquire(), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(running);
+        });
+        assert!(queued.is_ok());
+    }
+}
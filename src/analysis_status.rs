@@ -0,0 +1,107 @@
+//! Progress of a submitted file, keyed by its hash, so clients can poll without ever seeing Azure's URLs.
+
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use serde::Serialize;
+
+use crate::{
+    cache::{AnalysisFailure, CachedAnalysis},
+    cached_hashes,
+    file_hash::FileHash,
+    load_cached, AppError, AppState,
+};
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AnalysisStatus {
+    /// Just submitted to Azure
+    Queued,
+    /// Azure has not finished analyzing the file yet
+    Pending,
+    Processed {
+        /// `None` when no receipt could be extracted from the analysis
+        receipt_id: Option<i32>,
+    },
+    Failed {
+        #[serde(flatten)]
+        failure: AnalysisFailure,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FileStatus {
+    pub file_hash: FileHash,
+    #[serde(flatten)]
+    pub status: AnalysisStatus,
+}
+
+pub async fn analysis_status(
+    State(app_state): State<Arc<AppState>>,
+    Path(file_hash): Path<FileHash>,
+) -> Result<axum::Json<FileStatus>, AppError> {
+    if !cached_hashes(&app_state)?.contains(&file_hash) {
+        return Err(AppError::not_found(format!(
+            "File {file_hash} was never submitted"
+        )));
+    }
+    let status = match load_cached(&app_state, &file_hash).await? {
+        CachedAnalysis::Pending => AnalysisStatus::Pending,
+        CachedAnalysis::Failed(failure) => AnalysisStatus::Failed { failure },
+        CachedAnalysis::Done(_) => AnalysisStatus::Processed {
+            // A file merged into another receipt has that receipt's
+            receipt_id: sqlx::query_scalar!(
+                "SELECT COALESCE((SELECT id FROM receipts WHERE file_sha256 = $1), (SELECT receipt_id FROM merged_files WHERE file_sha256 = $1))",
+                file_hash.as_str()
+            )
+            .fetch_one(&app_state.pool)
+            .await?,
+        },
+    };
+    Ok(axum::Json(FileStatus { file_hash, status }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    #[test]
+    fn report_status_with_file_hash() {
+        let file_hash = FileHash::of(b"receipt");
+        let status = |status| {
+            serde_json::to_value(FileStatus {
+                file_hash: file_hash.clone(),
+                status,
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            status(AnalysisStatus::Queued),
+            serde_json::json!({"file_hash": file_hash.as_str(), "status": "queued"})
+        );
+        assert_eq!(
+            status(AnalysisStatus::Processed {
+                receipt_id: Some(7)
+            }),
+            serde_json::json!({"file_hash": file_hash.as_str(), "status": "processed", "receipt_id": 7})
+        );
+        assert_eq!(
+            status(AnalysisStatus::Failed {
+                failure: AnalysisFailure {
+                    code: "InvalidContent".into(),
+                    message: "The file is corrupted".into(),
+                    failed_at: DateTime::parse_from_rfc3339("2023-10-12T08:00:00Z").unwrap(),
+                }
+            }),
+            serde_json::json!({
+                "file_hash": file_hash.as_str(),
+                "status": "failed",
+                "code": "InvalidContent",
+                "message": "The file is corrupted",
+                "failed_at": "2023-10-12T08:00:00Z"
+            })
+        );
+    }
+}
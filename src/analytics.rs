@@ -0,0 +1,374 @@
+//! Views over line items for spotting unusual purchases, and over spending for charting it.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::extract::{Path, Query, State};
+use chrono::{Datelike, Days, Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppError, AppState};
+
+const DEFAULT_TOP_ITEMS: i64 = 10;
+const MAX_TOP_ITEMS: i64 = 100;
+/// Longest timeline served at once; longer spans are fetched page by page through `from` and `to`.
+const MAX_TIMELINE_BUCKETS: usize = 400;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TopItemsParams {
+    pub limit: Option<i64>,
+    /// First day to include, in the receipt timezone
+    pub from: Option<chrono::NaiveDate>,
+    /// Last day to include, in the receipt timezone
+    pub to: Option<chrono::NaiveDate>,
+}
+
+impl TopItemsParams {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_TOP_ITEMS)
+            .clamp(1, MAX_TOP_ITEMS)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopItem {
+    pub receipt_id: i32,
+    pub merchant_name: String,
+    pub paid_at: chrono::DateTime<chrono::Utc>,
+    pub name: String,
+    pub count: f64,
+    pub unit_price: f64,
+    /// `count * unit_price`
+    pub line_total: f64,
+}
+
+/// The line items that cost the most in total, most expensive first. Items without a price and flagged duplicates are left out.
+pub async fn top_items(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<TopItemsParams>,
+) -> Result<axum::Json<Vec<TopItem>>, AppError> {
+    let items = sqlx::query_as!(
+        TopItem,
+        r#"SELECT receipts.id AS receipt_id, receipts.merchant_name, receipts.paid_at, products.name, prices.count, prices.unit_price AS "unit_price!", prices.count * prices.unit_price AS "line_total!" FROM receipts JOIN prices ON receipts.id = prices.receipt_id JOIN products ON products.id = prices.product_id WHERE prices.unit_price IS NOT NULL AND receipts.duplicate_of IS NULL AND ($1::date IS NULL OR (receipts.paid_at AT TIME ZONE $4)::date >= $1) AND ($2::date IS NULL OR (receipts.paid_at AT TIME ZONE $4)::date <= $2) ORDER BY 7 DESC, receipts.paid_at DESC, prices.product_id LIMIT $3"#,
+        params.from,
+        params.to,
+        params.limit(),
+        app_state.config.receipt_timezone.name()
+    )
+    .fetch_all(&app_state.read_pool)
+    .await?;
+    Ok(axum::Json(items))
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnitCount {
+    pub unit: String,
+    /// Line items measured in this unit
+    pub count: i64,
+}
+
+/// Every quantity unit seen on a receipt, most used first, e.g. to offer unit filters in a UI.
+pub async fn units(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<Vec<UnitCount>>, AppError> {
+    let units = sqlx::query_as!(
+        UnitCount,
+        r#"SELECT quantity_unit AS "unit!", COUNT(*) AS "count!" FROM prices WHERE quantity_unit IS NOT NULL GROUP BY quantity_unit ORDER BY 2 DESC, 1"#
+    )
+    .fetch_all(&app_state.read_pool)
+    .await?;
+    Ok(axum::Json(units))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MerchantSpendParams {
+    /// First day to include, in the receipt timezone
+    pub from: Option<NaiveDate>,
+    /// Last day to include, in the receipt timezone
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MerchantSpend {
+    pub merchant_name: String,
+    pub receipt_count: i64,
+    /// Sum of `count * unit_price` over the priced line items
+    pub total_spent: f64,
+}
+
+/// Spend per merchant, biggest first, for dashboards. Flagged duplicates are left out.
+pub async fn merchants(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<MerchantSpendParams>,
+) -> Result<axum::Json<Vec<MerchantSpend>>, AppError> {
+    let merchants = sqlx::query_as!(
+        MerchantSpend,
+        r#"SELECT receipts.merchant_name, COUNT(DISTINCT receipts.id) AS "receipt_count!", COALESCE(SUM(prices.count * prices.unit_price), 0) AS "total_spent!" FROM receipts LEFT JOIN prices ON receipts.id = prices.receipt_id WHERE receipts.duplicate_of IS NULL AND ($1::date IS NULL OR (receipts.paid_at AT TIME ZONE $3)::date >= $1) AND ($2::date IS NULL OR (receipts.paid_at AT TIME ZONE $3)::date <= $2) GROUP BY receipts.merchant_name ORDER BY 3 DESC, 1"#,
+        params.from,
+        params.to,
+        app_state.config.receipt_timezone.name()
+    )
+    .fetch_all(&app_state.read_pool)
+    .await?;
+    Ok(axum::Json(merchants))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ProductPriceParams {
+    /// Only purchases from this merchant, matched case-insensitively
+    pub merchant: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProductPrice {
+    pub paid_at: chrono::DateTime<chrono::Utc>,
+    pub merchant_name: String,
+    pub unit_price: f64,
+    pub count: f64,
+}
+
+/// Every priced purchase of a product, oldest first, to follow how its price changed. Flagged duplicates are left out.
+pub async fn product_prices(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<ProductPriceParams>,
+) -> Result<axum::Json<Vec<ProductPrice>>, AppError> {
+    let product_id = sqlx::query_scalar!("SELECT id FROM products WHERE name = $1", name)
+        .fetch_optional(&app_state.read_pool)
+        .await?
+        .ok_or(AppError::not_found(format!(
+            "Product {name:?} does not exist"
+        )))?;
+    let prices = sqlx::query_as!(
+        ProductPrice,
+        r#"SELECT receipts.paid_at, receipts.merchant_name, prices.unit_price AS "unit_price!", prices.count FROM prices JOIN receipts ON receipts.id = prices.receipt_id WHERE prices.product_id = $1 AND prices.unit_price IS NOT NULL AND receipts.duplicate_of IS NULL AND ($2::text IS NULL OR lower(receipts.merchant_name) = lower($2)) ORDER BY receipts.paid_at, prices.receipt_id, prices.line_index"#,
+        product_id,
+        params.merchant
+    )
+    .fetch_all(&app_state.read_pool)
+    .await?;
+    Ok(axum::Json(prices))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    /// Weeks start on Monday
+    Week,
+    Month,
+}
+
+impl Bucket {
+    /// First day of the bucket containing `day`.
+    fn start(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => day,
+            Bucket::Week => day - Days::new(day.weekday().num_days_from_monday().into()),
+            Bucket::Month => day.with_day(1).unwrap(),
+        }
+    }
+
+    fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => start + Days::new(1),
+            Bucket::Week => start + Days::new(7),
+            Bucket::Month => start + Months::new(1),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimelineParams {
+    /// First day to include, in the receipt timezone
+    pub from: NaiveDate,
+    /// Last day to include, in the receipt timezone
+    pub to: NaiveDate,
+    /// Also accepted as `interval`
+    #[serde(default, alias = "interval")]
+    pub bucket: Bucket,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TimelineBucket {
+    /// First day of the bucket, in the receipt timezone
+    pub start: NaiveDate,
+    /// Sum of receipt totals; `0` when nothing was bought
+    pub spend: f64,
+}
+
+/// Sums daily spend into buckets from the one containing `from` to the one containing `to`, so charts get no gaps. `None` when there are more than [`MAX_TIMELINE_BUCKETS`].
+pub fn timeline_buckets(
+    daily: &[(NaiveDate, f64)],
+    from: NaiveDate,
+    to: NaiveDate,
+    bucket: Bucket,
+) -> Option<Vec<TimelineBucket>> {
+    let mut spend = HashMap::<NaiveDate, f64>::new();
+    for (day, amount) in daily {
+        *spend.entry(bucket.start(*day)).or_default() += amount;
+    }
+    let mut buckets = Vec::new();
+    let mut start = bucket.start(from);
+    while start <= to {
+        if buckets.len() == MAX_TIMELINE_BUCKETS {
+            return None;
+        }
+        let amount = spend.get(&start).copied().unwrap_or_default();
+        buckets.push(TimelineBucket {
+            start,
+            spend: (amount * 100.0).round() / 100.0,
+        });
+        start = bucket.next(start);
+    }
+    Some(buckets)
+}
+
+/// Spend over time, aggregated per day, week or month, for charts. Flagged duplicates are left out.
+pub async fn timeline(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<TimelineParams>,
+) -> Result<axum::Json<Vec<TimelineBucket>>, AppError> {
+    if params.from > params.to {
+        return Err(AppError::bad_request("from must not be after to"));
+    }
+    let daily = sqlx::query!(
+        r#"SELECT (paid_at AT TIME ZONE $3)::date AS "day!", SUM(total) AS "spend!" FROM receipts WHERE duplicate_of IS NULL AND (paid_at AT TIME ZONE $3)::date BETWEEN $1 AND $2 GROUP BY 1"#,
+        params.from,
+        params.to,
+        app_state.config.receipt_timezone.name()
+    )
+    .fetch_all(&app_state.read_pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.day, row.spend))
+    .collect::<Vec<_>>();
+    timeline_buckets(&daily, params.from, params.to, params.bucket)
+        .map(axum::Json)
+        .ok_or_else(|| {
+            AppError::bad_request(format!(
+                "At most {MAX_TIMELINE_BUCKETS} buckets can be requested at once"
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::Query;
+    use chrono::NaiveDate;
+
+    use super::{
+        timeline_buckets, Bucket, MerchantSpendParams, TimelineBucket, TimelineParams,
+        TopItemsParams,
+    };
+
+    fn params(query: &str) -> TopItemsParams {
+        let uri = format!("/analytics/top-items?{query}").parse().unwrap();
+        Query::<TopItemsParams>::try_from_uri(&uri).unwrap().0
+    }
+
+    #[test]
+    fn clamp_number_of_top_items() {
+        assert_eq!(params("").limit(), 10);
+        assert_eq!(params("limit=0").limit(), 1);
+        assert_eq!(params("limit=25").limit(), 25);
+        assert_eq!(params("limit=5000").limit(), 100);
+        assert_eq!(
+            params("from=2023-10-01&to=2023-10-31").from,
+            chrono::NaiveDate::from_ymd_opt(2023, 10, 1)
+        );
+    }
+
+    #[test]
+    fn fill_timeline_buckets_without_gaps() {
+        let day = |month, day| NaiveDate::from_ymd_opt(2023, month, day).unwrap();
+        let daily = [
+            (day(9, 30), 40.0),
+            (day(10, 2), 92.35),
+            (day(10, 2), 10.1),
+            (day(10, 4), 5.0),
+            (day(11, 1), 20.0),
+        ];
+
+        let buckets = timeline_buckets(&daily, day(10, 1), day(10, 5), Bucket::Day).unwrap();
+        let spend = buckets
+            .iter()
+            .map(|bucket| (bucket.start, bucket.spend))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spend,
+            [
+                (day(10, 1), 0.0),
+                (day(10, 2), 102.45),
+                (day(10, 3), 0.0),
+                (day(10, 4), 5.0),
+                (day(10, 5), 0.0),
+            ]
+        );
+
+        assert_eq!(
+            timeline_buckets(&daily, day(9, 15), day(11, 30), Bucket::Month).unwrap(),
+            [
+                TimelineBucket {
+                    start: day(9, 1),
+                    spend: 40.0
+                },
+                TimelineBucket {
+                    start: day(10, 1),
+                    spend: 107.45
+                },
+                TimelineBucket {
+                    start: day(11, 1),
+                    spend: 20.0
+                },
+            ]
+        );
+
+        let weeks = timeline_buckets(&daily, day(10, 1), day(10, 5), Bucket::Week).unwrap();
+        assert_eq!(weeks[0].start, day(9, 25));
+        assert_eq!(weeks[1].spend, 107.45);
+        assert!(timeline_buckets(&daily, day(1, 1), day(12, 31), Bucket::Month).is_some());
+        assert!(timeline_buckets(&[], day(1, 1), day(12, 31), Bucket::Day).is_some());
+        let long = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
+        assert!(timeline_buckets(&[], day(1, 1), long, Bucket::Day).is_none());
+    }
+
+    #[test]
+    fn accept_only_known_buckets() {
+        let params = |query: &str| {
+            let uri = format!("/analytics/timeline?{query}").parse().unwrap();
+            Query::<TimelineParams>::try_from_uri(&uri).map(|query| query.0)
+        };
+        assert_eq!(
+            params("from=2023-10-01&to=2023-10-31").unwrap().bucket,
+            Bucket::Day
+        );
+        assert_eq!(
+            params("from=2023-10-01&to=2023-10-31&bucket=week")
+                .unwrap()
+                .bucket,
+            Bucket::Week
+        );
+        assert_eq!(
+            params("from=2023-10-01&to=2023-10-31&interval=month")
+                .unwrap()
+                .bucket,
+            Bucket::Month
+        );
+        assert!(params("from=2023-10-01&to=2023-10-31&bucket=year").is_err());
+    }
+
+    #[test]
+    fn scope_merchant_spend_to_optional_period() {
+        let params = |query: &str| {
+            let uri = format!("/merchants?{query}").parse().unwrap();
+            Query::<MerchantSpendParams>::try_from_uri(&uri).unwrap().0
+        };
+        let unscoped = params("");
+        assert!(unscoped.from.is_none() && unscoped.to.is_none());
+        let scoped = params("from=2023-10-01");
+        assert_eq!(scoped.from, NaiveDate::from_ymd_opt(2023, 10, 1));
+        assert!(scoped.to.is_none());
+    }
+}
@@ -0,0 +1,272 @@
+//! Bulk ingest of receipts already stored elsewhere, by URL, without the client uploading each file.
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::extract::{Query, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admit_upload,
+    file_hash::FileHash,
+    has_analysis_result, submit_for_analysis,
+    upload_stream::{StreamedUpload, UploadStream},
+    AppError, AppState, UploadParams,
+};
+
+#[derive(Deserialize)]
+pub struct AnalyzeUrls {
+    urls: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UrlStatus {
+    Queued {
+        file_hash: FileHash,
+    },
+    /// The file already has an analysis result, or appeared earlier in the same batch
+    AlreadyAnalyzed {
+        file_hash: FileHash,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct UrlResult {
+    url: String,
+    #[serde(flatten)]
+    status: UrlStatus,
+}
+
+/// Files of a batch seen so far, so one listed twice is submitted once.
+#[derive(Default)]
+pub struct Batch {
+    seen: HashSet<FileHash>,
+}
+
+impl Batch {
+    /// Whether the file is new, i.e. neither `cached` nor earlier in the batch.
+    pub fn admit(&mut self, file_hash: &FileHash, cached: bool) -> bool {
+        !cached && self.seen.insert(file_hash.clone())
+    }
+}
+
+/// Whether `ip` is reachable from the internet at large. Anything else would let callers reach services next to this one.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 0.0.0.0/8 is "this network", 100.64.0.0/10 is shared by carrier-grade NAT
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Resolves the URL's host once and refuses it unless it is an http(s) URL whose every address is public.
+/// The fetch then connects to the checked address, so a second lookup can't point it elsewhere.
+async fn public_address(url: &reqwest::Url) -> Result<SocketAddr, String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "Only http and https URLs can be fetched, not {url}"
+        ));
+    }
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err(format!("{url} names no host"));
+    };
+    // IPv6 literals come bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|err| format!("Could not resolve {url}: {err}"))?
+        .collect::<Vec<_>>();
+    if addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(format!("{url} points to a private address"));
+    }
+    addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("Could not resolve {url}"))
+}
+
+async fn fetch(app_state: &AppState, url: &str) -> Result<StreamedUpload, String> {
+    let url = reqwest::Url::parse(url).map_err(|err| format!("Invalid URL {url}: {err}"))?;
+    let addr = public_address(&url).await?;
+    // Redirects are not followed, as their targets would skip the address check
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(url.host_str().unwrap_or_default(), addr)
+        .build()
+        .map_err(|err| format!("Could not fetch {url}: {err}"))?;
+    let mut res = client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| format!("Could not fetch {url}: {err}"))?;
+    let mut stream = UploadStream::new(app_state.upload_check.clone());
+    let mut len = 0;
+    while let Some(chunk) = res
+        .chunk()
+        .await
+        .map_err(|err| format!("Could not fetch {url}: {err}"))?
+    {
+        len += chunk.len();
+        if len > app_state.config.upload_limit_bytes {
+            return Err(format!(
+                "{url} is larger than the {} bytes accepted",
+                app_state.config.upload_limit_bytes
+            ));
+        }
+        stream
+            .push(chunk)
+            .await
+            .map_err(|err| err.public_message())?;
+    }
+    // Servers often label files loosely, so only their contents count
+    stream.finish(None).map_err(|err| err.public_message())
+}
+
+/// Fetches each URL and submits its file before fetching the next, so the batch waits for analysis slots like single uploads do
+/// and holds only one file in memory at a time.
+pub async fn analyze_urls(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    axum::Json(body): axum::Json<AnalyzeUrls>,
+) -> Result<axum::Json<Vec<UrlResult>>, AppError> {
+    let max = app_state.config.analyze_urls_max;
+    if body.urls.len() > max {
+        return Err(AppError::bad_request(format!(
+            "At most {max} URLs can be analyzed at once"
+        )));
+    }
+    let force = params.force;
+    let model_id = admit_upload(&app_state, params)?;
+
+    let mut batch = Batch::default();
+    let mut results = Vec::new();
+    for url in body.urls {
+        let upload = match fetch(&app_state, &url).await {
+            Ok(upload) => upload,
+            Err(error) => {
+                results.push(UrlResult {
+                    url,
+                    status: UrlStatus::Failed { error },
+                });
+                continue;
+            }
+        };
+        let file_hash = upload.file_hash.clone();
+        let cached = !force && has_analysis_result(&app_state, &file_hash)?;
+        let status = if !batch.admit(&file_hash, cached) {
+            app_state.upload_stats.record(&file_hash, true);
+            UrlStatus::AlreadyAnalyzed { file_hash }
+        } else {
+            match submit_for_analysis(app_state.clone(), model_id.clone(), upload, force, None)
+                .await
+            {
+                Ok(_) => UrlStatus::Queued { file_hash },
+                Err(err) => UrlStatus::Failed {
+                    error: err.public_message(),
+                },
+            }
+        };
+        results.push(UrlResult { url, status });
+    }
+    Ok(axum::Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_cached_and_repeated_files() {
+        let cached = FileHash::of(b"old");
+        let mut batch = Batch::default();
+        let admitted = ["new", "old", "other", "new"].map(|file| {
+            let file_hash = FileHash::of(file.as_bytes());
+            batch.admit(&file_hash, file_hash == cached)
+        });
+        assert_eq!(admitted, [true, false, true, false]);
+    }
+
+    #[test]
+    fn refuse_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_urls_to_other_schemes_or_private_hosts() {
+        for url in [
+            "file:///etc/passwd",
+            "ftp://example.com/receipt.jpg",
+            "http://localhost:8000/receipt.jpg",
+            "http://127.0.0.1/receipt.jpg",
+            "http://[::1]/receipt.jpg",
+            "http://169.254.169.254/latest/meta-data",
+        ] {
+            assert!(
+                public_address(&url.parse().unwrap()).await.is_err(),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn report_status_per_url() {
+        let result = UrlResult {
+            url: "https://example.com/receipt.jpg".into(),
+            status: UrlStatus::AlreadyAnalyzed {
+                file_hash: FileHash::of(b"abc"),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            serde_json::json!({
+                "url": "https://example.com/receipt.jpg",
+                "status": "already_analyzed",
+                "file_hash": sha256::digest("abc")
+            })
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//! Interpretation of the raw Azure responses kept in the persist cache, keyed by file hash.
+
+use std::{future::Future, time::Duration};
+
+use futures::{stream, StreamExt};
+use serde::Serialize;
+
+use crate::{
+    config::{AzureApiVersion, ReanalysisPolicy},
+    manual::AnalyzeResultOperation,
+};
+
+/// Why Azure gave up on a file, taken from the most specific error it reported.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AnalysisFailure {
+    pub code: String,
+    pub message: String,
+    pub failed_at: chrono::DateTime<chrono::FixedOffset>,
+}
+
+#[derive(Debug)]
+pub enum CachedAnalysis {
+    /// The hash is claimed, but no response has been stored yet
+    Pending,
+    Failed(AnalysisFailure),
+    Done(Box<AnalyzeResultOperation>),
+}
+
+/// Azure nests the actual cause in `innererror`, e.g. `InvalidRequest` > `InvalidContent`.
+pub fn analysis_failure(operation: &AnalyzeResultOperation) -> Option<AnalysisFailure> {
+    if operation.status != "failed" {
+        return None;
+    }
+    let mut error = operation.error.as_ref();
+    while let Some(inner) = error.and_then(|error| error.get("innererror")) {
+        error = Some(inner);
+    }
+    let field = |name: &str| {
+        error
+            .and_then(|error| error.get(name))
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    Some(AnalysisFailure {
+        code: field("code"),
+        message: field("message"),
+        failed_at: operation.lastUpdatedDateTime,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reanalysis {
+    Replace,
+    KeepPrevious,
+    Reject,
+}
+
+fn document_confidence(operation: &AnalyzeResultOperation) -> f64 {
+    operation
+        .analyzeResult
+        .as_ref()
+        .and_then(|result| result.documents.as_ref())
+        .and_then(|documents| documents.first())
+        .map_or(0.0, |document| document.confidence.0)
+}
+
+/// Decides what happens to a new result for a file whose earlier result is still cached.
+pub fn resolve_reanalysis(
+    policy: ReanalysisPolicy,
+    previous: &AnalyzeResultOperation,
+    new: &AnalyzeResultOperation,
+) -> Reanalysis {
+    match policy {
+        ReanalysisPolicy::Latest => Reanalysis::Replace,
+        ReanalysisPolicy::Confidence
+            if document_confidence(new) >= document_confidence(previous) =>
+        {
+            Reanalysis::Replace
+        }
+        ReanalysisPolicy::Confidence => Reanalysis::KeepPrevious,
+        ReanalysisPolicy::Reject => Reanalysis::Reject,
+    }
+}
+
+pub type ParseError = serde_path_to_error::Error<serde_json::Error>;
+
+/// Deserializes a raw Azure response of the given API version; on failure the error tells which field didn't match our model.
+pub fn parse_operation(
+    raw: &str,
+    api_version: AzureApiVersion,
+) -> Result<AnalyzeResultOperation, ParseError> {
+    let mut deserializer = serde_json::Deserializer::from_str(raw);
+    match api_version {
+        AzureApiVersion::V2023_07_31 => serde_path_to_error::deserialize(&mut deserializer),
+        AzureApiVersion::V2024_11_30 => {
+            let mut value = serde_path_to_error::deserialize(&mut deserializer)?;
+            round_polygons(&mut value);
+            serde_path_to_error::deserialize(value)
+        }
+    }
+}
+
+/// Document Intelligence reports polygon coordinates with fractions, where our model keeps the whole pixels Form Recognizer used.
+fn round_polygons(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields {
+                match field {
+                    serde_json::Value::Array(coordinates) if name == "polygon" => {
+                        for coordinate in coordinates {
+                            if let Some(rounded) = coordinate.as_f64().map(f64::round) {
+                                *coordinate = (rounded as i64).into();
+                            }
+                        }
+                    }
+                    field => round_polygons(field),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(round_polygons),
+        _ => {}
+    }
+}
+
+pub fn parse_cached(raw: &str, api_version: AzureApiVersion) -> Result<CachedAnalysis, ParseError> {
+    if raw.is_empty() {
+        return Ok(CachedAnalysis::Pending);
+    }
+    let operation = parse_operation(raw, api_version)?;
+    Ok(match analysis_failure(&operation) {
+        Some(failure) => CachedAnalysis::Failed(failure),
+        None => CachedAnalysis::Done(Box::new(operation)),
+    })
+}
+
+/// What a cache entry holds, judged without deserializing the stored response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryStatus {
+    Pending,
+    Cached,
+    /// Not even valid JSON, so no parse of it will succeed
+    Corrupt,
+}
+
+/// Only checks that the entry is well-formed JSON, which is much cheaper than [`parse_cached`] on large responses.
+pub fn entry_status(raw: &str) -> EntryStatus {
+    if raw.is_empty() {
+        EntryStatus::Pending
+    } else if serde_json::from_str::<serde::de::IgnoredAny>(raw).is_ok() {
+        EntryStatus::Cached
+    } else {
+        EntryStatus::Corrupt
+    }
+}
+
+/// When Azure last updated the analysis; `None` for pending entries, which never expire so their claim on the file hash holds.
+pub fn analyzed_at(analysis: &CachedAnalysis) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    match analysis {
+        CachedAnalysis::Pending => None,
+        CachedAnalysis::Failed(failure) => Some(failure.failed_at),
+        CachedAnalysis::Done(operation) => Some(operation.lastUpdatedDateTime),
+    }
+}
+
+/// A TTL of whole days; `None` if it overflows.
+pub fn ttl_from_days(days: u64) -> Option<Duration> {
+    days.checked_mul(24 * 60 * 60).map(Duration::from_secs)
+}
+
+/// Entries last updated before this are older than `ttl`; `None` if `ttl` reaches back further than timestamps go.
+pub fn prune_cutoff(
+    now: chrono::DateTime<chrono::Utc>,
+    ttl: Duration,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::Duration::from_std(ttl)
+        .ok()
+        .and_then(|ttl| now.checked_sub_signed(ttl))
+}
+
+/// Picks the entries of one batch. Keys are sorted first, so consecutive batches neither overlap nor miss entries.
+pub fn batch<K: Ord>(mut file_hashes: Vec<K>, offset: usize, limit: Option<usize>) -> Vec<K> {
+    file_hashes.sort_unstable();
+    file_hashes
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+/// Runs `load` for every cache key with at most `width` loads in flight, so large caches don't exhaust memory or the persist backend. Results come in completion order.
+pub async fn load_bounded<K, F, Fut, T>(file_hashes: Vec<K>, width: usize, load: F) -> Vec<T>
+where
+    F: FnMut(K) -> Fut,
+    Fut: Future<Output = T>,
+{
+    stream::iter(file_hashes)
+        .map(load)
+        .buffer_unordered(width.max(1))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{
+        analyzed_at, batch, entry_status, load_bounded, parse_cached, parse_operation,
+        prune_cutoff, resolve_reanalysis, ttl_from_days, AnalysisFailure, CachedAnalysis,
+        EntryStatus, Reanalysis,
+    };
+    use crate::{
+        config::{AzureApiVersion, ReanalysisPolicy},
+        manual::{Confidence, DocumentFields},
+    };
+
+    #[test]
+    fn report_path_of_malformed_field() {
+        let raw = include_str!("../response1.json").replacen(
+            r#""valueNumber": 204.52"#,
+            r#""valueNumber": "204.52""#,
+            1,
+        );
+        let err = parse_operation(&raw, AzureApiVersion::V2023_07_31).unwrap_err();
+        assert_eq!(
+            err.path().to_string(),
+            "analyzeResult.documents[0].fields.Total.valueNumber"
+        );
+
+        for (field, valid, malformed) in [
+            ("TransactionDate.valueDate", "2023-09-10", "2023-13-10"),
+            ("TransactionTime.valueTime", "20:00:00", "8 PM"),
+        ] {
+            let raw = include_str!("../response1.json").replacen(valid, malformed, 1);
+            let err = parse_operation(&raw, AzureApiVersion::V2023_07_31).unwrap_err();
+            assert_eq!(
+                err.path().to_string(),
+                format!("analyzeResult.documents[0].fields.{field}")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn bound_concurrent_cache_reads() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let file_hashes = (0..100).map(|i| format!("hash{i}")).collect();
+        let loaded = load_bounded(file_hashes, 4, |file_hash| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                file_hash
+            }
+        })
+        .await;
+        assert_eq!(loaded.len(), 100);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn tell_failed_pending_and_done_entries_apart() {
+        let CachedAnalysis::Failed(failure) = parse_cached(
+            include_str!("../response_failed.json"),
+            AzureApiVersion::V2023_07_31,
+        )
+        .unwrap() else {
+            panic!("Expected a failed analysis");
+        };
+        assert_eq!(
+            failure,
+            AnalysisFailure {
+                code: "InvalidContent".into(),
+                message: "The file is corrupted or format is unsupported. Refer to documentation for the list of supported formats.".into(),
+                failed_at: "2023-10-13T09:00:03Z".parse().unwrap(),
+            }
+        );
+        assert!(matches!(
+            parse_cached("", AzureApiVersion::V2023_07_31).unwrap(),
+            CachedAnalysis::Pending
+        ));
+        assert!(matches!(
+            parse_cached(
+                include_str!("../response1.json"),
+                AzureApiVersion::V2023_07_31
+            )
+            .unwrap(),
+            CachedAnalysis::Done(_)
+        ));
+    }
+
+    #[test]
+    fn judge_entries_without_parsing_them() {
+        assert_eq!(entry_status(""), EntryStatus::Pending);
+        assert_eq!(
+            entry_status(include_str!("../response1.json")),
+            EntryStatus::Cached
+        );
+        assert_eq!(
+            entry_status(include_str!("../response_failed.json")),
+            EntryStatus::Cached
+        );
+        let truncated = &include_str!("../response1.json")[..100];
+        assert_eq!(entry_status(truncated), EntryStatus::Corrupt);
+    }
+
+    #[test]
+    fn parse_responses_of_configured_api_version() {
+        let raw = include_str!("../response1_v4.json");
+        assert!(parse_operation(raw, AzureApiVersion::V2023_07_31).is_err());
+        let operation = parse_operation(raw, AzureApiVersion::V2024_11_30).unwrap();
+        let result = operation.analyzeResult.unwrap();
+        assert_eq!(result.apiVersion.0, "2024-11-30");
+        let documents = result.documents.unwrap();
+        let DocumentFields::Receipt(receipt) = &documents[0].fields else {
+            panic!("Expected receipt fields, got {:?}", documents[0].fields);
+        };
+        assert_eq!(
+            receipt.total.bounding_regions[0].polygon,
+            [1219, 2174, 1376, 2173, 1375, 2217, 1221, 2217]
+        );
+
+        // Older responses still in the cache keep parsing after the version bump
+        parse_operation(
+            include_str!("../response1.json"),
+            AzureApiVersion::V2024_11_30,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn date_entries_by_last_update() {
+        let parse = |raw| parse_cached(raw, AzureApiVersion::V2023_07_31).unwrap();
+        assert_eq!(analyzed_at(&parse("")), None);
+        assert_eq!(
+            analyzed_at(&parse(include_str!("../response1.json"))).map(|at| at.to_rfc3339()),
+            Some("2023-10-05T15:42:56+00:00".into())
+        );
+        assert!(analyzed_at(&parse(include_str!("../response_failed.json"))).is_some());
+    }
+
+    #[test]
+    fn reject_ttls_reaching_past_representable_times() {
+        let now = chrono::Utc::now();
+        let ttl = ttl_from_days(30).unwrap();
+        assert_eq!(
+            prune_cutoff(now, ttl),
+            Some(now - chrono::Duration::days(30))
+        );
+        assert_eq!(ttl_from_days(u64::MAX), None);
+        // Fits a std Duration, but not a chrono one
+        assert_eq!(
+            prune_cutoff(now, ttl_from_days(u64::MAX / 86400).unwrap()),
+            None
+        );
+        // Fits a chrono Duration, but not the subtraction from now
+        assert_eq!(prune_cutoff(now, ttl_from_days(200_000_000).unwrap()), None);
+    }
+
+    #[test]
+    fn resolve_second_result_for_same_file() {
+        let parse = |confidence| {
+            let mut operation = parse_operation(
+                include_str!("../response1.json"),
+                AzureApiVersion::V2023_07_31,
+            )
+            .unwrap();
+            operation
+                .analyzeResult
+                .as_mut()
+                .unwrap()
+                .documents
+                .as_mut()
+                .unwrap()[0]
+                .confidence = Confidence(confidence);
+            operation
+        };
+        let (confident, unsure) = (parse(0.99), parse(0.6));
+        for (policy, previous, new, expected) in [
+            (
+                ReanalysisPolicy::Latest,
+                &confident,
+                &unsure,
+                Reanalysis::Replace,
+            ),
+            (
+                ReanalysisPolicy::Confidence,
+                &confident,
+                &unsure,
+                Reanalysis::KeepPrevious,
+            ),
+            (
+                ReanalysisPolicy::Confidence,
+                &unsure,
+                &confident,
+                Reanalysis::Replace,
+            ),
+            (
+                ReanalysisPolicy::Confidence,
+                &unsure,
+                &unsure,
+                Reanalysis::Replace,
+            ),
+            (
+                ReanalysisPolicy::Reject,
+                &unsure,
+                &confident,
+                Reanalysis::Reject,
+            ),
+        ] {
+            assert_eq!(
+                resolve_reanalysis(policy, previous, new),
+                expected,
+                "{policy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn process_only_the_requested_batch() {
+        let file_hashes = vec![
+            "c".to_string(),
+            "a".into(),
+            "e".into(),
+            "b".into(),
+            "d".into(),
+        ];
+        assert_eq!(batch(file_hashes.clone(), 1, Some(2)), ["b", "c"]);
+        assert_eq!(batch(file_hashes.clone(), 3, Some(10)), ["d", "e"]);
+        assert_eq!(batch(file_hashes.clone(), 0, None).len(), 5);
+        assert!(batch(file_hashes, 5, Some(2)).is_empty());
+    }
+}
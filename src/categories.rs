@@ -0,0 +1,117 @@
+//! Rule-based product categories. Rules are tried in configured order and the first match wins, so a product's category only depends on the rules, never on the order products were seen in.
+
+use std::sync::Arc;
+
+use axum::extract::State;
+use serde::Serialize;
+
+use crate::{compare::normalize_product_name, config::CategoryRule, AppError, AppState};
+
+/// A product whose stored category differs from what the current rules give it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CategoryConflict {
+    pub product_id: i32,
+    pub name: String,
+    pub stored: Option<String>,
+    pub current: Option<String>,
+}
+
+/// Category of receipts from merchants no rule matches
+pub const UNCATEGORIZED: &str = "uncategorized";
+
+pub fn categorize<'a>(name: &str, rules: &'a [CategoryRule]) -> Option<&'a str> {
+    let name = normalize_product_name(name);
+    rules
+        .iter()
+        .find(|rule| name.contains(&rule.pattern))
+        .map(|rule| rule.category.as_str())
+}
+
+/// Budgeting category of a receipt, from the first rule matching its merchant's name.
+pub fn merchant_category<'a>(merchant_name: &str, rules: &'a [CategoryRule]) -> &'a str {
+    categorize(merchant_name, rules).unwrap_or(UNCATEGORIZED)
+}
+
+/// Takes `(id, name, stored category)` of each product.
+pub fn find_conflicts(
+    products: Vec<(i32, String, Option<String>)>,
+    rules: &[CategoryRule],
+) -> Vec<CategoryConflict> {
+    products
+        .into_iter()
+        .filter_map(|(product_id, name, stored)| {
+            let current = categorize(&name, rules).map(String::from);
+            (current != stored).then_some(CategoryConflict {
+                product_id,
+                name,
+                stored,
+                current,
+            })
+        })
+        .collect()
+}
+
+/// Lists the products that would be categorized differently under the current rules. Stored categories are never changed behind the user's back.
+pub async fn category_conflicts(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<Vec<CategoryConflict>>, AppError> {
+    let products = sqlx::query!("SELECT id, name, category FROM products ORDER BY name")
+        .fetch_all(&app_state.pool)
+        .await?
+        .into_iter()
+        .map(|product| (product.id, product.name, product.category))
+        .collect();
+    Ok(axum::Json(find_conflicts(
+        products,
+        &app_state.config.category_rules,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{categorize, find_conflicts, merchant_category, CategoryConflict};
+    use crate::config::CategoryRule;
+
+    fn rules(raw: &[&str]) -> Vec<CategoryRule> {
+        raw.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let dairy_first = rules(&["mælk=dairy", "kakaomælk=sweets"]);
+        assert_eq!(categorize("KAKAOMÆLK 0.5L", &dairy_first), Some("dairy"));
+        let sweets_first = rules(&["kakaomælk=sweets", "mælk=dairy"]);
+        assert_eq!(categorize("KAKAOMÆLK 0.5L", &sweets_first), Some("sweets"));
+        assert_eq!(categorize("MINIMÆLK", &sweets_first), Some("dairy"));
+        assert_eq!(categorize("RUGBRØD", &sweets_first), None);
+    }
+
+    #[test]
+    fn categorize_receipts_by_merchant() {
+        let rules = rules(&["netto=groceries", "shell=fuel", "café=dining"]);
+        assert_eq!(merchant_category("NETTO Vesterbro", &rules), "groceries");
+        assert_eq!(merchant_category("Shell Express", &rules), "fuel");
+        assert_eq!(merchant_category("Café Norden", &rules), "dining");
+        assert_eq!(merchant_category("IKEA", &rules), "uncategorized");
+        assert_eq!(merchant_category("Netto", &[]), "uncategorized");
+    }
+
+    #[test]
+    fn report_products_whose_category_would_change() {
+        let products = vec![
+            (1, "KAKAOMÆLK 0.5L".to_string(), Some("dairy".to_string())),
+            (2, "MINIMÆLK".to_string(), Some("dairy".to_string())),
+            (3, "RUGBRØD".to_string(), None),
+        ];
+        let conflicts = find_conflicts(products, &rules(&["kakaomælk=sweets", "mælk=dairy"]));
+        assert_eq!(
+            conflicts,
+            [CategoryConflict {
+                product_id: 1,
+                name: "KAKAOMÆLK 0.5L".into(),
+                stored: Some("dairy".into()),
+                current: Some("sweets".into()),
+            }]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Each client gets its own bearer token, so one can be revoked without rotating everyone's.
+
+use std::collections::HashMap;
+
+/// Names the client a request came from; inserted into the request's extensions by the auth middleware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientLabel(pub String);
+
+#[derive(Debug, Clone)]
+pub struct ClientTokens {
+    labels: HashMap<String, ClientLabel>,
+    /// The first token listed
+    signing_key: String,
+}
+
+impl ClientTokens {
+    /// Parses `CLIENT_SECRET`: comma-separated tokens, each optionally prefixed with a label as in `phone:token`.
+    /// Unlabeled tokens are called `client-N` after their position in the list.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let mut labels = HashMap::new();
+        let mut signing_key = None;
+        let entries = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty());
+        for (i, entry) in entries.enumerate() {
+            let (label, token) = match entry.split_once(':') {
+                Some((label, token)) => (label.trim().to_string(), token.trim()),
+                None => (format!("client-{}", i + 1), entry),
+            };
+            if label.is_empty() || token.is_empty() {
+                return Err(format!(
+                    "CLIENT_SECRET entry {} needs both a label and a token",
+                    i + 1
+                ));
+            }
+            if labels
+                .insert(token.to_string(), ClientLabel(label))
+                .is_some()
+            {
+                return Err(format!(
+                    "CLIENT_SECRET lists the token of entry {} twice",
+                    i + 1
+                ));
+            }
+            signing_key.get_or_insert_with(|| token.to_string());
+        }
+        let Some(signing_key) = signing_key else {
+            return Err("CLIENT_SECRET lists no tokens".into());
+        };
+        Ok(Self {
+            labels,
+            signing_key,
+        })
+    }
+
+    pub fn client(&self, token: &str) -> Option<&ClientLabel> {
+        self.labels.get(token)
+    }
+
+    /// Key for signing what the service sends to clients, like webhook calls.
+    pub fn signing_key(&self) -> &str {
+        &self.signing_key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_any_listed_token() {
+        let tokens = ClientTokens::parse(" phone:abc123 , laptop:def456,ghi789 ").unwrap();
+        assert_eq!(tokens.client("abc123"), Some(&ClientLabel("phone".into())));
+        assert_eq!(tokens.client("def456"), Some(&ClientLabel("laptop".into())));
+        assert_eq!(
+            tokens.client("ghi789"),
+            Some(&ClientLabel("client-3".into()))
+        );
+        assert_eq!(tokens.client("phone:abc123"), None);
+        assert_eq!(tokens.client(""), None);
+        assert_eq!(tokens.signing_key(), "abc123");
+
+        // A single secret keeps working as before
+        let tokens = ClientTokens::parse("secret").unwrap();
+        assert_eq!(
+            tokens.client("secret"),
+            Some(&ClientLabel("client-1".into()))
+        );
+    }
+
+    #[test]
+    fn reject_malformed_token_lists() {
+        for raw in ["", " , ", "phone:", ":abc", "abc,phone:abc"] {
+            assert!(ClientTokens::parse(raw).is_err(), "{raw:?}");
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Side-by-side diff of two shopping trips to the same store.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use axum::extract::{Query, State};
+use serde::{Deserialize, Serialize};
+
+use sqlx::types::Json;
+
+use crate::{extract::LineItem, manual::BoundingRegion, AppError, AppState};
+
+#[derive(Deserialize)]
+pub struct CompareParams {
+    a: i32,
+    b: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ItemPrice {
+    pub name: String,
+    pub count: f64,
+    pub unit_price: Option<f64>,
+}
+
+/// A product bought on both trips; `price_change` is how much more it cost per unit on `b`, if both prices are known.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CommonItem {
+    pub name: String,
+    pub a: ItemPrice,
+    pub b: ItemPrice,
+    pub price_change: Option<f64>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ItemsDiff {
+    pub common: Vec<CommonItem>,
+    /// Bought on `a` but not on `b`
+    pub dropped: Vec<ItemPrice>,
+    /// Bought on `b` but not on `a`
+    pub added: Vec<ItemPrice>,
+}
+
+#[derive(Serialize)]
+pub struct ReceiptSummary {
+    id: i32,
+    merchant_name: String,
+    paid_at: chrono::DateTime<chrono::Utc>,
+    total: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct Comparison {
+    a: ReceiptSummary,
+    b: ReceiptSummary,
+    #[serde(flatten)]
+    items: ItemsDiff,
+}
+
+/// Receipts print the same product with varying case and spacing between visits.
+pub fn normalize_product_name(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn by_normalized_name(items: Vec<LineItem>) -> BTreeMap<String, ItemPrice> {
+    let mut by_name = BTreeMap::new();
+    // A product listed several times is compared by its first line
+    for item in items {
+        by_name
+            .entry(normalize_product_name(&item.name))
+            .or_insert(ItemPrice {
+                name: item.name,
+                count: item.count,
+                unit_price: item.unit_price,
+            });
+    }
+    by_name
+}
+
+/// Joins the line items of two receipts by normalized product name, ordered by that name.
+pub fn diff_items(a: Vec<LineItem>, b: Vec<LineItem>) -> ItemsDiff {
+    let mut b = by_normalized_name(b);
+    let mut diff = ItemsDiff::default();
+    for (normalized_name, a_item) in by_normalized_name(a) {
+        match b.remove(&normalized_name) {
+            Some(b_item) => diff.common.push(CommonItem {
+                price_change: a_item
+                    .unit_price
+                    .zip(b_item.unit_price)
+                    .map(|(a, b)| ((b - a) * 100.0).round() / 100.0),
+                name: a_item.name.clone(),
+                a: a_item,
+                b: b_item,
+            }),
+            None => diff.dropped.push(a_item),
+        }
+    }
+    diff.added = b.into_values().collect();
+    diff
+}
+
+async fn receipt_summary(app_state: &AppState, id: i32) -> Result<ReceiptSummary, AppError> {
+    sqlx::query_as!(
+        ReceiptSummary,
+        "SELECT id, merchant_name, paid_at, total FROM receipts WHERE id = $1",
+        id
+    )
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or(AppError::not_found(format!("Receipt {id} does not exist")))
+}
+
+pub async fn receipt_items(app_state: &AppState, id: i32) -> Result<Vec<LineItem>, AppError> {
+    Ok(receipts_items(app_state, &[id])
+        .await?
+        .remove(&id)
+        .unwrap_or_default())
+}
+
+/// The items of each of the receipts, in one query; receipts without items are left out.
+pub async fn receipts_items(
+    app_state: &AppState,
+    ids: &[i32],
+) -> Result<HashMap<i32, Vec<LineItem>>, AppError> {
+    let rows = sqlx::query!(
+        r#"SELECT prices.receipt_id, products.name, prices.count, prices.unit_price, prices.quantity_defaulted, prices.quantity_unit, products.product_code, prices.price_missing, prices.synthesized, prices.unattributed, COALESCE((SELECT jsonb_agg(jsonb_build_object('pageNumber', item_regions.page_number, 'polygon', item_regions.polygon) ORDER BY item_regions.id) FROM item_regions WHERE item_regions.receipt_id = prices.receipt_id AND item_regions.line_index = prices.line_index), '[]') AS "regions!: Json<Vec<BoundingRegion>>" FROM prices JOIN products ON products.id = prices.product_id WHERE prices.receipt_id = ANY($1) ORDER BY prices.receipt_id, prices.line_index"#,
+        ids
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
+    let mut items = HashMap::<i32, Vec<LineItem>>::new();
+    for row in rows {
+        items.entry(row.receipt_id).or_default().push(LineItem {
+            name: row.name,
+            count: row.count,
+            unit_price: row.unit_price,
+            quantity_defaulted: row.quantity_defaulted,
+            quantity_unit: row.quantity_unit,
+            product_code: row.product_code,
+            price_missing: row.price_missing,
+            synthesized: row.synthesized,
+            unattributed: row.unattributed,
+            regions: row.regions.0,
+        });
+    }
+    Ok(items)
+}
+
+/// Compares the items of two receipts from the same merchant, e.g. to watch prices.
+pub async fn compare_receipts(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<CompareParams>,
+) -> Result<axum::Json<Comparison>, AppError> {
+    let a = receipt_summary(&app_state, params.a).await?;
+    let b = receipt_summary(&app_state, params.b).await?;
+    if a.merchant_name.to_lowercase() != b.merchant_name.to_lowercase() {
+        return Err(AppError::bad_request(format!(
+            "Receipts {} and {} are from different merchants",
+            a.id, b.id
+        )));
+    }
+    let items = diff_items(
+        receipt_items(&app_state, a.id).await?,
+        receipt_items(&app_state, b.id).await?,
+    );
+    Ok(axum::Json(Comparison { a, b, items }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_product_name;
+
+    #[test]
+    fn normalize_product_names() {
+        assert_eq!(
+            normalize_product_name(" Salling  MINIMÆLK 1L "),
+            "salling minimælk 1l"
+        );
+    }
+}
@@ -0,0 +1,595 @@
+use std::{str::FromStr, time::Duration};
+
+use shuttle_secrets::SecretStore;
+
+/// What to do when a newly analyzed receipt matches one already stored (same merchant, `paid_at` and total).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Store the new receipt, but point it at the receipt it most likely duplicates
+    Flag,
+    /// Keep only the existing receipt, taking the new one's items if it read more lines, instead of storing a second one
+    Merge,
+    /// Store the new receipt as if no duplicate was found
+    Allow,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "flag" => Ok(Self::Flag),
+            "merge" => Ok(Self::Merge),
+            "allow" => Ok(Self::Allow),
+            other => Err(format!(
+                "Unknown duplicate receipt policy {other:?}, expected one of: flag, merge, allow"
+            )),
+        }
+    }
+}
+
+/// Which result to keep when a file that already has an analysis result is analyzed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReanalysisPolicy {
+    /// The newer result replaces the stored one
+    Latest,
+    /// The result Azure is more confident about is kept, the newer one on a tie
+    Confidence,
+    /// The stored result is kept and the newer one reported as an error
+    Reject,
+}
+
+impl FromStr for ReanalysisPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "latest" => Ok(Self::Latest),
+            "confidence" => Ok(Self::Confidence),
+            "reject" => Ok(Self::Reject),
+            other => Err(format!(
+                "Unknown reanalysis policy {other:?}, expected one of: latest, confidence, reject"
+            )),
+        }
+    }
+}
+
+/// Where a merchant label may be taken from when Azure detected no merchant name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerchantFallback {
+    Address,
+    Phone,
+}
+
+impl FromStr for MerchantFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "address" => Ok(Self::Address),
+            "phone" => Ok(Self::Phone),
+            other => Err(format!(
+                "Unknown merchant name fallback {other:?}, expected address or phone"
+            )),
+        }
+    }
+}
+
+/// Time of day assumed for receipts where only the date could be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateOnlyTime {
+    Noon,
+    Midnight,
+}
+
+impl DateOnlyTime {
+    pub fn time(self) -> chrono::NaiveTime {
+        match self {
+            Self::Noon => chrono::NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+            Self::Midnight => chrono::NaiveTime::MIN,
+        }
+    }
+}
+
+impl FromStr for DateOnlyTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "noon" => Ok(Self::Noon),
+            "midnight" => Ok(Self::Midnight),
+            other => Err(format!(
+                "Unknown date-only time {other:?}, expected noon or midnight"
+            )),
+        }
+    }
+}
+
+/// What to do with line items priced at exactly zero, like free gifts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroPricePolicy {
+    Keep,
+    Drop,
+    /// Keep the items, but mark the receipt for review
+    Flag,
+}
+
+impl FromStr for ZeroPricePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "drop" => Ok(Self::Drop),
+            "flag" => Ok(Self::Flag),
+            other => Err(format!(
+                "Unknown zero-price policy {other:?}, expected one of: keep, drop, flag"
+            )),
+        }
+    }
+}
+
+/// Version of the Azure analyze API to call. Form Recognizer was renamed Document Intelligence as of `2024-11-30`, which moved the endpoint and changed parts of the response shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AzureApiVersion {
+    V2023_07_31,
+    V2024_11_30,
+}
+
+impl AzureApiVersion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::V2023_07_31 => "2023-07-31",
+            Self::V2024_11_30 => "2024-11-30",
+        }
+    }
+
+    /// Path segment the analyze endpoints live under for this version.
+    pub fn service(self) -> &'static str {
+        match self {
+            Self::V2023_07_31 => "formrecognizer",
+            Self::V2024_11_30 => "documentintelligence",
+        }
+    }
+}
+
+impl FromStr for AzureApiVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "2023-07-31" => Ok(Self::V2023_07_31),
+            "2024-11-30" => Ok(Self::V2024_11_30),
+            other => Err(format!(
+                "Unsupported Azure API version {other:?}, expected 2023-07-31 or 2024-11-30"
+            )),
+        }
+    }
+}
+
+/// Gives products (or, as a merchant rule, receipts from merchants) whose name contains `pattern` (case-insensitively) the `category`, written `pattern=category`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CategoryRule {
+    pub pattern: String,
+    pub category: String,
+}
+
+impl FromStr for CategoryRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, category) = s
+            .split_once('=')
+            .map(|(pattern, category)| (pattern.trim(), category.trim()))
+            .filter(|(pattern, category)| !pattern.is_empty() && !category.is_empty())
+            .ok_or_else(|| format!("Invalid category rule {s:?}, expected pattern=category"))?;
+        Ok(Self {
+            pattern: crate::compare::normalize_product_name(pattern),
+            category: category.to_string(),
+        })
+    }
+}
+
+/// Reads the date of receipts from merchants whose name contains `pattern` (case-insensitively) from the printed text in `format`,
+/// for merchants whose dates the model is known to misread, e.g. by flipping day and month. Written `pattern=format`, e.g. `netto=%Y-%m-%d`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerchantDateQuirk {
+    pub pattern: String,
+    /// `chrono` strftime format of the printed date
+    pub format: String,
+}
+
+impl MerchantDateQuirk {
+    /// The printed date, if the quirk applies to the merchant and the text is in its format.
+    pub fn read(&self, merchant_name: &str, content: &str) -> Option<chrono::NaiveDate> {
+        if !merchant_name.to_lowercase().contains(&self.pattern) {
+            return None;
+        }
+        chrono::NaiveDate::parse_from_str(content.trim(), &self.format).ok()
+    }
+}
+
+impl FromStr for MerchantDateQuirk {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, format) = s
+            .split_once('=')
+            .map(|(pattern, format)| (pattern.trim(), format.trim()))
+            .filter(|(pattern, format)| !pattern.is_empty() && !format.is_empty())
+            .ok_or_else(|| format!("Invalid merchant date quirk {s:?}, expected pattern=format"))?;
+        if chrono::format::StrftimeItems::new(format)
+            .any(|item| item == chrono::format::Item::Error)
+        {
+            return Err(format!(
+                "Invalid date format {format:?} in merchant date quirk"
+            ));
+        }
+        Ok(Self {
+            pattern: pattern.to_lowercase(),
+            format: format.to_string(),
+        })
+    }
+}
+
+/// Currency assumed for receipts from merchants in `country` that print none, written `country=currency`, e.g. `DNK=DKK`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountryCurrency {
+    /// Country as Azure reports it in addresses, usually an ISO 3166-1 alpha-3 code
+    pub country: String,
+    pub currency: String,
+}
+
+impl FromStr for CountryCurrency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (country, currency) = s
+            .split_once('=')
+            .map(|(country, currency)| (country.trim(), currency.trim()))
+            .filter(|(country, currency)| !country.is_empty() && !currency.is_empty())
+            .ok_or_else(|| format!("Invalid country currency {s:?}, expected country=currency"))?;
+        Ok(Self {
+            country: country.to_uppercase(),
+            currency: currency.to_uppercase(),
+        })
+    }
+}
+
+/// Decimal separator used for amounts in exported files, to match the spreadsheet locale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecimalSeparator {
+    Dot,
+    Comma,
+}
+
+impl FromStr for DecimalSeparator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "dot" | "." => Ok(Self::Dot),
+            "comma" | "," => Ok(Self::Comma),
+            other => Err(format!(
+                "Unknown decimal separator {other:?}, expected dot or comma"
+            )),
+        }
+    }
+}
+
+/// Deployment settings read from secrets; every field falls back to a sensible default when its secret is absent.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub duplicate_policy: DuplicatePolicy,
+    pub duplicate_total_tolerance: f64,
+    pub reanalysis_policy: ReanalysisPolicy,
+    /// How many analyses may be in flight against Azure at once
+    pub analysis_concurrency: usize,
+    /// How many uploads may wait for a free analysis slot before being turned away
+    pub analysis_queue_depth: usize,
+    pub analysis_queue_timeout: Duration,
+    /// Longest to wait for Azure to finish analyzing a file before giving up on it
+    pub analysis_poll_timeout: Duration,
+    /// Wait before first asking Azure for a result, doubled for every further poll
+    pub analysis_first_poll_delay: Duration,
+    /// Age after which `/dev/cache/prune` removes a cached analysis; unset keeps them until a cutoff is given
+    pub cache_ttl: Option<Duration>,
+    /// Retries of an Azure call answered with 429, 500, 502 or 503, backing off exponentially or as `Retry-After` says
+    pub azure_max_retries: u32,
+    /// Count stored for line items where no quantity was detected
+    pub default_quantity: f64,
+    /// Tried in order when the merchant name is empty; if none applies the receipt is marked for review
+    pub merchant_fallbacks: Vec<MerchantFallback>,
+    /// Azure model used when an upload doesn't ask for a specific one
+    pub default_model_id: String,
+    pub api_version: AzureApiVersion,
+    /// Models uploads may ask for; always includes the default model
+    pub allowed_model_ids: Vec<String>,
+    pub date_only_time: DateOnlyTime,
+    /// How far in the future a receipt may be dated before it is marked for review, for till clocks running ahead
+    pub future_allowance: Duration,
+    /// How long to stop submitting to Azure after a quota error that doesn't say when to retry; zero disables the pause
+    pub quota_cooldown: Duration,
+    pub export_decimal_separator: DecimalSeparator,
+    /// Amounts in exported files are rounded to this many decimal places
+    pub export_decimal_places: usize,
+    /// How many cache entries are read (and, when repopulating, saved) at once
+    pub cache_read_concurrency: usize,
+    /// Most cache entries a single repopulation call processes; unset processes the whole cache
+    pub repopulate_max_entries: Option<usize>,
+    pub zero_price_policy: ZeroPricePolicy,
+    /// Store responses that don't match our model of Azure's output, to fix the model against later
+    pub keep_parse_failures: bool,
+    /// Store items without a detected price (with no price) instead of dropping them
+    pub keep_priceless_items: bool,
+    /// Book the total of a receipt without any detected items as a single item named after the merchant
+    pub synthesize_missing_items: bool,
+    /// Book what a receipt's total has beyond the sum of its items as an extra, flagged item
+    pub add_unattributed_lines: bool,
+    /// Tried in order when a new product is stored; the first matching rule gives its category
+    pub category_rules: Vec<CategoryRule>,
+    /// Tried in order when a receipt is stored; the first rule matching its merchant gives its category, else it is `uncategorized`
+    pub merchant_category_rules: Vec<CategoryRule>,
+    /// Tried in the order listed; the first quirk matching the merchant whose format reads the printed date decides it
+    pub merchant_date_quirks: Vec<MerchantDateQuirk>,
+    /// Currencies assumed for receipts printing none, by the country of the merchant's address
+    pub country_currencies: Vec<CountryCurrency>,
+    /// Currency assumed for receipts printing none whose country has no entry in `country_currencies`
+    pub default_currency: Option<String>,
+    /// Most URLs a single `/analyze-urls` call may submit
+    pub analyze_urls_max: usize,
+    /// Largest request body accepted by `/upload`; base64 uploads may be a third larger
+    pub upload_limit_bytes: usize,
+    /// Uploaded files larger than this are rejected before analysis; unset leaves only the request body limit
+    pub max_upload_file_bytes: Option<usize>,
+    /// Connection string of a read-only replica for listings, exports and analytics; unset uses the primary database
+    pub read_replica_url: Option<String>,
+    /// Receives a signed JSON summary whenever processing an analysis finishes, successfully or not
+    pub completion_webhook_url: Option<String>,
+    /// Longest a request may take before it is answered with `504`; uploads are exempt
+    pub request_timeout: Duration,
+    /// Timezone of the wall-clock times printed on receipts
+    pub receipt_timezone: chrono_tz::Tz,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            duplicate_policy: DuplicatePolicy::Flag,
+            duplicate_total_tolerance: 0.01,
+            reanalysis_policy: ReanalysisPolicy::Latest,
+            analysis_concurrency: 4,
+            analysis_queue_depth: 16,
+            analysis_queue_timeout: Duration::from_secs(10),
+            analysis_poll_timeout: Duration::from_secs(300),
+            analysis_first_poll_delay: Duration::from_secs(2),
+            cache_ttl: None,
+            azure_max_retries: 3,
+            default_quantity: 1.0,
+            merchant_fallbacks: vec![MerchantFallback::Address, MerchantFallback::Phone],
+            default_model_id: "prebuilt-receipt".into(),
+            api_version: AzureApiVersion::V2023_07_31,
+            allowed_model_ids: vec!["prebuilt-receipt".into()],
+            date_only_time: DateOnlyTime::Noon,
+            future_allowance: Duration::from_secs(15 * 60),
+            quota_cooldown: Duration::from_secs(60 * 60),
+            export_decimal_separator: DecimalSeparator::Dot,
+            export_decimal_places: 2,
+            cache_read_concurrency: 4,
+            repopulate_max_entries: None,
+            zero_price_policy: ZeroPricePolicy::Keep,
+            keep_parse_failures: false,
+            keep_priceless_items: false,
+            synthesize_missing_items: false,
+            add_unattributed_lines: false,
+            category_rules: vec![],
+            merchant_category_rules: vec![],
+            // Azure flips day and month of Netto's year-first dates
+            merchant_date_quirks: vec![MerchantDateQuirk {
+                pattern: "netto".into(),
+                format: "%Y-%m-%d".into(),
+            }],
+            country_currencies: vec![],
+            default_currency: None,
+            analyze_urls_max: 20,
+            upload_limit_bytes: 10 * 1024 * 1024,
+            max_upload_file_bytes: None,
+            read_replica_url: None,
+            completion_webhook_url: None,
+            request_timeout: Duration::from_secs(30),
+            receipt_timezone: chrono_tz::Europe::Copenhagen,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_secrets(secret_store: &SecretStore) -> Result<Self, String> {
+        let default = Self::default();
+        let duplicate_total_tolerance = parse_secret(secret_store, "DUPLICATE_TOTAL_TOLERANCE")?
+            .unwrap_or(default.duplicate_total_tolerance);
+        if duplicate_total_tolerance.is_nan() || duplicate_total_tolerance < 0.0 {
+            return Err("DUPLICATE_TOTAL_TOLERANCE must be a non-negative number".into());
+        }
+        let analysis_concurrency = parse_secret(secret_store, "ANALYSIS_CONCURRENCY")?
+            .unwrap_or(default.analysis_concurrency);
+        if analysis_concurrency == 0 {
+            return Err("ANALYSIS_CONCURRENCY must be at least 1".into());
+        }
+        let default_quantity = parse_secret(secret_store, "DEFAULT_ITEM_QUANTITY")?
+            .unwrap_or(default.default_quantity);
+        if !default_quantity.is_finite() || default_quantity <= 0.0 {
+            return Err("DEFAULT_ITEM_QUANTITY must be a positive number".into());
+        }
+        let cache_read_concurrency = parse_secret(secret_store, "CACHE_READ_CONCURRENCY")?
+            .unwrap_or(default.cache_read_concurrency);
+        if cache_read_concurrency == 0 {
+            return Err("CACHE_READ_CONCURRENCY must be at least 1".into());
+        }
+        // `UPLOAD_LIMIT_MB` is the friendlier setting; `UPLOAD_LIMIT_BYTES` keeps working for existing deployments
+        let upload_limit_bytes = match parse_secret::<usize>(secret_store, "UPLOAD_LIMIT_MB")? {
+            Some(mb) => mb.saturating_mul(1024 * 1024),
+            None => parse_secret(secret_store, "UPLOAD_LIMIT_BYTES")?
+                .unwrap_or(default.upload_limit_bytes),
+        };
+        if upload_limit_bytes == 0 || upload_limit_bytes > MAX_UPLOAD_LIMIT_BYTES {
+            return Err(format!(
+                "The upload limit must be between 1 byte and {} MB",
+                MAX_UPLOAD_LIMIT_BYTES / 1024 / 1024
+            ));
+        }
+        let analysis_first_poll_delay =
+            parse_secret(secret_store, "ANALYSIS_FIRST_POLL_DELAY_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(default.analysis_first_poll_delay);
+        if analysis_first_poll_delay.is_zero() {
+            return Err("ANALYSIS_FIRST_POLL_DELAY_SECS must be at least 1".into());
+        }
+        let cache_ttl = parse_secret(secret_store, "CACHE_TTL_DAYS")?
+            .map(|days: u64| {
+                crate::cache::ttl_from_days(days)
+                    .filter(|&ttl| crate::cache::prune_cutoff(chrono::Utc::now(), ttl).is_some())
+                    .ok_or_else(|| format!("CACHE_TTL_DAYS of {days} days is out of range"))
+            })
+            .transpose()?;
+        let default_model_id = secret_store
+            .get("AZURE_MODEL_ID")
+            .map(|model_id| model_id.trim().to_string())
+            .unwrap_or(default.default_model_id);
+        let mut allowed_model_ids = secret_store
+            .get("AZURE_ALLOWED_MODEL_IDS")
+            .map(|raw| {
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|model_id| !model_id.is_empty())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if !allowed_model_ids.contains(&default_model_id) {
+            allowed_model_ids.push(default_model_id.clone());
+        }
+        Ok(Self {
+            duplicate_policy: parse_secret(secret_store, "DUPLICATE_RECEIPT_POLICY")?
+                .unwrap_or(default.duplicate_policy),
+            duplicate_total_tolerance,
+            reanalysis_policy: parse_secret(secret_store, "REANALYSIS_POLICY")?
+                .unwrap_or(default.reanalysis_policy),
+            analysis_concurrency,
+            analysis_queue_depth: parse_secret(secret_store, "ANALYSIS_QUEUE_DEPTH")?
+                .unwrap_or(default.analysis_queue_depth),
+            analysis_queue_timeout: parse_secret(secret_store, "ANALYSIS_QUEUE_TIMEOUT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(default.analysis_queue_timeout),
+            analysis_poll_timeout: parse_secret(secret_store, "ANALYSIS_POLL_TIMEOUT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(default.analysis_poll_timeout),
+            analysis_first_poll_delay,
+            cache_ttl,
+            azure_max_retries: parse_secret(secret_store, "AZURE_MAX_RETRIES")?
+                .unwrap_or(default.azure_max_retries),
+            default_quantity,
+            merchant_fallbacks: secret_store
+                .get("MERCHANT_NAME_FALLBACKS")
+                .map(|raw| parse_list(&raw))
+                .transpose()
+                .map_err(|err| format!("Invalid value for MERCHANT_NAME_FALLBACKS: {err}"))?
+                .unwrap_or(default.merchant_fallbacks),
+            default_model_id,
+            api_version: parse_secret(secret_store, "AZURE_API_VERSION")?
+                .unwrap_or(default.api_version),
+            allowed_model_ids,
+            date_only_time: parse_secret(secret_store, "DATE_ONLY_TIME")?
+                .unwrap_or(default.date_only_time),
+            future_allowance: parse_secret(secret_store, "FUTURE_RECEIPT_ALLOWANCE_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(default.future_allowance),
+            quota_cooldown: parse_secret(secret_store, "AZURE_QUOTA_COOLDOWN_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(default.quota_cooldown),
+            export_decimal_separator: parse_secret(secret_store, "EXPORT_DECIMAL_SEPARATOR")?
+                .unwrap_or(default.export_decimal_separator),
+            export_decimal_places: parse_secret(secret_store, "EXPORT_DECIMAL_PLACES")?
+                .unwrap_or(default.export_decimal_places),
+            cache_read_concurrency,
+            repopulate_max_entries: parse_secret(secret_store, "REPOPULATE_MAX_ENTRIES")?,
+            zero_price_policy: parse_secret(secret_store, "ZERO_PRICE_ITEMS")?
+                .unwrap_or(default.zero_price_policy),
+            keep_parse_failures: parse_secret(secret_store, "KEEP_PARSE_FAILURES")?
+                .unwrap_or(default.keep_parse_failures),
+            keep_priceless_items: parse_secret(secret_store, "KEEP_PRICELESS_ITEMS")?
+                .unwrap_or(default.keep_priceless_items),
+            synthesize_missing_items: parse_secret(secret_store, "SYNTHESIZE_MISSING_ITEMS")?
+                .unwrap_or(default.synthesize_missing_items),
+            add_unattributed_lines: parse_secret(secret_store, "ADD_UNATTRIBUTED_LINES")?
+                .unwrap_or(default.add_unattributed_lines),
+            category_rules: secret_store
+                .get("CATEGORY_RULES")
+                .map(|raw| parse_list(&raw))
+                .transpose()
+                .map_err(|err| format!("Invalid value for CATEGORY_RULES: {err}"))?
+                .unwrap_or(default.category_rules),
+            merchant_category_rules: secret_store
+                .get("MERCHANT_CATEGORY_RULES")
+                .map(|raw| parse_list(&raw))
+                .transpose()
+                .map_err(|err| format!("Invalid value for MERCHANT_CATEGORY_RULES: {err}"))?
+                .unwrap_or(default.merchant_category_rules),
+            merchant_date_quirks: secret_store
+                .get("MERCHANT_DATE_QUIRKS")
+                .map(|raw| parse_list(&raw))
+                .transpose()
+                .map_err(|err| format!("Invalid value for MERCHANT_DATE_QUIRKS: {err}"))?
+                .unwrap_or(default.merchant_date_quirks),
+            country_currencies: secret_store
+                .get("COUNTRY_CURRENCIES")
+                .map(|raw| parse_list(&raw))
+                .transpose()
+                .map_err(|err| format!("Invalid value for COUNTRY_CURRENCIES: {err}"))?
+                .unwrap_or(default.country_currencies),
+            default_currency: secret_store
+                .get("DEFAULT_CURRENCY")
+                .map(|code| code.trim().to_uppercase())
+                .filter(|code| !code.is_empty()),
+            analyze_urls_max: parse_secret(secret_store, "ANALYZE_URLS_MAX")?
+                .unwrap_or(default.analyze_urls_max),
+            upload_limit_bytes,
+            max_upload_file_bytes: parse_secret(secret_store, "UPLOAD_MAX_FILE_BYTES")?,
+            read_replica_url: secret_store
+                .get("DATABASE_READ_REPLICA_URL")
+                .filter(|url| !url.trim().is_empty()),
+            completion_webhook_url: secret_store
+                .get("COMPLETION_WEBHOOK_URL")
+                .filter(|url| !url.trim().is_empty()),
+            request_timeout: parse_secret(secret_store, "REQUEST_TIMEOUT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(default.request_timeout),
+            receipt_timezone: parse_secret(secret_store, "RECEIPT_TIMEZONE")?
+                .unwrap_or(default.receipt_timezone),
+        })
+    }
+}
+
+/// Azure rejects larger files anyway, and buffering more than this per upload risks running out of memory.
+const MAX_UPLOAD_LIMIT_BYTES: usize = 500 * 1024 * 1024;
+
+fn parse_secret<T>(secret_store: &SecretStore, key: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    secret_store
+        .get(key)
+        .map(|raw| {
+            raw.parse::<T>()
+                .map_err(|err| format!("Invalid value for {key}: {err}"))
+        })
+        .transpose()
+}
+
+/// Parses a comma-separated list, where an empty string is an empty list.
+fn parse_list<T: FromStr>(raw: &str) -> Result<Vec<T>, T::Err> {
+    raw.split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
@@ -0,0 +1,121 @@
+//! Drift between the persist cache and the DB, e.g. after a failed save or a cache eviction.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use axum::extract::State;
+use serde::Serialize;
+use sqlx::PgConnection;
+use tracing::Instrument;
+
+use crate::{
+    analysis_span, cache, cached_hashes, file_hash::FileHash, save_cached_analysis, AppError,
+    AppState,
+};
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Consistency {
+    /// Cached responses without a receipt; includes pending and failed analyses, which never get one
+    pub cache_only: Vec<FileHash>,
+    /// Receipts whose cache entry is gone
+    pub db_only: Vec<FileHash>,
+}
+
+pub fn compare(cache_hashes: Vec<FileHash>, db_hashes: Vec<FileHash>) -> Consistency {
+    let cached = cache_hashes.into_iter().collect::<BTreeSet<_>>();
+    let stored = db_hashes.into_iter().collect::<BTreeSet<_>>();
+    Consistency {
+        cache_only: cached.difference(&stored).cloned().collect(),
+        db_only: stored.difference(&cached).cloned().collect(),
+    }
+}
+
+/// Files stored in the DB that should have a cache entry. Google imports never get one; they are kept in `google_imports` instead.
+pub async fn db_hashes(conn: &mut PgConnection) -> Result<Vec<FileHash>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT file_sha256 AS "file_sha256!: FileHash" FROM (SELECT file_sha256 FROM receipts WHERE file_sha256 IS NOT NULL UNION ALL SELECT file_sha256 FROM merged_files) stored WHERE NOT EXISTS (SELECT 1 FROM google_imports WHERE google_imports.file_sha256 = stored.file_sha256)"#
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Marks the receipts of `db_only` files for review, since they can no longer be re-extracted.
+pub async fn flag_for_review(
+    conn: &mut PgConnection,
+    db_only: &[FileHash],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE receipts SET needs_review = true WHERE file_sha256 = ANY($1)",
+        db_only as &[FileHash]
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn consistency(app_state: &AppState) -> Result<Consistency, AppError> {
+    let cache_hashes = cached_hashes(app_state)?;
+    let db_hashes = db_hashes(&mut *app_state.pool.acquire().await?).await?;
+    Ok(compare(cache_hashes, db_hashes))
+}
+
+pub async fn check_consistency(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<Consistency>, AppError> {
+    Ok(axum::Json(consistency(&app_state).await?))
+}
+
+/// Saves the receipts of cache-only entries in the background and marks DB-only receipts for review, since they can no longer be re-extracted.
+pub async fn reconcile(State(app_state): State<Arc<AppState>>) -> Result<String, AppError> {
+    let Consistency {
+        cache_only,
+        db_only,
+    } = consistency(&app_state).await?;
+    flag_for_review(&mut *app_state.pool.acquire().await?, &db_only).await?;
+
+    let msg = format!(
+        "Flagged {} receipts missing from the cache for review and enqueued saving {} cached analysis results missing from the DB",
+        db_only.len(),
+        cache_only.len()
+    );
+    let width = app_state.config.cache_read_concurrency;
+    tokio::spawn(async move {
+        cache::load_bounded(cache_only, width, |file_hash| {
+            let app_state = app_state.clone();
+            let span = analysis_span(&file_hash);
+            async move { save_cached_analysis(&app_state, &file_hash).await }.instrument(span)
+        })
+        .await;
+    });
+    tracing::info!(msg);
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_hashes_missing_on_either_side() {
+        let hashes = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.repeat(64).parse().unwrap())
+                .collect()
+        };
+        let consistency = compare(hashes(&["c", "a", "b"]), hashes(&["b", "d"]));
+        assert_eq!(
+            consistency,
+            Consistency {
+                cache_only: hashes(&["a", "c"]),
+                db_only: hashes(&["d"]),
+            }
+        );
+        assert_eq!(
+            compare(hashes(&["a"]), hashes(&["a"])),
+            Consistency {
+                cache_only: vec![],
+                db_only: vec![],
+            }
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Errors returned by handlers, grouped by what went wrong. Clients get a message safe to show them; the full error is only logged.
+
+use std::time::Duration;
+
+use axum::{extract::multipart::MultipartError, response::IntoResponse};
+use reqwest::{header::ToStrError, header::RETRY_AFTER, StatusCode};
+use serde::Serialize;
+use shuttle_persist::PersistError;
+use thiserror::Error;
+
+use crate::{analysis_queue, cache, file_hash::InvalidFileHash};
+
+/// The request itself is unacceptable; the message tells the client what to change.
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+}
+
+/// Getting a file analyzed failed: receiving it, talking to Azure or making sense of its answer.
+#[derive(Error, Debug)]
+pub enum IngestError {
+    #[error(transparent)]
+    Multipart(#[from] MultipartError),
+    #[error(transparent)]
+    ToStr(#[from] ToStrError),
+    #[error(transparent)]
+    EncodeSlice(#[from] base64::EncodeSliceError),
+    #[error(transparent)]
+    HttpClient(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    JsonPath(#[from] cache::ParseError),
+    #[error(transparent)]
+    ChronoParse(#[from] chrono::ParseError),
+    /// Azure answered, but without a usable result
+    #[error("{0}")]
+    Analysis(String),
+    #[error("Too many receipts are being analyzed right now, retry in {} seconds", .0.retry_after.as_secs())]
+    AnalysisBusy(analysis_queue::Busy),
+    #[error("Azure analysis quota is exhausted: {message}")]
+    QuotaExceeded {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+}
+
+/// Reading or writing the DB or the cache failed.
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+    #[error(transparent)]
+    ShuttlePersist(#[from] PersistError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    Validation,
+    Ingest,
+    Storage,
+    Internal,
+}
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum AppError {
+    Validation(#[from] ValidationError),
+    Ingest(#[from] IngestError),
+    Storage(#[from] StorageError),
+    /// Bugs and everything else nobody anticipated
+    Internal(#[from] anyhow::Error),
+}
+
+impl AppError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ValidationError::BadRequest(message.into()).into()
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ValidationError::NotFound(message.into()).into()
+    }
+
+    pub fn unsupported_media_type(message: impl Into<String>) -> Self {
+        ValidationError::UnsupportedMediaType(message.into()).into()
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            AppError::Validation(_) => ErrorCategory::Validation,
+            AppError::Ingest(_) => ErrorCategory::Ingest,
+            AppError::Storage(_) => ErrorCategory::Storage,
+            AppError::Internal(_) => ErrorCategory::Internal,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Validation(ValidationError::BadRequest(_)) => StatusCode::BAD_REQUEST,
+            AppError::Validation(ValidationError::NotFound(_)) => StatusCode::NOT_FOUND,
+            AppError::Validation(ValidationError::UnsupportedMediaType(_)) => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            // `ToStr` comes from headers of Azure's responses rather than the client's request, so it stays a server error
+            AppError::Ingest(IngestError::Multipart(_)) => StatusCode::BAD_REQUEST,
+            AppError::Ingest(IngestError::AnalysisBusy(_)) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Ingest(IngestError::QuotaExceeded { .. }) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Ingest(IngestError::HttpClient(err)) if err.is_timeout() => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            AppError::Storage(StorageError::Sqlx(sqlx::Error::RowNotFound)) => {
+                StatusCode::NOT_FOUND
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable counterpart of [`AppError::public_message`], e.g. `not_found`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Validation(ValidationError::BadRequest(_)) => "bad_request",
+            AppError::Validation(ValidationError::NotFound(_)) => "not_found",
+            AppError::Validation(ValidationError::UnsupportedMediaType(_)) => {
+                "unsupported_media_type"
+            }
+            AppError::Ingest(IngestError::Multipart(_)) => "invalid_upload",
+            AppError::Ingest(IngestError::AnalysisBusy(_)) => "analysis_busy",
+            AppError::Ingest(IngestError::QuotaExceeded { .. }) => "quota_exceeded",
+            AppError::Ingest(IngestError::HttpClient(err)) if err.is_timeout() => {
+                "analysis_timeout"
+            }
+            AppError::Ingest(_) => "analysis_failed",
+            AppError::Storage(StorageError::Sqlx(sqlx::Error::RowNotFound)) => "not_found",
+            AppError::Storage(_) => "storage",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    /// What the client is told; internal details like SQL or Azure responses stay in the logs.
+    pub fn public_message(&self) -> String {
+        match self {
+            AppError::Validation(err) => err.to_string(),
+            AppError::Ingest(IngestError::Multipart(err)) => format!("Invalid upload: {err}"),
+            AppError::Ingest(
+                err @ (IngestError::AnalysisBusy(_) | IngestError::QuotaExceeded { .. }),
+            ) => err.to_string(),
+            AppError::Ingest(IngestError::HttpClient(err)) if err.is_timeout() => {
+                "Azure did not answer in time".into()
+            }
+            AppError::Ingest(_) => "The receipt could not be analyzed".into(),
+            AppError::Storage(StorageError::Sqlx(sqlx::Error::RowNotFound)) => {
+                "The requested record does not exist".into()
+            }
+            AppError::Storage(_) => "Stored receipts could not be read or written".into(),
+            AppError::Internal(_) => "Something went wrong".into(),
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            AppError::Ingest(IngestError::AnalysisBusy(busy)) => Some(busy.retry_after),
+            AppError::Ingest(IngestError::QuotaExceeded { retry_after, .. }) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+macro_rules! app_error_from {
+    ($category:ident, $($source:ty),+) => {
+        $(impl From<$source> for AppError {
+            fn from(err: $source) -> Self {
+                AppError::$category(err.into())
+            }
+        })+
+    };
+}
+
+app_error_from!(
+    Ingest,
+    MultipartError,
+    ToStrError,
+    base64::EncodeSliceError,
+    reqwest::Error,
+    serde_json::Error,
+    cache::ParseError,
+    chrono::ParseError
+);
+app_error_from!(Storage, sqlx::Error, PersistError);
+app_error_from!(
+    Internal,
+    csv::Error,
+    std::string::FromUtf8Error,
+    std::io::Error
+);
+
+impl From<InvalidFileHash> for AppError {
+    fn from(err: InvalidFileHash) -> Self {
+        AppError::bad_request(err.to_string())
+    }
+}
+
+/// Body of every error response.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: String,
+    pub kind: &'static str,
+}
+
+// Tell axum how to convert `AppError` into a response.
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        if self.category() != ErrorCategory::Validation {
+            tracing::error!("{:?} error: {}", self.category(), self);
+        }
+        let body = ErrorBody {
+            error: self.public_message(),
+            kind: self.kind(),
+        };
+        let mut response = (self.status(), axum::Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after() {
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                retry_after.as_secs().to_string().parse().unwrap(),
+            );
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_categories_to_status_and_public_message() {
+        let cases: Vec<(AppError, ErrorCategory, StatusCode, &str, &str)> = vec![
+            (
+                AppError::bad_request("Tags must not be empty"),
+                ErrorCategory::Validation,
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                "Tags must not be empty",
+            ),
+            (
+                AppError::not_found("Receipt 7 does not exist"),
+                ErrorCategory::Validation,
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Receipt 7 does not exist",
+            ),
+            (
+                serde_json::from_str::<i32>("{").unwrap_err().into(),
+                ErrorCategory::Ingest,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "analysis_failed",
+                "The receipt could not be analyzed",
+            ),
+            (
+                IngestError::QuotaExceeded {
+                    message: "Out of call volume quota".into(),
+                    retry_after: Some(Duration::from_secs(60)),
+                }
+                .into(),
+                ErrorCategory::Ingest,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "quota_exceeded",
+                "Azure analysis quota is exhausted: Out of call volume quota",
+            ),
+            (
+                sqlx::Error::RowNotFound.into(),
+                ErrorCategory::Storage,
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "The requested record does not exist",
+            ),
+            (
+                sqlx::Error::PoolTimedOut.into(),
+                ErrorCategory::Storage,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "storage",
+                "Stored receipts could not be read or written",
+            ),
+            (
+                anyhow::anyhow!("Missing analyzeResult field").into(),
+                ErrorCategory::Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Something went wrong",
+            ),
+        ];
+        for (err, category, status, kind, message) in cases {
+            assert_eq!(err.category(), category, "{err}");
+            assert_eq!(err.status(), status, "{err}");
+            assert_eq!(err.kind(), kind, "{err}");
+            assert_eq!(err.public_message(), message, "{err}");
+        }
+    }
+
+    #[test]
+    fn keep_internal_details_out_of_the_response() {
+        let err: AppError =
+            sqlx::Error::Protocol("relation \"receipts\" does not exist".into()).into();
+        assert!(err.to_string().contains("relation"));
+        assert!(!err.public_message().contains("relation"));
+
+        let response = AppError::from(IngestError::QuotaExceeded {
+            message: "Out of call volume quota".into(),
+            retry_after: Some(Duration::from_secs(60)),
+        })
+        .into_response();
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+        assert_eq!(
+            response.headers()[reqwest::header::CONTENT_TYPE],
+            "application/json"
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! The `/download` export: locale-aware CSV by default, or JSON.
+
+use axum::http::{HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, DecimalSeparator},
+    AllData, AppError,
+};
+
+/// Newest `created_at` among the exported receipts; passed back as `since`, the next export continues after them.
+pub const CURSOR_HEADER: &str = "x-export-cursor";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportParams {
+    /// Only include receipts stored after this instant, e.g. the cursor of the previous export
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// Array of the rows with plain numbers, ignoring the CSV locale settings
+    Json,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DownloadParams {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    pub fn content_disposition(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "attachment; filename=\"data.csv\"",
+            ExportFormat::Json => "attachment; filename=\"data.json\"",
+        }
+    }
+}
+
+/// With no new receipts the cursor stays at `since`, so the client can store it unconditionally.
+pub fn cursor_headers(
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(cursor) = until.or(since) {
+        let value = cursor.to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+        headers.insert(CURSOR_HEADER, HeaderValue::from_str(&value).unwrap());
+    }
+    headers
+}
+
+/// A row of the export with amounts already formatted for the configured locale.
+#[derive(Serialize)]
+struct ExportRow {
+    name: String,
+    unit_price: String,
+    count: String,
+    quantity_unit: Option<String>,
+    currency_code: Option<String>,
+    items_match_total: Option<bool>,
+    category: String,
+    merchant_name: String,
+    paid_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Fixed number of decimal places with the configured separator, e.g. `12.50` or `12,50`.
+pub fn format_amount(value: f64, config: &Config) -> String {
+    let formatted = format!("{value:.*}", config.export_decimal_places);
+    match config.export_decimal_separator {
+        DecimalSeparator::Dot => formatted,
+        DecimalSeparator::Comma => formatted.replace('.', ","),
+    }
+}
+
+/// Encodes the export a row at a time, so it is sent while rows are still being read instead of being built in memory.
+pub struct ExportEncoder {
+    format: ExportFormat,
+    config: Config,
+    /// Comma-decimal locales expect `;` between CSV fields, as spreadsheets there do
+    delimiter: u8,
+    rows: usize,
+}
+
+impl ExportEncoder {
+    pub fn new(format: ExportFormat, config: &Config) -> Self {
+        let delimiter = match config.export_decimal_separator {
+            DecimalSeparator::Dot => b',',
+            DecimalSeparator::Comma => b';',
+        };
+        Self {
+            format,
+            config: config.clone(),
+            delimiter,
+            rows: 0,
+        }
+    }
+
+    /// The bytes of the next row; the CSV header comes with the first one.
+    pub fn row(&mut self, row: AllData) -> Result<Vec<u8>, AppError> {
+        self.rows += 1;
+        match self.format {
+            ExportFormat::Csv => {
+                let config = &self.config;
+                let mut writer = csv::WriterBuilder::new()
+                    .delimiter(self.delimiter)
+                    .has_headers(self.rows == 1)
+                    .from_writer(vec![]);
+                writer.serialize(ExportRow {
+                    unit_price: row
+                        .unit_price
+                        .map(|unit_price| format_amount(unit_price, config))
+                        .unwrap_or_default(),
+                    count: format_amount(row.count, config),
+                    quantity_unit: row.quantity_unit,
+                    currency_code: row.currency_code,
+                    items_match_total: row.items_match_total,
+                    category: row.category,
+                    name: row.name,
+                    merchant_name: row.merchant_name,
+                    paid_at: row.paid_at,
+                })?;
+                Ok(writer.into_inner().map_err(|err| err.into_error())?)
+            }
+            ExportFormat::Json => {
+                let mut chunk = if self.rows == 1 { b"[" } else { b"," }.to_vec();
+                serde_json::to_writer(&mut chunk, &row).map_err(anyhow::Error::from)?;
+                Ok(chunk)
+            }
+        }
+    }
+
+    /// The bytes closing the export, after the last row.
+    pub fn finish(self) -> Vec<u8> {
+        match self.format {
+            ExportFormat::Csv => vec![],
+            ExportFormat::Json if self.rows == 0 => b"[]".to_vec(),
+            ExportFormat::Json => b"]".to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use axum::extract::Query;
+
+    use super::{
+        cursor_headers, DownloadParams, ExportEncoder, ExportFormat, ExportParams, CURSOR_HEADER,
+    };
+    use crate::{
+        config::{Config, DecimalSeparator},
+        AllData,
+    };
+
+    fn rows() -> Vec<AllData> {
+        vec![AllData {
+            name: "MINIMÆLK".into(),
+            unit_price: Some(12.5),
+            count: 1.0 / 3.0,
+            quantity_unit: Some("kg".into()),
+            currency_code: Some("DKK".into()),
+            items_match_total: Some(true),
+            category: "groceries".into(),
+            merchant_name: "Netto".into(),
+            paid_at: chrono::Utc.with_ymd_and_hms(2023, 9, 10, 18, 0, 0).unwrap(),
+        }]
+    }
+
+    fn encode(format: ExportFormat, rows: Vec<AllData>, config: &Config) -> String {
+        let mut encoder = ExportEncoder::new(format, config);
+        let mut content = vec![];
+        for row in rows {
+            content.extend(encoder.row(row).unwrap());
+        }
+        content.extend(encoder.finish());
+        String::from_utf8(content).unwrap()
+    }
+
+    #[test]
+    fn format_amounts_for_export_locale() {
+        let csv = encode(ExportFormat::Csv, rows(), &Config::default());
+        assert_eq!(
+            csv,
+            "name,unit_price,count,quantity_unit,currency_code,items_match_total,category,merchant_name,paid_at\nMINIMÆLK,12.50,0.33,kg,DKK,true,groceries,Netto,2023-09-10T18:00:00Z\n"
+        );
+
+        let config = Config {
+            export_decimal_separator: DecimalSeparator::Comma,
+            export_decimal_places: 3,
+            ..Config::default()
+        };
+        let csv = encode(ExportFormat::Csv, rows(), &config);
+        assert_eq!(
+            csv,
+            "name;unit_price;count;quantity_unit;currency_code;items_match_total;category;merchant_name;paid_at\nMINIMÆLK;12,500;0,333;kg;DKK;true;groceries;Netto;2023-09-10T18:00:00Z\n"
+        );
+
+        let csv = encode(
+            ExportFormat::Csv,
+            rows().into_iter().chain(rows()).collect(),
+            &Config::default(),
+        );
+        assert_eq!(csv.matches("name,unit_price").count(), 1);
+        assert_eq!(csv.lines().count(), 3);
+
+        let mut counted = rows();
+        counted[0].quantity_unit = None;
+        counted[0].currency_code = None;
+        counted[0].items_match_total = None;
+        assert!(encode(ExportFormat::Csv, counted, &Config::default())
+            .ends_with("\nMINIMÆLK,12.50,0.33,,,,groceries,Netto,2023-09-10T18:00:00Z\n"));
+        assert_eq!(encode(ExportFormat::Csv, vec![], &Config::default()), "");
+    }
+
+    #[test]
+    fn export_json_on_request() {
+        let format = |query: &str| {
+            let uri = format!("/download?{query}").parse().unwrap();
+            Query::<DownloadParams>::try_from_uri(&uri).map(|params| params.0.format)
+        };
+        assert_eq!(format("").unwrap(), ExportFormat::Csv);
+        assert_eq!(format("format=json").unwrap(), ExportFormat::Json);
+        assert!(format("format=xml").is_err());
+
+        let config = Config {
+            export_decimal_separator: DecimalSeparator::Comma,
+            ..Config::default()
+        };
+        let json = encode(
+            ExportFormat::Json,
+            rows().into_iter().chain(rows()).collect(),
+            &config,
+        );
+        let row = serde_json::json!({
+            "name": "MINIMÆLK",
+            "unit_price": 12.5,
+            "count": 1.0 / 3.0,
+            "quantity_unit": "kg",
+            "currency_code": "DKK",
+            "items_match_total": true,
+            "category": "groceries",
+            "merchant_name": "Netto",
+            "paid_at": "2023-09-10T18:00:00Z"
+        });
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!([row, row])
+        );
+        assert_eq!(encode(ExportFormat::Json, vec![], &config), "[]");
+    }
+
+    #[test]
+    fn advance_cursor_to_newest_exported_receipt() {
+        let uri = "/download?since=2023-10-07T12:00:00.5Z".parse().unwrap();
+        let since = Query::<ExportParams>::try_from_uri(&uri).unwrap().0.since;
+        assert_eq!(
+            since,
+            Some(
+                chrono::Utc.with_ymd_and_hms(2023, 10, 7, 12, 0, 0).unwrap()
+                    + chrono::Duration::milliseconds(500)
+            )
+        );
+
+        let until = Some(chrono::Utc.with_ymd_and_hms(2023, 10, 8, 9, 30, 0).unwrap());
+        assert_eq!(
+            cursor_headers(until, since)[CURSOR_HEADER],
+            "2023-10-08T09:30:00.000000Z"
+        );
+        // Nothing new, so the client keeps its cursor
+        assert_eq!(
+            cursor_headers(None, since)[CURSOR_HEADER],
+            "2023-10-07T12:00:00.500000Z"
+        );
+        assert!(cursor_headers(None, None).is_empty());
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use chrono::TimeZone;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    config::{Config, MerchantFallback, ZeroPricePolicy},
+    errors::IngestError,
+    manual::{
+        AnalyzeResultOperation, BoundingRegion, DocType, DocumentFields, Receipt,
+        SelectionMarkObject, SelectionMarkState,
+    },
+    reconcile, AppError, BIND_LIMIT,
+};
+
+/// A single purchased line as extracted from the analysis results.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineItem {
+    pub name: String,
+    pub count: f64,
+    /// `None` only for items kept despite having no detected price
+    pub unit_price: Option<f64>,
+    /// No quantity was detected, so `count` holds the configured default
+    pub quantity_defaulted: bool,
+    /// What `count` is measured in as printed, e.g. `kg` or `stk`, normalized by [`normalize_unit`]
+    pub quantity_unit: Option<String>,
+    /// Article number as printed, e.g. an EAN; identifies the product across OCR variants of its name
+    pub product_code: Option<String>,
+    /// No price was detected; any `unit_price` was filled in by hand
+    pub price_missing: bool,
+    /// Nothing was itemized, so this single line stands for the receipt's total
+    pub synthesized: bool,
+    /// Stands for the part of the total no detected line accounts for, e.g. lines lost to OCR
+    pub unattributed: bool,
+    /// Where the line was read on the receipt's pages; empty for lines not read from the receipt
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<BoundingRegion>,
+}
+
+/// Tax charged at one rate, as listed in the receipt's tax summary.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaxLine {
+    /// Percentage, e.g. `25.0` for Danish VAT
+    pub rate: Option<f64>,
+    /// Amount the tax was charged on
+    pub net_amount: Option<f64>,
+    pub amount: f64,
+    /// Currency of `amount`, the receipt's unless the tax summary names another one
+    pub currency_code: Option<String>,
+}
+
+/// Everything we keep from one analyzed receipt, independent of how it gets stored.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReceiptRecord {
+    pub merchant_name: String,
+    pub paid_at: chrono::DateTime<chrono_tz::Tz>,
+    pub total: f64,
+    /// Tax included in `total`, as printed
+    pub total_tax: Option<f64>,
+    pub currency_code: Option<String>,
+    /// Nothing on the receipt named a currency, so `currency_code` is the configured default
+    pub currency_inferred: bool,
+    pub items: Vec<LineItem>,
+    /// One entry per tax rate on the receipt
+    pub taxes: Vec<TaxLine>,
+    /// Full OCR text, kept for free-text search
+    pub text: String,
+    pub time_precision: TimePrecision,
+    /// State of each checkbox field on the receipt, by field name
+    pub selection_marks: BTreeMap<String, SelectionMarkState>,
+    /// Azure model that produced the analysis
+    pub model_id: Option<String>,
+    /// Something essential could not be extracted reliably and a human should look at the receipt
+    pub needs_review: bool,
+}
+
+impl ReceiptRecord {
+    /// The priced lines add up to the printed total, as [`reconcile::reconcile`] checks it.
+    pub fn items_match_total(&self) -> bool {
+        reconcile::reconcile(Some(self.total), &self.items, &self.taxes).balanced
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    MissingPrice,
+    ZeroPrice,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DroppedItem {
+    pub content: String,
+    pub reason: DropReason,
+}
+
+/// How much of `paid_at` was actually printed on the receipt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimePrecision {
+    /// Date and time of day
+    Time,
+    /// Only the date; the time of day is the configured default
+    Date,
+}
+
+impl TimePrecision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Time => "time",
+            Self::Date => "date",
+        }
+    }
+}
+
+/// Which field the merchant label was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MerchantSource {
+    Name,
+    Address,
+    Phone,
+    /// Nothing usable was found, the receipt needs review
+    Missing,
+}
+
+/// Which field the transaction date was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateSource {
+    ValueDate,
+    /// Raw text, read as a [`crate::config::MerchantDateQuirk`] of the merchant says
+    Content,
+    /// Raw text in one of [`CONTENT_DATE_FORMATS`], because the model gave no date value
+    ContentFallback,
+}
+
+/// Date formats tried on the printed text, in order. Two-digit years come before four-digit ones, as `%Y` would happily read `23` as year 23.
+pub const CONTENT_DATE_FORMATS: &[&str] = &[
+    "%Y-%m-%d", "%Y/%m/%d", "%Y.%m.%d", "%d.%m.%y", "%d-%m-%y", "%d/%m/%y", "%d %m %y", "%d.%m.%Y",
+    "%d-%m-%Y", "%d/%m/%Y", "%d %m %Y",
+];
+
+/// Records the decisions made while extracting a receipt, to explain why the stored data looks the way it does.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ExtractionTrace {
+    pub dropped_items: Vec<DroppedItem>,
+    pub merchant_source: Option<MerchantSource>,
+    pub date_source: Option<DateSource>,
+    /// Format the date was parsed with, when it was read from the printed text
+    pub date_format: Option<String>,
+    pub datetime_string: Option<String>,
+}
+
+/// Brings the fields of every supported document type into the receipt schema. Unknown types are read as receipts when their fields fit.
+fn receipt_fields(doc_type: DocType, fields: DocumentFields) -> Result<Receipt, AppError> {
+    match fields {
+        DocumentFields::Receipt(receipt) => Ok(*receipt),
+        DocumentFields::Hotel(hotel) => Ok((*hotel).into()),
+        DocumentFields::Other(fields) => serde_json::from_value(serde_json::Value::Object(
+            fields.into_iter().collect(),
+        ))
+        .map_err(|err| {
+            IngestError::Analysis(format!(
+                "Documents of type {} are not supported: {}",
+                doc_type.0, err
+            ))
+            .into()
+        }),
+    }
+}
+
+pub fn extract_receipt(
+    analysis_result: AnalyzeResultOperation,
+    config: &Config,
+) -> Result<ReceiptRecord, AppError> {
+    extract_receipt_traced(analysis_result, config, &mut ExtractionTrace::default())
+}
+
+/// Same as [`extract_receipt`], filling in `trace` as far as extraction gets, even when it fails.
+pub fn extract_receipt_traced(
+    analysis_result: AnalyzeResultOperation,
+    config: &Config,
+    trace: &mut ExtractionTrace,
+) -> Result<ReceiptRecord, AppError> {
+    let analyze_result = analysis_result
+        .analyzeResult
+        .ok_or(anyhow!("Missing analyzeResult field"))?;
+    let model_id = analyze_result.modelId.0;
+    let text = analyze_result.content;
+    let document = analyze_result
+        .documents
+        .ok_or(anyhow!("Missing documents field"))?
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("Documents field is present but empty"))?;
+    let receipt_fields = receipt_fields(document.docType, document.fields)?;
+    let country = receipt_fields
+        .merchant_address
+        .as_ref()
+        .and_then(|address| address.value_address.as_ref())
+        .and_then(|address| address.countryRegion.as_deref());
+    let (currency_code, currency_inferred) =
+        infer_currency(document_currency(&receipt_fields), country, config);
+    let taxes = tax_lines(&receipt_fields, currency_code.as_deref());
+    let selection_marks = selection_marks(&receipt_fields);
+    let items_missing = receipt_fields.items.is_none();
+    if items_missing {
+        tracing::warn!("Analysis found no Items on the receipt, marking it for review");
+    }
+    let mut has_zero_price = false;
+    let items = receipt_fields
+        .items
+        .iter()
+        .flat_map(|items| &items.value_array)
+        .filter_map(|item| {
+            let unit_price = item
+                .value_object
+                .unit_price
+                .as_ref()
+                .or(item.value_object.total_price.as_ref())
+                .map(|obj| obj.value_number);
+            if unit_price.is_none() && !config.keep_priceless_items {
+                // We throw away items where no price was detected
+                trace.dropped_items.push(DroppedItem {
+                    content: item.content.clone(),
+                    reason: DropReason::MissingPrice,
+                });
+                return None;
+            }
+            if unit_price == Some(0.0) {
+                match config.zero_price_policy {
+                    ZeroPricePolicy::Keep => {}
+                    ZeroPricePolicy::Drop => {
+                        trace.dropped_items.push(DroppedItem {
+                            content: item.content.clone(),
+                            reason: DropReason::ZeroPrice,
+                        });
+                        return None;
+                    }
+                    ZeroPricePolicy::Flag => has_zero_price = true,
+                }
+            }
+            let name = item.value_object.description.value_string.clone();
+            let (count, quantity_defaulted) = match &item.value_object.quantity {
+                Some(q) => (q.value_number, false),
+                None => (default_quantity(&name, config), true),
+            };
+            let quantity_unit = item
+                .value_object
+                .quantity_unit
+                .as_ref()
+                .and_then(|unit| normalize_unit(&unit.value_string));
+            let product_code = item
+                .value_object
+                .product_code
+                .as_ref()
+                .and_then(|code| normalize_product_code(&code.value_string));
+            Some(LineItem {
+                name,
+                count,
+                price_missing: unit_price.is_none(),
+                unit_price,
+                quantity_defaulted,
+                quantity_unit,
+                product_code,
+                synthesized: false,
+                unattributed: false,
+                regions: item.bounding_regions.clone(),
+            })
+        })
+        .take(BIND_LIMIT)
+        .collect::<Vec<_>>();
+    let (merchant_source, merchant_name) = merchant_label(&receipt_fields, config);
+    trace.merchant_source = Some(merchant_source);
+    let total = receipt_fields.total.value_number;
+    let mut items = items;
+    synthesize_missing_items(&mut items, &merchant_name, total, config);
+    add_unattributed_line(&mut items, total, &taxes, config);
+
+    let transaction_date = receipt_fields.transaction_date;
+    let quirk_date = || {
+        config.merchant_date_quirks.iter().find_map(|quirk| {
+            quirk
+                .read(&merchant_name, &transaction_date.content)
+                .map(|date| (date, &quirk.format))
+        })
+    };
+    let fallback_date = || {
+        transaction_date
+            .value_date
+            .is_none()
+            .then(|| parse_content_date(&transaction_date.content))
+            .flatten()
+    };
+    let (date_source, date) = if let Some((date, format)) = quirk_date() {
+        trace.date_format = Some(format.clone());
+        (DateSource::Content, date)
+    } else if let Some((date, format)) = fallback_date() {
+        trace.date_format = Some(format.into());
+        (DateSource::ContentFallback, date)
+    } else {
+        let date = transaction_date.value_date.ok_or_else(|| {
+            anyhow!(
+                "No transaction date could be read from {:?}",
+                transaction_date.content
+            )
+        })?;
+        (DateSource::ValueDate, date)
+    };
+    trace.date_source = Some(date_source);
+
+    let time = receipt_fields.transaction_time.value_time;
+    trace.datetime_string = Some(match time {
+        Some(time) => format!("{date} {time}"),
+        None => date.to_string(),
+    });
+    let (timestamp, time_precision) = timestamp(date, time, config);
+    let paid_at = localize(timestamp, config)?;
+    // A printed time in a DST gap is rolled forward, which crosses midnight in zones whose gap starts at 23:00
+    let wrong_day = paid_at.date_naive() != timestamp.date();
+    if wrong_day {
+        tracing::warn!(
+            "Receipt printed on {} was converted to {}",
+            timestamp.date(),
+            paid_at
+        );
+    }
+    let future_dated = is_future_dated(paid_at, chrono::Utc::now(), config.future_allowance);
+    if future_dated {
+        tracing::warn!("Receipt is dated in the future: {}", paid_at);
+    }
+
+    Ok(ReceiptRecord {
+        merchant_name,
+        paid_at,
+        time_precision,
+        total,
+        total_tax: receipt_fields
+            .total_tax
+            .as_ref()
+            .map(|total_tax| total_tax.value_number),
+        currency_code,
+        currency_inferred,
+        items,
+        taxes,
+        text,
+        selection_marks,
+        model_id: Some(model_id),
+        needs_review: merchant_source == MerchantSource::Missing
+            || items_missing
+            || has_zero_price
+            || wrong_day
+            || future_dated,
+    })
+}
+
+/// Azure gives the rate and net amount only when they're printed; whichever is missing is derived from the other.
+fn tax_lines(receipt_fields: &Receipt, receipt_currency: Option<&str>) -> Vec<TaxLine> {
+    receipt_fields
+        .tax_details
+        .value_array
+        .iter()
+        .map(|entry| {
+            let entry = &entry.value_object;
+            let amount = entry.amount.value_currency.amount;
+            let net_amount = entry
+                .net_amount
+                .as_ref()
+                .map(|net| net.value_currency.amount);
+            // Azure reports `0.25` for 25%, but a printed `25` can come through as is
+            let rate = entry
+                .rate
+                .as_ref()
+                .map(|rate| rate.value_number)
+                .map(|rate| if rate <= 1.0 { rate * 100.0 } else { rate });
+            TaxLine {
+                rate: rate.or_else(|| {
+                    net_amount
+                        .filter(|net| *net != 0.0)
+                        .map(|net| (amount / net * 10_000.0).round() / 100.0)
+                }),
+                net_amount: net_amount.or_else(|| {
+                    rate.filter(|rate| *rate > 0.0)
+                        .map(|rate| (amount / rate * 10_000.0).round() / 100.0)
+                }),
+                amount,
+                currency_code: entry
+                    .amount
+                    .value_currency
+                    .currency_code
+                    .as_deref()
+                    .or(receipt_currency)
+                    .map(str::to_string),
+            }
+        })
+        .collect()
+}
+
+/// Azure reports checkboxes as document fields named by the model, so they are whatever fields have the `selectionMark` type.
+fn selection_marks(receipt_fields: &Receipt) -> BTreeMap<String, SelectionMarkState> {
+    receipt_fields
+        .other_fields
+        .iter()
+        .filter(|(_, field)| field["type"] == "selectionMark")
+        .filter_map(|(name, field)| {
+            let mark = serde_json::from_value::<SelectionMarkObject>(field.clone()).ok()?;
+            Some((name.clone(), mark.value_selection_mark?))
+        })
+        .collect()
+}
+
+/// Small shops often print no name Azure recognizes; rather than storing a blank merchant, label them by address or phone number.
+fn merchant_label(receipt_fields: &Receipt, config: &Config) -> (MerchantSource, String) {
+    let name = receipt_fields.merchant_name.value_string.trim();
+    if !name.is_empty() {
+        return (MerchantSource::Name, name.to_string());
+    }
+    config
+        .merchant_fallbacks
+        .iter()
+        .find_map(|fallback| {
+            let (source, label) = match fallback {
+                MerchantFallback::Address => {
+                    let address = receipt_fields.merchant_address.as_ref()?;
+                    let label = address.content.lines().map(str::trim).join(", ");
+                    (MerchantSource::Address, label)
+                }
+                MerchantFallback::Phone => {
+                    let phone = receipt_fields.merchant_phone_number.as_ref()?;
+                    let label = phone
+                        .value_phone_number
+                        .clone()
+                        .unwrap_or_else(|| phone.content.clone());
+                    (MerchantSource::Phone, label)
+                }
+            };
+            let label = label.trim();
+            (!label.is_empty()).then(|| (source, label.to_string()))
+        })
+        .unwrap_or((MerchantSource::Missing, String::new()))
+}
+
+/// Lump-sum receipts (e.g. for a single service) itemize nothing, which would leave their spending out of every analysis.
+pub fn synthesize_missing_items(
+    items: &mut Vec<LineItem>,
+    merchant_name: &str,
+    total: f64,
+    config: &Config,
+) {
+    if !config.synthesize_missing_items || !items.is_empty() || total <= 0.0 {
+        return;
+    }
+    let name = if merchant_name.trim().is_empty() {
+        "Unitemized purchase"
+    } else {
+        merchant_name.trim()
+    };
+    items.push(LineItem {
+        name: name.to_string(),
+        count: 1.0,
+        unit_price: Some(total),
+        quantity_defaulted: false,
+        quantity_unit: None,
+        product_code: None,
+        price_missing: false,
+        synthesized: true,
+        unattributed: false,
+        regions: vec![],
+    });
+}
+
+/// Product name of the line added by [`add_unattributed_line`].
+pub const UNATTRIBUTED_LINE: &str = "Unattributed amount";
+
+/// Lines lost to OCR make the items sum to less than the printed total; booking the difference as its own line keeps aggregate spend right.
+pub fn add_unattributed_line(
+    items: &mut Vec<LineItem>,
+    total: f64,
+    taxes: &[TaxLine],
+    config: &Config,
+) {
+    if !config.add_unattributed_lines {
+        return;
+    }
+    let reconciliation = reconcile::reconcile(Some(total), items, taxes);
+    let Some(difference) = reconciliation
+        .difference
+        .filter(|difference| *difference >= reconcile::TOLERANCE)
+    else {
+        return;
+    };
+    items.push(LineItem {
+        name: UNATTRIBUTED_LINE.into(),
+        count: 1.0,
+        unit_price: Some(difference),
+        quantity_defaulted: false,
+        quantity_unit: None,
+        product_code: None,
+        price_missing: false,
+        synthesized: false,
+        unattributed: true,
+        regions: vec![],
+    });
+}
+
+/// Receipts print the same unit as `Stk.`, `STK` or `stk`; they're stored lowercase without trailing dots so they group together.
+pub fn normalize_unit(raw: &str) -> Option<String> {
+    let unit = raw.trim().trim_end_matches('.').trim().to_lowercase();
+    (!unit.is_empty()).then_some(unit)
+}
+
+/// Blank codes count as no code, so they don't all match the same product.
+pub fn normalize_product_code(raw: &str) -> Option<String> {
+    let code = raw.trim();
+    (!code.is_empty()).then(|| code.to_string())
+}
+
+/// Most lines without a detected quantity really are single items, but weighed goods end up here too, hence the flag on [`LineItem`].
+pub fn default_quantity(name: &str, config: &Config) -> f64 {
+    tracing::debug!(
+        "No quantity detected for item {:?}, defaulting to {}",
+        name,
+        config.default_quantity
+    );
+    config.default_quantity
+}
+
+/// Reads a date printed in any of [`CONTENT_DATE_FORMATS`], returning it with the format that matched.
+pub fn parse_content_date(content: &str) -> Option<(chrono::NaiveDate, &'static str)> {
+    use chrono::Datelike;
+
+    CONTENT_DATE_FORMATS.iter().find_map(|format| {
+        chrono::NaiveDate::parse_from_str(content.trim(), format)
+            .ok()
+            // A year-first format can misread a two-digit year at the end as the day
+            .filter(|date| date.year() >= 2000)
+            .map(|date| (date, *format))
+    })
+}
+
+/// The printed date and time, or without a time the date alone at the configured time of day.
+pub fn timestamp(
+    date: chrono::NaiveDate,
+    time: Option<chrono::NaiveTime>,
+    config: &Config,
+) -> (chrono::NaiveDateTime, TimePrecision) {
+    match time {
+        Some(time) => (date.and_time(time), TimePrecision::Time),
+        None => {
+            tracing::debug!("No time on the receipt, using date {} only", date);
+            (
+                date.and_time(config.date_only_time.time()),
+                TimePrecision::Date,
+            )
+        }
+    }
+}
+
+/// Receipts print local wall-clock time; this pins it to the configured receipt timezone.
+/// In the hour repeated when DST ends the earlier instant is taken; a time skipped when DST starts is moved forward by the skipped hour.
+pub fn localize(
+    timestamp: chrono::NaiveDateTime,
+    config: &Config,
+) -> Result<chrono::DateTime<chrono_tz::Tz>, AppError> {
+    let timezone = config.receipt_timezone;
+    localize_in(timestamp, timezone).ok_or_else(|| {
+        anyhow!(
+            "Error converting naive timestamp to {} time",
+            timezone.name()
+        )
+        .into()
+    })
+}
+
+/// [`localize`] for any timezone.
+pub fn localize_in(
+    timestamp: chrono::NaiveDateTime,
+    time_zone: chrono_tz::Tz,
+) -> Option<chrono::DateTime<chrono_tz::Tz>> {
+    match time_zone.from_local_datetime(&timestamp) {
+        chrono::LocalResult::Single(paid_at) => Some(paid_at),
+        chrono::LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        chrono::LocalResult::None => time_zone
+            .from_local_datetime(&(timestamp + chrono::Duration::hours(1)))
+            .single(),
+    }
+}
+
+/// Clocks of tills and of this server drift apart, so a receipt is only considered future-dated beyond `allowance`.
+pub fn is_future_dated(
+    paid_at: chrono::DateTime<chrono_tz::Tz>,
+    now: chrono::DateTime<chrono::Utc>,
+    allowance: std::time::Duration,
+) -> bool {
+    chrono::Duration::from_std(allowance).is_ok_and(|allowance| paid_at > now + allowance)
+}
+
+/// Prefers the currency Azure detected for the whole receipt, falling back to the most common currency among item prices and tax lines.
+fn document_currency(receipt_fields: &Receipt) -> Option<String> {
+    let detected = receipt_fields
+        .currency
+        .as_ref()
+        .map(|currency| currency.value_string.trim().to_uppercase())
+        .filter(|code| !code.is_empty());
+    if detected.is_some() {
+        return detected;
+    }
+    let item_currencies = receipt_fields
+        .items
+        .iter()
+        .flat_map(|items| &items.value_array)
+        .flat_map(|item| {
+            [
+                item.value_object.unit_price.as_ref(),
+                item.value_object.total_price.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            .filter_map(|price| price.value_currency.as_ref())
+        });
+    let tax_currencies = receipt_fields
+        .tax_details
+        .value_array
+        .iter()
+        .map(|tax| &tax.value_object.amount.value_currency);
+    most_common_currency(
+        item_currencies
+            .chain(tax_currencies)
+            .filter_map(|currency| currency.currency_code.as_deref()),
+    )
+}
+
+/// Amounts without a currency would silently mix with all others in analytics, so they get the merchant country's currency, or else the global default.
+pub fn infer_currency(
+    detected: Option<String>,
+    country: Option<&str>,
+    config: &Config,
+) -> (Option<String>, bool) {
+    if detected.is_some() {
+        return (detected, false);
+    }
+    let inferred = country
+        .and_then(|country| {
+            config
+                .country_currencies
+                .iter()
+                .find(|entry| entry.country.eq_ignore_ascii_case(country.trim()))
+        })
+        .map(|entry| entry.currency.clone())
+        .or_else(|| config.default_currency.clone());
+    let is_inferred = inferred.is_some();
+    (inferred, is_inferred)
+}
+
+/// Ties are broken alphabetically so the pick doesn't depend on field order.
+pub fn most_common_currency<'a>(codes: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let counts = codes
+        .into_iter()
+        .map(|code| code.trim().to_uppercase())
+        .filter(|code| !code.is_empty())
+        .counts_by(|code| code);
+    let max = counts.values().copied().max()?;
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count == max)
+        .map(|(code, _)| code)
+        .min()
+}
+
+/// Two receipts from the same merchant at the same instant are considered the same purchase when their totals agree within `tolerance`.
+pub fn is_probable_duplicate(
+    receipt: &ReceiptRecord,
+    existing_total: Option<f64>,
+    tolerance: f64,
+) -> bool {
+    existing_total.is_some_and(|total| (total - receipt.total).abs() <= tolerance)
+}
@@ -0,0 +1,128 @@
+//! The SHA-256 of an uploaded file identifies it as cache key and in `file_sha256` columns, so it is validated before reaching either.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Hex-encoded SHA-256 of a file: always 64 lowercase hex characters.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(try_from = "String")]
+#[sqlx(transparent)]
+pub struct FileHash(String);
+
+#[derive(Error, Debug, PartialEq)]
+#[error("{0:?} is not a file hash, expected 64 lowercase hex characters")]
+pub struct InvalidFileHash(String);
+
+impl FileHash {
+    pub fn of(bytes: &[u8]) -> Self {
+        Self::finish(Sha256::new_with_prefix(bytes))
+    }
+
+    /// Hash of everything fed to `hasher`, for files hashed chunk by chunk.
+    pub fn finish(hasher: Sha256) -> Self {
+        FileHash(format!("{:x}", hasher.finalize()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for FileHash {
+    type Err = InvalidFileHash;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.to_string().try_into()
+    }
+}
+
+impl TryFrom<String> for FileHash {
+    type Error = InvalidFileHash;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let is_hash = value.len() == 64
+            && value
+                .bytes()
+                .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+        if is_hash {
+            Ok(FileHash(value))
+        } else {
+            Err(InvalidFileHash(value))
+        }
+    }
+}
+
+impl fmt::Display for FileHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Path, http::Request, routing::get, Router};
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::AppError;
+
+    #[test]
+    fn accept_only_lowercase_sha256_hex() {
+        let hash = FileHash::of(b"receipt");
+        assert_eq!(hash.as_str(), sha256::digest("receipt"));
+        assert_eq!(hash.as_str().parse(), Ok(hash.clone()));
+        assert_eq!(
+            serde_json::from_value::<FileHash>(serde_json::json!(hash.as_str())).unwrap(),
+            hash
+        );
+
+        let malformed = [
+            String::new(),
+            "abc".into(),
+            hash.as_str().to_uppercase(),
+            format!("{hash}0"),
+            format!("g{}", &hash.as_str()[1..]),
+            format!("../{}", &hash.as_str()[3..]),
+        ];
+        for value in malformed {
+            assert_eq!(
+                value.parse::<FileHash>(),
+                Err(InvalidFileHash(value.clone()))
+            );
+            assert!(serde_json::from_value::<FileHash>(serde_json::json!(value)).is_err());
+        }
+    }
+
+    #[test]
+    fn reject_malformed_hash_as_bad_request() {
+        let err = AppError::from("not-a-hash".parse::<FileHash>().unwrap_err());
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn validate_hashes_in_paths() {
+        let app = Router::new().route(
+            "/status/:file_hash",
+            get(|Path(file_hash): Path<FileHash>| async move { file_hash.to_string() }),
+        );
+        let status = |uri: String| {
+            let app = app.clone();
+            async move {
+                let request = Request::get(uri).body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+        let hash = FileHash::of(b"receipt");
+        assert_eq!(status(format!("/status/{hash}")).await, StatusCode::OK);
+        assert_eq!(
+            status("/status/not-a-hash".into()).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Azure only analyzes some file formats and answers anything else with an unhelpful error, so uploads are recognized by their first bytes before being sent.
+
+use crate::AppError;
+
+/// Longest signature checked by [`FileType::sniff`]
+pub const SIGNATURE_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Jpeg,
+    Png,
+    Pdf,
+    Tiff,
+    Bmp,
+}
+
+impl FileType {
+    /// Recognizes a file by its signature; `head` is the start of the file, ideally [`SIGNATURE_LEN`] bytes long.
+    pub fn sniff(head: &[u8]) -> Option<Self> {
+        let signatures: [(&[u8], FileType); 6] = [
+            (b"\xFF\xD8\xFF", FileType::Jpeg),
+            (b"\x89PNG\r\n\x1A\n", FileType::Png),
+            (b"%PDF-", FileType::Pdf),
+            (b"II*\0", FileType::Tiff),
+            (b"MM\0*", FileType::Tiff),
+            (b"BM", FileType::Bmp),
+        ];
+        signatures
+            .into_iter()
+            .find(|(signature, _)| head.starts_with(signature))
+            .map(|(_, file_type)| file_type)
+    }
+
+    /// `None` for content types that don't name one of the supported formats, like `application/octet-stream`.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(FileType::Jpeg),
+            "image/png" => Some(FileType::Png),
+            "application/pdf" => Some(FileType::Pdf),
+            "image/tiff" => Some(FileType::Tiff),
+            "image/bmp" | "image/x-ms-bmp" => Some(FileType::Bmp),
+            _ => None,
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            FileType::Jpeg => "image/jpeg",
+            FileType::Png => "image/png",
+            FileType::Pdf => "application/pdf",
+            FileType::Tiff => "image/tiff",
+            FileType::Bmp => "image/bmp",
+        }
+    }
+}
+
+/// The file's actual type; a declared content type naming a different supported format means the upload isn't what the client thinks it is.
+pub fn verify(head: &[u8], declared: Option<&str>) -> Result<FileType, AppError> {
+    let Some(file_type) = FileType::sniff(head) else {
+        return Err(AppError::unsupported_media_type(
+            "Unsupported file type, expected a JPEG, PNG, PDF, TIFF or BMP file",
+        ));
+    };
+    match declared.and_then(FileType::from_content_type) {
+        Some(declared_type) if declared_type != file_type => {
+            Err(AppError::unsupported_media_type(format!(
+                "File was sent as {} but its contents are {}",
+                declared_type.mime(),
+                file_type.mime()
+            )))
+        }
+        _ => Ok(file_type),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    #[test]
+    fn recognize_supported_formats_by_signature() {
+        for (head, file_type) in [
+            (&b"\xFF\xD8\xFF\xE0\0\x10JFIF"[..], FileType::Jpeg),
+            (b"\x89PNG\r\n\x1A\n\0\0", FileType::Png),
+            (b"%PDF-1.7\n", FileType::Pdf),
+            (b"II*\0\x08\0\0\0", FileType::Tiff),
+            (b"MM\0*\0\0\0\x08", FileType::Tiff),
+            (b"BM\x36\x0C\0\0", FileType::Bmp),
+        ] {
+            assert_eq!(verify(head, None).unwrap(), file_type);
+            assert_eq!(verify(head, Some(file_type.mime())).unwrap(), file_type);
+        }
+        assert_eq!(
+            verify(b"%PDF-1.4", Some("application/octet-stream")).unwrap(),
+            FileType::Pdf
+        );
+        assert_eq!(
+            verify(b"\xFF\xD8\xFF\xDB", Some("image/JPG; name=receipt.jpg")).unwrap(),
+            FileType::Jpeg
+        );
+    }
+
+    #[test]
+    fn reject_unsupported_or_mislabeled_files() {
+        for (head, declared) in [
+            (&b"GIF89a"[..], Some("image/gif")),
+            (b"PK\x03\x04", None),
+            (b"<html>", Some("application/pdf")),
+            (b"", None),
+            (b"\x89PNG\r\n\x1A\n", Some("image/jpeg")),
+        ] {
+            let err = verify(head, declared).unwrap_err();
+            assert_eq!(err.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+    }
+}
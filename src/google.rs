@@ -0,0 +1,313 @@
+//! Conversion of Google Document AI receipt/expense parser output into our own receipt data.
+
+use anyhow::anyhow;
+use serde_derive::Deserialize;
+
+use crate::{
+    config::{Config, MerchantFallback, ZeroPricePolicy},
+    extract::{self, LineItem, ReceiptRecord, TaxLine, TimePrecision},
+    AppError, BIND_LIMIT,
+};
+
+/// The client's body, so a malformed one is the client's to fix; the error names where it went wrong.
+pub fn parse_response(body: &[u8]) -> Result<ProcessResponse, AppError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+        AppError::bad_request(format!(
+            "Invalid Document AI response at {}: {}",
+            err.path(),
+            err.inner()
+        ))
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProcessResponse {
+    pub document: Document,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Document {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub entities: Vec<Entity>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entity {
+    #[serde(rename = "type")]
+    pub entity_type: String,
+    #[serde(default)]
+    pub mention_text: String,
+    pub normalized_value: Option<NormalizedValue>,
+    #[serde(default)]
+    pub properties: Vec<Entity>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NormalizedValue {
+    pub money_value: Option<Money>,
+    pub date_value: Option<Date>,
+    pub datetime_value: Option<DateTime>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Money {
+    pub currency_code: Option<String>,
+    /// int64 values are encoded as JSON strings
+    #[serde(default)]
+    pub units: Option<String>,
+    #[serde(default)]
+    pub nanos: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DateTime {
+    #[serde(default)]
+    pub hours: u32,
+    #[serde(default)]
+    pub minutes: u32,
+    #[serde(default)]
+    pub seconds: u32,
+}
+
+impl Money {
+    fn amount(&self) -> Option<f64> {
+        let units = self.units.as_deref().unwrap_or("0").parse::<i64>().ok()?;
+        Some(units as f64 + f64::from(self.nanos) / 1e9)
+    }
+}
+
+impl Entity {
+    fn property(&self, entity_type: &str) -> Option<&Entity> {
+        self.properties
+            .iter()
+            .find(|property| property.entity_type == entity_type)
+    }
+
+    fn amount(&self) -> Option<f64> {
+        self.normalized_value
+            .as_ref()
+            .and_then(|value| value.money_value.as_ref())
+            .and_then(Money::amount)
+            .or_else(|| parse_number(&self.mention_text))
+    }
+
+    fn currency_code(&self) -> Option<&str> {
+        self.normalized_value
+            .as_ref()
+            .and_then(|value| value.money_value.as_ref())
+            .and_then(|money| money.currency_code.as_deref())
+    }
+}
+
+/// Receipts print amounts with either decimal separator and sometimes a currency prefix.
+fn parse_number(text: &str) -> Option<f64> {
+    let cleaned = text
+        .chars()
+        .filter(|c| c.is_ascii_digit() || matches!(c, ',' | '.' | '-'))
+        .collect::<String>()
+        .replace(',', ".");
+    cleaned.parse().ok()
+}
+
+fn paid_at(
+    entities: &[&Entity],
+    config: &Config,
+) -> Result<(chrono::DateTime<chrono_tz::Tz>, TimePrecision), AppError> {
+    let date_entity = entities
+        .iter()
+        .find(|entity| entity.entity_type == "receipt_date")
+        .ok_or(anyhow!("Missing receipt_date entity"))?;
+    let date = match date_entity
+        .normalized_value
+        .as_ref()
+        .and_then(|value| value.date_value.as_ref())
+    {
+        Some(date) => chrono::NaiveDate::from_ymd_opt(date.year, date.month, date.day),
+        None => chrono::NaiveDate::parse_from_str(date_entity.mention_text.trim(), "%Y-%m-%d").ok(),
+    }
+    .ok_or(anyhow!(
+        "Invalid receipt date: {}",
+        date_entity.mention_text
+    ))?;
+
+    let time = entities
+        .iter()
+        .find(|entity| entity.entity_type == "purchase_time")
+        .and_then(|time_entity| {
+            match time_entity
+                .normalized_value
+                .as_ref()
+                .and_then(|value| value.datetime_value.as_ref())
+            {
+                Some(time) => {
+                    chrono::NaiveTime::from_hms_opt(time.hours, time.minutes, time.seconds)
+                }
+                None => {
+                    let text = time_entity.mention_text.trim();
+                    chrono::NaiveTime::parse_from_str(text, "%H:%M:%S")
+                        .or_else(|_| chrono::NaiveTime::parse_from_str(text, "%H:%M"))
+                        .ok()
+                }
+            }
+        });
+
+    let (time, precision) = match time {
+        Some(time) => (time, TimePrecision::Time),
+        None => (config.date_only_time.time(), TimePrecision::Date),
+    };
+    Ok((extract::localize(date.and_time(time), config)?, precision))
+}
+
+/// Applies the same rules as the Azure extraction: items without any price are dropped unless configured otherwise, zero-priced ones follow the configured policy and a missing quantity counts as one.
+pub fn into_receipt_record(
+    response: ProcessResponse,
+    config: &Config,
+) -> Result<ReceiptRecord, AppError> {
+    let entities = response.document.entities.iter().collect::<Vec<_>>();
+    let find = |entity_type: &str| {
+        entities
+            .iter()
+            .find(|entity| entity.entity_type == entity_type)
+            .copied()
+    };
+
+    let mention = |entity_type: &str| {
+        find(entity_type)
+            .map(|entity| {
+                entity
+                    .mention_text
+                    .lines()
+                    .map(str::trim)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .filter(|text| !text.is_empty())
+    };
+    let merchant_name = mention("supplier_name").or_else(|| {
+        config
+            .merchant_fallbacks
+            .iter()
+            .find_map(|fallback| match fallback {
+                MerchantFallback::Address => mention("supplier_address"),
+                MerchantFallback::Phone => mention("supplier_phone"),
+            })
+    });
+    let total_entity = find("total_amount").ok_or(anyhow!("Missing total_amount entity"))?;
+    let total = total_entity.amount().ok_or(anyhow!(
+        "Invalid total amount: {}",
+        total_entity.mention_text
+    ))?;
+    let currency_code = find("currency")
+        .map(|entity| entity.mention_text.as_str())
+        .filter(|code| !code.trim().is_empty())
+        .or(total_entity.currency_code())
+        .map(|code| code.trim().to_uppercase());
+    let (currency_code, currency_inferred) = extract::infer_currency(currency_code, None, config);
+
+    let mut has_zero_price = false;
+    let mut items = entities
+        .iter()
+        .filter(|entity| entity.entity_type == "line_item")
+        .filter_map(|line| {
+            let unit_price = line
+                .property("line_item/unit_price")
+                .or(line.property("line_item/amount"))
+                .and_then(Entity::amount);
+            if unit_price.is_none() && !config.keep_priceless_items {
+                return None;
+            }
+            if unit_price == Some(0.0) {
+                match config.zero_price_policy {
+                    ZeroPricePolicy::Keep => {}
+                    ZeroPricePolicy::Drop => return None,
+                    ZeroPricePolicy::Flag => has_zero_price = true,
+                }
+            }
+            let name = line
+                .property("line_item/description")
+                .map(|description| description.mention_text.trim().to_string())
+                .unwrap_or_else(|| line.mention_text.trim().to_string());
+            let (count, quantity_defaulted) = match line
+                .property("line_item/quantity")
+                .and_then(|quantity| parse_number(&quantity.mention_text))
+            {
+                Some(count) => (count, false),
+                None => (extract::default_quantity(&name, config), true),
+            };
+            let product_code = line
+                .property("line_item/product_code")
+                .and_then(|code| extract::normalize_product_code(&code.mention_text));
+            Some(LineItem {
+                name,
+                count,
+                price_missing: unit_price.is_none(),
+                unit_price,
+                quantity_defaulted,
+                quantity_unit: None,
+                product_code,
+                synthesized: false,
+                unattributed: false,
+                regions: vec![],
+            })
+        })
+        .take(BIND_LIMIT)
+        .collect();
+
+    let taxes = entities
+        .iter()
+        .filter(|entity| entity.entity_type == "vat")
+        .filter_map(|vat| {
+            let tax_amount = vat.property("vat/tax_amount")?;
+            Some(TaxLine {
+                amount: tax_amount.amount()?,
+                net_amount: vat.property("vat/amount").and_then(Entity::amount),
+                rate: vat
+                    .property("vat/tax_rate")
+                    .and_then(|rate| parse_number(&rate.mention_text)),
+                currency_code: tax_amount
+                    .currency_code()
+                    .or(currency_code.as_deref())
+                    .map(str::to_string),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let (paid_at, time_precision) = paid_at(&entities, config)?;
+    extract::synthesize_missing_items(
+        &mut items,
+        merchant_name.as_deref().unwrap_or_default(),
+        total,
+        config,
+    );
+    extract::add_unattributed_line(&mut items, total, &taxes, config);
+
+    Ok(ReceiptRecord {
+        needs_review: merchant_name.is_none() || has_zero_price,
+        merchant_name: merchant_name.unwrap_or_default(),
+        paid_at,
+        time_precision,
+        total,
+        total_tax: find("total_tax_amount").and_then(Entity::amount),
+        currency_code,
+        currency_inferred,
+        items,
+        taxes,
+        text: response.document.text.clone(),
+        selection_marks: Default::default(),
+        model_id: None,
+    })
+}
@@ -0,0 +1,76 @@
+//! Receipts keep the wall-clock time printed on them plus the IANA timezone it was printed in, so the instant can be resolved, and shown in any timezone, at query time.
+
+use chrono::{DateTime, NaiveDateTime};
+use chrono_tz::Tz;
+
+use crate::{extract, AppError};
+
+/// The `local_paid_at` and `time_zone` columns for a localized `paid_at`.
+pub fn local_columns(paid_at: DateTime<Tz>) -> (NaiveDateTime, &'static str) {
+    (paid_at.naive_local(), paid_at.timezone().name())
+}
+
+pub fn parse_time_zone(name: &str) -> Result<Tz, AppError> {
+    name.trim().parse().map_err(|_| {
+        AppError::bad_request(format!(
+            "Unknown timezone {name:?}, expected an IANA name like Europe/Copenhagen"
+        ))
+    })
+}
+
+/// Resolves the stored wall-clock time in its own timezone, then shows that instant in `display`.
+pub fn resolve(
+    local_paid_at: NaiveDateTime,
+    time_zone: &str,
+    display: Tz,
+) -> Result<DateTime<Tz>, AppError> {
+    let stored: Tz = time_zone
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Stored timezone {time_zone:?} is not an IANA timezone"))?;
+    let paid_at = extract::localize_in(local_paid_at, stored)
+        .ok_or_else(|| anyhow::anyhow!("{local_paid_at} does not exist in timezone {time_zone}"))?;
+    Ok(paid_at.with_timezone(&display))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    #[test]
+    fn render_the_same_stored_row_in_two_timezones() {
+        let local_paid_at = NaiveDate::from_ymd_opt(2023, 10, 7)
+            .unwrap()
+            .and_hms_opt(14, 24, 0)
+            .unwrap();
+        let in_copenhagen =
+            resolve(local_paid_at, "Europe/Copenhagen", Tz::Europe__Copenhagen).unwrap();
+        let in_new_york = resolve(
+            local_paid_at,
+            "Europe/Copenhagen",
+            parse_time_zone("America/New_York").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(in_copenhagen, in_new_york);
+        assert_eq!(in_copenhagen.to_rfc3339(), "2023-10-07T14:24:00+02:00");
+        assert_eq!(in_new_york.to_rfc3339(), "2023-10-07T08:24:00-04:00");
+        assert_eq!(
+            local_columns(in_copenhagen),
+            (local_paid_at, "Europe/Copenhagen")
+        );
+    }
+
+    #[test]
+    fn reject_unknown_timezones() {
+        assert!(matches!(
+            parse_time_zone("Mars/Olympus_Mons"),
+            Err(AppError::Validation(_))
+        ));
+        let local_paid_at = NaiveDate::from_ymd_opt(2023, 10, 7)
+            .unwrap()
+            .and_hms_opt(14, 24, 0)
+            .unwrap();
+        assert!(resolve(local_paid_at, "CEST", Tz::UTC).is_err());
+    }
+}
@@ -1,309 +1,887 @@
-use chrono::TimeZone;
 use shuttle_persist::{PersistError, PersistInstance};
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use anyhow::anyhow;
 use axum::{
-    extract::{multipart::MultipartError, DefaultBodyLimit, Multipart, State},
-    response::IntoResponse,
-    routing::{delete, get, post, put},
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{SinkExt, StreamExt};
 
-use chrono_tz::Europe::Copenhagen;
-use itertools::Itertools;
+use analysis_queue::AnalysisQueue;
+use analysis_status::{AnalysisStatus, FileStatus};
+use cache::CachedAnalysis;
+use config::{AzureApiVersion, Config, DuplicatePolicy};
+#[cfg(test)]
+use errors::ValidationError;
+use errors::{AppError, IngestError};
+use extract::{LineItem, ReceiptRecord};
+use file_hash::FileHash;
 use manual::AnalyzeResultOperation;
+use pagination::{Listing, Page, PageParams};
 use reqwest::{
-    header::{ToStrError, CONTENT_LENGTH, CONTENT_TYPE},
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
     Client, Response, StatusCode,
 };
+use retry::{send_with_retry, RetryPolicy};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use shuttle_axum::ShuttleAxum;
 use shuttle_runtime::{self};
 use shuttle_secrets::SecretStore;
-use sqlx::{pool::PoolOptions, postgres::PgPoolOptions, Executor, PgPool, Row};
-use thiserror::Error;
+use sqlx::{PgConnection, PgPool};
+use tracing::Instrument;
 
+mod analysis_errors;
+mod analysis_queue;
+mod analysis_status;
+mod analytics;
+mod analyze_urls;
+mod cache;
+mod categories;
+mod client_tokens;
+mod compare;
+mod config;
+mod consistency;
+mod errors;
+mod export;
+mod extract;
+mod file_hash;
+mod file_type;
+mod google;
+mod local_time;
 mod manual;
+mod pagination;
+mod quota;
+mod receipts;
+mod reconcile;
+mod report;
+mod result_poll;
+mod retry;
+mod review;
+mod schema;
+mod search;
+mod tags;
+mod taxes;
+mod upload_check;
+mod upload_stats;
+mod upload_stream;
+mod webhook;
 
+/// Body of the Azure analyze call, also accepted by `/upload/base64` from clients that can't send multipart.
+#[allow(non_snake_case)]
 #[derive(Serialize, Deserialize)]
 struct AnalyzeRequestBody {
     base64Source: String,
 }
 
+impl AnalyzeRequestBody {
+    fn decode(&self) -> Result<axum::body::Bytes, AppError> {
+        let data = BASE64_STANDARD
+            .decode(self.base64Source.trim())
+            .map_err(|err| {
+                AppError::bad_request(format!("base64Source is not valid base64: {err}"))
+            })?;
+        if data.is_empty() {
+            return Err(AppError::bad_request("base64Source is empty"));
+        }
+        Ok(data.into())
+    }
+}
+
 const ENDPOINT: &str = "https://receipt-model.cognitiveservices.azure.com/";
-const MODEL_ID: &str = "prebuilt-receipt";
 
-async fn analyze_file(
+fn analyze_request(
     file_string: &str,
+    model_id: &str,
+    api_version: AzureApiVersion,
     api_key: &str,
     client: &Client,
-) -> Result<Response, reqwest::Error> {
+) -> Result<reqwest::Request, reqwest::Error> {
     let url = format!(
-        "{ENDPOINT}formrecognizer/documentModels/{MODEL_ID}:analyze?api-version=2023-07-31"
+        "{ENDPOINT}{}/documentModels/{model_id}:analyze?api-version={}",
+        api_version.service(),
+        api_version.as_str()
     );
-    let req = client
+    client
         .post(url)
         .header(CONTENT_TYPE, "application/json")
         .header("Ocp-Apim-Subscription-Key", api_key)
         .body(json!({ "base64Source": file_string }).to_string())
-        .build()?;
-    client.execute(req).await
+        .build()
+}
+
+async fn analyze_file(
+    file_string: &str,
+    model_id: &str,
+    api_version: AzureApiVersion,
+    api_key: &str,
+    client: &Client,
+    retry: RetryPolicy,
+) -> Result<Response, reqwest::Error> {
+    send_with_retry(retry, || async {
+        let req = analyze_request(file_string, model_id, api_version, api_key, client)?;
+        client.execute(req).await
+    })
+    .await
 }
 
 async fn get_analysis_results(
     url: &str,
     api_key: &str,
     client: &Client,
+    retry: RetryPolicy,
 ) -> Result<Response, reqwest::Error> {
-    let req = client
-        .get(url)
-        .header(CONTENT_TYPE, "application/json")
-        .header(CONTENT_LENGTH, "0")
-        .header("Ocp-Apim-Subscription-Key", api_key)
-        .build()?;
-    client.execute(req).await
-}
-
-// Make our own error that wraps `anyhow::Error`.
-#[derive(Error, Debug)]
-#[error(transparent)]
-enum AppError {
-    #[error(transparent)]
-    Multipart(#[from] MultipartError),
-    #[error(transparent)]
-    Anyhow(#[from] anyhow::Error),
-    #[error(transparent)]
-    ToStr(#[from] ToStrError),
-    #[error(transparent)]
-    EncodeSlice(#[from] base64::EncodeSliceError),
-    #[error(transparent)]
-    HttpClient(#[from] reqwest::Error),
-    #[error(transparent)]
-    Json(#[from] serde_json::Error),
-    #[error(transparent)]
-    Sqlx(#[from] sqlx::Error),
-    #[error(transparent)]
-    ChronoParse(#[from] chrono::ParseError),
-    #[error(transparent)]
-    ShuttlePersist(#[from] PersistError),
-    #[error(transparent)]
-    Csv(#[from] csv::Error),
-    #[error(transparent)]
-    StringFromUtf8(#[from] std::string::FromUtf8Error),
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
-    #[error(transparent)]
-    CsvIntoInner(#[from] csv::IntoInnerError<csv::Writer<Vec<u8>>>),
-    
-}
-
-// Tell axum how to convert `AppError` into a response.
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.to_string()),
-        )
-            .into_response()
-    }
+    send_with_retry(retry, || async {
+        let req = client
+            .get(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, "0")
+            .header("Ocp-Apim-Subscription-Key", api_key)
+            .build()?;
+        client.execute(req).await
+    })
+    .await
 }
 
+/// `replaces` is the receipt the file corrects, if it was uploaded as a correction.
 async fn process_analysis_results(
-    file_hash: &str,
-    res: reqwest::Response,
+    file_hash: &FileHash,
+    text: String,
     app_state: Arc<AppState>,
+    replaces: Option<i32>,
 ) -> Result<(), AppError> {
-    let text = res.text().await?;
-    app_state.persist.save(&file_hash, &text)?;
-    tracing::info!("Successfully cached raw response text in KV storage. Processing further...");
-    let data: manual::AnalyzeResultOperation = serde_json::from_str(&text)?;
-    save_analysis_data(&app_state.pool, data, file_hash).await?;
-    tracing::info!("Successfully saved receipt data in database");
-    Ok::<(), AppError>(())
+    // A result is only cached already when the hash claim was lost and the file got analyzed again
+    let previous = match load_cached(&app_state, file_hash).await {
+        Ok(CachedAnalysis::Done(previous)) => Some(previous),
+        _ => None,
+    };
+    // A good earlier result is never overwritten by a response we can't use
+    let cache_response = || {
+        if previous.is_none() {
+            app_state.persist.save(file_hash.as_str(), &text)?;
+            tracing::info!("Successfully cached raw response text in KV storage");
+        }
+        Ok::<(), PersistError>(())
+    };
+    let data = match cache::parse_operation(&text, app_state.config.api_version) {
+        Ok(data) => data,
+        Err(err) => {
+            cache_response()?;
+            tracing::error!(
+                "Response for file {} does not match the expected schema at {}: {}",
+                file_hash,
+                err.path(),
+                err.inner()
+            );
+            if app_state.config.keep_parse_failures {
+                sqlx::query!(
+                    "INSERT INTO parse_failures(file_sha256, field_path, error, response) VALUES ($1, $2, $3, $4) ON CONFLICT (file_sha256) DO UPDATE SET field_path = excluded.field_path, error = excluded.error, response = excluded.response, failed_at = now()",
+                    file_hash.as_str(),
+                    err.path().to_string(),
+                    err.inner().to_string(),
+                    text
+                )
+                .execute(&app_state.pool)
+                .await?;
+            }
+            return Err(err.into());
+        }
+    };
+    if let Some(failure) = cache::analysis_failure(&data) {
+        // The cached response keeps the failure on record, so the entry no longer looks pending
+        cache_response()?;
+        return Err(IngestError::Analysis(format!(
+            "Azure failed to analyze file {file_hash}: {} ({})",
+            failure.message, failure.code
+        ))
+        .into());
+    }
+    let Some(previous) = previous else {
+        app_state.persist.save(file_hash.as_str(), &text)?;
+        tracing::info!(
+            "Successfully cached raw response text in KV storage. Processing further..."
+        );
+        match replaces {
+            Some(receipt_id) => {
+                let receipt = extract::extract_receipt(data, &app_state.config)?;
+                replace_with_corrected_file(&app_state, receipt, file_hash, receipt_id).await?;
+            }
+            None => save_analysis_data(&app_state, data, file_hash).await?,
+        }
+        tracing::info!("Successfully saved receipt data in database");
+        return Ok(());
+    };
+    match cache::resolve_reanalysis(app_state.config.reanalysis_policy, &previous, &data) {
+        cache::Reanalysis::Replace => {
+            tracing::warn!(
+                "File {} was analyzed again, replacing its earlier result",
+                file_hash
+            );
+        }
+        cache::Reanalysis::KeepPrevious => {
+            tracing::info!(
+                "File {} was analyzed again, keeping its earlier, more confident result",
+                file_hash
+            );
+            return Ok(());
+        }
+        cache::Reanalysis::Reject => {
+            return Err(IngestError::Analysis(format!(
+                "File {file_hash} already has an analysis result, rejecting the new one"
+            ))
+            .into());
+        }
+    }
+    app_state.persist.save(file_hash.as_str(), &text)?;
+    let receipt_id = sqlx::query_scalar!(
+        "SELECT id FROM receipts WHERE file_sha256 = $1",
+        file_hash.as_str()
+    )
+    .fetch_optional(&app_state.pool)
+    .await?;
+    match receipt_id {
+        Some(receipt_id) => {
+            let receipt = extract::extract_receipt(data, &app_state.config)?;
+            replace_receipt_record(&app_state, receipt, receipt_id).await?;
+        }
+        None => save_analysis_data(&app_state, data, file_hash).await?,
+    }
+    tracing::info!("Successfully replaced receipt data in database");
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RepopulateParams {
+    /// Most cache entries to process in this call, capped by the configured maximum
+    limit: Option<usize>,
+    /// Entries to skip, in file hash order
+    offset: Option<usize>,
 }
 
+/// Rebuilds the DB from the cache, in batches if asked to. The DB is only cleared by the batch starting at offset 0,
+/// which also imports the kept Google Document AI responses again.
+/// Tags and notes of all receipts are lost, as are receipts whose cache entry is gone.
 async fn repopulate_db_from_cache(
     State(app_state): State<Arc<AppState>>,
-) -> Result<&'static str, AppError> {
-    let tx = app_state.pool.begin().await?;
-    sqlx::query!("DELETE FROM prices")
-        .execute(&app_state.pool)
-        .await?;
-    sqlx::query!("DELETE FROM products")
-        .execute(&app_state.pool)
-        .await?;
-    sqlx::query!("DELETE FROM receipts")
-        .execute(&app_state.pool)
-        .await?;
-    tx.commit().await?;
+    Query(params): Query<RepopulateParams>,
+) -> Result<String, AppError> {
+    let offset = params.offset.unwrap_or(0);
+    let limit = match (params.limit, app_state.config.repopulate_max_entries) {
+        (Some(limit), Some(max)) => Some(limit.min(max)),
+        (limit, max) => limit.or(max),
+    };
+    let all_hashes = cached_hashes(&app_state)?;
+    let total = all_hashes.len();
+    let file_hashes = cache::batch(all_hashes, offset, limit);
+
+    if offset == 0 {
+        let mut tx = app_state.pool.begin().await?;
+        sqlx::query!("DELETE FROM prices").execute(&mut *tx).await?;
+        sqlx::query!("DELETE FROM products")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM receipts")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
 
-    let file_hashes = app_state.persist.list()?;
+    let msg = format!(
+        "Successfully enqueued repopulation of DB data from {} of {} cached analysis results, starting at offset {}. Results should be available shortly",
+        file_hashes.len(),
+        total,
+        offset
+    );
+    // Every save holds a connection, so more saves in flight than the pool has connections would only time out waiting for one
+    let width = app_state
+        .config
+        .cache_read_concurrency
+        .min(app_state.pool.options().get_max_connections() as usize);
     tokio::spawn(async move {
-        for file_hash in file_hashes {
-            tokio::time::sleep(Duration::from_secs(1)).await; // TODO: Find a way to change shuttle-rs acquire_timeout option for PgPool to avoid timeout errors
-            let app_state_clone = app_state.clone();
-            tokio::spawn(async move {
-                let res = app_state_clone
-                    .persist
-                    .load::<String>(&file_hash)
-                    .map_err(AppError::from)
-                    .and_then(|text| serde_json::from_str(&text).map_err(AppError::from));
-                match res {
-                    Ok(data) => {
-                        if let Err(err) =
-                            save_analysis_data(&app_state_clone.pool, data, &file_hash).await
-                        {
-                            tracing::error!("{}", err.to_string());
-                        } else {
-                            tracing::info!(
-                                "Successfully saved receipted data in DB for cached results of analyzing file {}",
-                                file_hash
-                            );
-                        };
-                    }
-                    Err(err) => tracing::error!(
-                        "Cached results for file {} encountered an error during processing: {}",
-                        file_hash,
-                        err.to_string()
-                    ),
-                };
-            });
+        let mut outcomes = cache::load_bounded(file_hashes, width, |file_hash| {
+            let app_state = app_state.clone();
+            let span = analysis_span(&file_hash);
+            async move {
+                let outcome = save_cached_analysis(&app_state, &file_hash).await;
+                (file_hash, outcome)
+            }
+            .instrument(span)
+        })
+        .await;
+        if offset == 0 {
+            outcomes.extend(replay_google_imports(&app_state).await);
+        }
+        let report = RepopulationReport::new(offset, outcomes);
+        tracing::info!(
+            "Repopulation starting at offset {} finished: {} saved, {} skipped, {} failed",
+            offset,
+            report.saved.len(),
+            report.skipped,
+            report.failed.len()
+        );
+        tracing::debug!("Files whose receipts were committed: {:?}", report.saved);
+        if !report.failed.is_empty() {
+            tracing::warn!(
+                "Files whose receipts were not stored, none of their data was kept: {:?}",
+                report.failed
+            );
         }
+        *app_state.last_repopulation.lock().unwrap() = Some(report);
     });
-    let msg = "Successfully enqueued repopulation of DB data from cached analysis results. Results should be available shortly";
     tracing::info!(msg);
     Ok(msg)
 }
 
+/// Groups everything logged while processing one file, so logs of overlapping uploads can be told apart.
+/// Created within a request, it stays a child of the request's span and so keeps naming the client.
+fn analysis_span(file_hash: &FileHash) -> tracing::Span {
+    tracing::info_span!("analysis", file_hash = %file_hash)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Repopulated {
+    Saved,
+    /// The cached analysis is pending or failed
+    Skipped,
+    Failed,
+}
+
+/// Which files of a repopulation batch ended up committed. Each file is saved all-or-nothing, so a failed one left nothing behind.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct RepopulationReport {
+    /// Offset into the cache the batch started at
+    offset: usize,
+    saved: Vec<FileHash>,
+    skipped: usize,
+    failed: Vec<FileHash>,
+}
+
+impl RepopulationReport {
+    fn new(offset: usize, outcomes: Vec<(FileHash, Repopulated)>) -> Self {
+        let mut report = Self {
+            offset,
+            saved: vec![],
+            skipped: 0,
+            failed: vec![],
+        };
+        for (file_hash, outcome) in outcomes {
+            match outcome {
+                Repopulated::Saved => report.saved.push(file_hash),
+                Repopulated::Skipped => report.skipped += 1,
+                Repopulated::Failed => report.failed.push(file_hash),
+            }
+        }
+        report.saved.sort_unstable();
+        report.failed.sort_unstable();
+        report
+    }
+}
+
+/// Report of the last repopulation batch to finish since startup, `null` if none has
+async fn last_repopulation(
+    State(app_state): State<Arc<AppState>>,
+) -> axum::Json<Option<RepopulationReport>> {
+    axum::Json(app_state.last_repopulation.lock().unwrap().clone())
+}
+
+/// Stores the receipt of a finished cached analysis; pending and failed ones are skipped.
+async fn save_cached_analysis(app_state: &AppState, file_hash: &FileHash) -> Repopulated {
+    match load_cached(app_state, file_hash).await {
+        Ok(CachedAnalysis::Pending) => {
+            tracing::info!("Skipping file {}, its analysis has not finished", file_hash);
+            Repopulated::Skipped
+        }
+        Ok(CachedAnalysis::Failed(failure)) => {
+            tracing::info!(
+                "Skipping file {}, its analysis failed with {}",
+                file_hash,
+                failure.code
+            );
+            Repopulated::Skipped
+        }
+        Ok(CachedAnalysis::Done(data)) => {
+            if let Err(err) = save_analysis_data(app_state, *data, file_hash).await {
+                tracing::error!("{}", err.to_string());
+                analysis_errors::record(app_state, file_hash, &err).await;
+                Repopulated::Failed
+            } else {
+                tracing::info!(
+                    "Successfully saved receipted data in DB for cached results of analyzing file {}",
+                    file_hash
+                );
+                Repopulated::Saved
+            }
+        }
+        Err(err) => {
+            tracing::error!(
+                "Cached results for file {} encountered an error during processing: {}",
+                file_hash,
+                err.to_string()
+            );
+            analysis_errors::record(app_state, file_hash, &err).await;
+            Repopulated::Failed
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UploadParams {
+    /// Azure model to analyze the file with; must be in the configured allowlist
+    model_id: Option<String>,
+    /// Analyze the file even if it already has a result; the reanalysis policy decides which result is kept
+    #[serde(default)]
+    force: bool,
+    /// Hash of an earlier upload this file corrects; its receipt takes on the new file's data instead of a second receipt being stored
+    replaces: Option<FileHash>,
+}
+
 async fn upload(
     State(app_state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
     mut multipart: Multipart,
-) -> Result<String, AppError> {
-    if let Some(field) = multipart.next_field().await? {
-        let data = field.bytes().await?;
+) -> Result<(StatusCode, axum::Json<FileStatus>), AppError> {
+    let Some(mut field) = multipart.next_field().await? else {
+        return Err(AppError::bad_request("No file was submitted for analysis"));
+    };
+    let force = params.force;
+    let replaces = replaced_receipt(&app_state, params.replaces.as_ref()).await?;
+    let model_id = admit_upload(&app_state, params)?;
+    let content_type = field.content_type().map(str::to_string);
+    let mut stream = upload_stream::UploadStream::new(app_state.upload_check.clone());
+    while let Some(chunk) = field.chunk().await? {
+        stream.push(chunk).await?;
+    }
+    let upload = stream.finish(content_type.as_deref())?;
+    let status = submit_for_analysis(app_state, model_id, upload, force, replaces).await?;
+    Ok((StatusCode::ACCEPTED, axum::Json(status)))
+}
 
-        let file_hash = sha256::digest(data.as_ref());
+/// Same as [`upload`], for a JSON body with the base64-encoded file.
+async fn upload_base64(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    axum::Json(body): axum::Json<AnalyzeRequestBody>,
+) -> Result<(StatusCode, axum::Json<FileStatus>), AppError> {
+    let force = params.force;
+    let replaces = replaced_receipt(&app_state, params.replaces.as_ref()).await?;
+    let model_id = admit_upload(&app_state, params)?;
+    let mut stream = upload_stream::UploadStream::new(app_state.upload_check.clone());
+    stream.push(body.decode()?).await?;
+    let upload = stream.finish(None)?;
+    let status = submit_for_analysis(app_state, model_id, upload, force, replaces).await?;
+    Ok((StatusCode::ACCEPTED, axum::Json(status)))
+}
 
-        let is_already_analyzed = app_state
-            .persist
-            .list()?
-            .into_iter()
-            .find(|hash| &file_hash == hash)
-            .is_some();
+/// Id of the receipt stored for the file a corrected upload replaces.
+async fn replaced_receipt(
+    app_state: &AppState,
+    replaces: Option<&FileHash>,
+) -> Result<Option<i32>, AppError> {
+    let Some(replaces) = replaces else {
+        return Ok(None);
+    };
+    let receipt_id = sqlx::query_scalar!(
+        "SELECT id FROM receipts WHERE file_sha256 = $1",
+        replaces.as_str()
+    )
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or(AppError::not_found(format!(
+        "No receipt is stored for file {replaces}"
+    )))?;
+    Ok(Some(receipt_id))
+}
 
-        if is_already_analyzed {
-            return Err(AppError::Anyhow(anyhow!(
-                "Submitted file's hash is already saved in the KV store. Not runnning analysis."
-            )));
-        } else {
-            app_state.persist.save(&file_hash, "")?;
-            tracing::info!("Successfully cached file hash in KV storage. Processing further...");
+/// Checked before the file is read, so a refused upload isn't streamed in for nothing. Returns the model to analyze with.
+fn admit_upload(app_state: &AppState, params: UploadParams) -> Result<String, AppError> {
+    let model_id = params
+        .model_id
+        .unwrap_or_else(|| app_state.config.default_model_id.clone());
+    if !app_state.config.allowed_model_ids.contains(&model_id) {
+        return Err(AppError::bad_request(format!(
+            "Model {model_id} is not allowed for analysis"
+        )));
+    }
+
+    if let Some(retry_after) = app_state.quota.paused_for(chrono::Utc::now()) {
+        return Err(IngestError::QuotaExceeded {
+            message: "new submissions are paused until the quota cooldown ends".into(),
+            retry_after: Some(retry_after),
         }
+        .into());
+    }
+    Ok(model_id)
+}
 
-        let base64_file = BASE64_STANDARD.encode(data);
+async fn submit_for_analysis(
+    app_state: Arc<AppState>,
+    model_id: String,
+    upload: upload_stream::StreamedUpload,
+    force: bool,
+    replaces: Option<i32>,
+) -> Result<FileStatus, AppError> {
+    let upload_stream::StreamedUpload {
+        file_hash,
+        base64: base64_file,
+        file_type,
+    } = upload;
 
-        tracing::info!("New file detected, starting analysis...");
-        let res = analyze_file(
-            &base64_file,
-            &app_state.azure_form_recognizer_api_key,
-            &app_state.client,
-        )
-        .await?;
-        tracing::info!("Successfully received response from analysis API. Processing...");
-
-        if let StatusCode::ACCEPTED = res.status() {
-            let result_url = res
-                .headers()
-                .get("Operation-Location")
-                .ok_or(anyhow!(
-                    "Missing Operation-Location in response header. This should never happen"
-                ))?
-                .to_str()?
-                .to_string();
-            let msg = format!(
-                "Successfully queued image analysis. Result will be available at: {result_url}"
-            );
-            tracing::info!(msg);
-
-            tokio::spawn(async move {
-                tracing::info!("Waiting before asking for results...");
-                tokio::time::sleep(Duration::from_secs(30)).await;
-                tracing::info!("Requesting results...");
-                let res = get_analysis_results(
-                    &result_url,
-                    &app_state.azure_form_recognizer_api_key,
-                    &app_state.client,
-                )
-                .await;
-                tracing::info!("Received response from API. Processing...");
+    let has_result = has_analysis_result(&app_state, &file_hash)?;
+    app_state
+        .upload_stats
+        .record(&file_hash, has_result && !force);
+
+    if has_result && !force {
+        return Err(AppError::bad_request(
+            "Submitted file has already been analyzed. Pass force=true to analyze it again",
+        ));
+    }
+    if has_result && replaces.is_some() {
+        return Err(AppError::bad_request(
+            "A file that has already been analyzed can't replace another one",
+        ));
+    }
+    // A stored result stays in place until the new one arrives, so a failed re-analysis loses nothing
+    let claimed = !has_result;
+    if claimed {
+        app_state.persist.save(file_hash.as_str(), "")?;
+        tracing::info!("Successfully cached file hash in KV storage. Processing further...");
+    } else {
+        tracing::info!("Analyzing file {} again as requested", file_hash);
+    }
+    let submitted = start_analysis(
+        app_state.clone(),
+        model_id,
+        base64_file,
+        file_type,
+        file_hash.clone(),
+        replaces,
+    )
+    .await;
+    // Nothing was handed to the background, so release the claim on the hash to let the client resubmit the file
+    if submitted.is_err() && claimed {
+        app_state.persist.remove(file_hash.as_str())?;
+    }
+    submitted
+}
+
+/// Submits the file to Azure and polls for its result in the background; an error means nothing was left running.
+async fn start_analysis(
+    app_state: Arc<AppState>,
+    model_id: String,
+    base64_file: String,
+    file_type: file_type::FileType,
+    file_hash: FileHash,
+    replaces: Option<i32>,
+) -> Result<FileStatus, AppError> {
+    let permit = app_state
+        .analysis_queue
+        .acquire()
+        .await
+        .map_err(IngestError::AnalysisBusy)?;
+
+    tracing::info!(
+        "New {} file detected, starting analysis...",
+        file_type.mime()
+    );
+    tracing::info!("Analyzing with model {}", model_id);
+    let res = analyze_file(
+        &base64_file,
+        &model_id,
+        app_state.config.api_version,
+        &app_state.azure_form_recognizer_api_key,
+        &app_state.client,
+        RetryPolicy::new(app_state.config.azure_max_retries),
+    )
+    .await?;
+    tracing::info!("Successfully received response from analysis API. Processing...");
+
+    if let StatusCode::ACCEPTED = res.status() {
+        app_state.quota.clear();
+        let result_url = res
+            .headers()
+            .get("Operation-Location")
+            .ok_or(anyhow!(
+                "Missing Operation-Location in response header. This should never happen"
+            ))?
+            .to_str()?
+            .to_string();
+        tracing::info!(
+            "Successfully queued image analysis. Result will be available at: {}",
+            result_url
+        );
+        let status = FileStatus {
+            file_hash: file_hash.clone(),
+            status: AnalysisStatus::Queued,
+        };
+
+        let span = analysis_span(&file_hash);
+        tokio::spawn(
+            async move {
+                let _permit = permit;
+                tracing::info!("Polling for results...");
+                let res =
+                    result_poll::poll_analysis_results(&app_state, &result_url, &file_hash).await;
+                tracing::info!("Analysis finished. Processing...");
                 let process_res = match res {
-                    Ok(success_res) => {
-                        process_analysis_results(&file_hash, success_res, app_state.clone()).await
+                    Ok(text) => {
+                        process_analysis_results(&file_hash, text, app_state.clone(), replaces)
+                            .await
                     }
-                    Err(err) => Err(err.into()),
+                    Err(err) => Err(err),
                 };
-                if let Err(err) = process_res {
+                if let Err(err) = &process_res {
                     tracing::error!(
                         "Error when processing analysis results: {}",
                         err.to_string()
                     );
+                    analysis_errors::record(&app_state, &file_hash, err).await;
                 } else {
                     tracing::info!("Successfully processed analysis results");
                 }
-            });
-            Ok(msg)
-        } else {
-            Err(AppError::Anyhow(anyhow!(
-                "Analysis API responded with an error status code {}",
-                res.status()
-            )))
-        }
+                webhook::notify(&app_state, &file_hash, process_res).await;
+            }
+            .instrument(span),
+        );
+        Ok(status)
     } else {
-        Err(AppError::Anyhow(anyhow!(
-            "No file was submitted for analysis"
-        )))
+        let status = res.status();
+        let body = res.text().await?;
+        if let Some(quota) = quota::parse_quota_error(status, &body) {
+            tracing::error!("Azure quota exceeded: {}", quota.message);
+            app_state
+                .quota
+                .record(&quota, app_state.config.quota_cooldown, chrono::Utc::now());
+            return Err(IngestError::QuotaExceeded {
+                retry_after: app_state.quota.paused_for(chrono::Utc::now()),
+                message: quota.message,
+            }
+            .into());
+        }
+        Err(IngestError::Analysis(format!(
+            "Analysis API responded with an error status code {}",
+            status
+        ))
+        .into())
     }
 }
 
+#[derive(Deserialize)]
+struct ListFilter {
+    /// Only include receipts carrying this tag
+    tag: Option<String>,
+    /// Only include receipts of this category
+    category: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ReceiptsFilter {
+    /// Earliest purchase time to include
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Latest purchase time to include
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    /// Part of the merchant name, matched case-insensitively
+    merchant: Option<String>,
+    /// Only include receipts carrying this tag
+    tag: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct AllData {
     name: String,
-    unit_price: f64,
+    unit_price: Option<f64>,
     count: f64,
+    quantity_unit: Option<String>,
+    /// Currency of `unit_price`, the receipt's
+    currency_code: Option<String>,
+    /// The receipt's lines add up to its total; `None` if it has no total
+    items_match_total: Option<bool>,
+    /// The receipt's, from its merchant
+    category: String,
     merchant_name: String,
     paid_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// Upper bound of an export's `created_at` window, read before any rows so receipts stored during the export are left for the next one instead of being skipped. `None` if nothing was stored after `since`.
+async fn export_until(
+    pool: &PgPool,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, AppError> {
+    Ok(sqlx::query_scalar!(
+        "SELECT MAX(created_at) FROM receipts WHERE $1::timestamptz IS NULL OR created_at > $1",
+        since
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
 async fn show_all(
     State(app_state): State<Arc<AppState>>,
-) -> Result<axum::Json<Vec<AllData>>, AppError> {
-    let pool = &app_state.pool;
-    let data = sqlx::query_as!(AllData, "SELECT receipts.paid_at, receipts.merchant_name, prices.count, prices.unit_price, products.name FROM receipts JOIN prices ON receipts.id = prices.receipt_id JOIN products ON products.id = prices.product_id").fetch_all(pool).await?;
-    Ok(axum::Json(data))
+    Query(page): Query<PageParams>,
+    Query(filter): Query<ListFilter>,
+    Query(export_params): Query<export::ExportParams>,
+) -> Result<(axum::http::HeaderMap, axum::Json<Listing<AllData>>), AppError> {
+    let pool = &app_state.read_pool;
+    let tag = filter
+        .tag
+        .map(|tag| tags::normalize_tag(&tag))
+        .transpose()?;
+    let since = export_params.since;
+    let until = export_until(pool, since).await?;
+    let mut headers = export::cursor_headers(until, since);
+    let Some(until) = until else {
+        let listing = if page.count_only {
+            Listing::Count { total: 0 }
+        } else {
+            Listing::Page(Page::new(vec![], 0, page))
+        };
+        listing.insert_total_header(&mut headers);
+        return Ok((headers, axum::Json(listing)));
+    };
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM prices JOIN receipts ON receipts.id = prices.receipt_id WHERE ($1::text IS NULL OR EXISTS (SELECT 1 FROM receipt_tags WHERE receipt_tags.receipt_id = prices.receipt_id AND receipt_tags.tag = $1)) AND ($2::timestamptz IS NULL OR receipts.created_at > $2) AND receipts.created_at <= $3 AND ($4::text IS NULL OR receipts.category = $4)"#,
+        tag,
+        since,
+        until,
+        filter.category
+    )
+    .fetch_one(pool)
+    .await?;
+    if page.count_only {
+        let listing = Listing::Count { total };
+        listing.insert_total_header(&mut headers);
+        return Ok((headers, axum::Json(listing)));
+    }
+    let data = sqlx::query_as!(AllData, "SELECT receipts.paid_at, receipts.merchant_name, prices.count, prices.quantity_unit, prices.unit_price, receipts.currency_code, receipts.items_match_total, receipts.category, products.name FROM receipts JOIN prices ON receipts.id = prices.receipt_id JOIN products ON products.id = prices.product_id WHERE ($3::text IS NULL OR EXISTS (SELECT 1 FROM receipt_tags WHERE receipt_tags.receipt_id = receipts.id AND receipt_tags.tag = $3)) AND ($4::timestamptz IS NULL OR receipts.created_at > $4) AND receipts.created_at <= $5 AND ($6::text IS NULL OR receipts.category = $6) ORDER BY receipts.paid_at DESC, prices.receipt_id, prices.line_index LIMIT $1 OFFSET $2", page.limit(), page.offset(), tag, since, until, filter.category).fetch_all(pool).await?;
+    let listing = Listing::Page(Page::new(data, total, page));
+    listing.insert_total_header(&mut headers);
+    Ok((headers, axum::Json(listing)))
 }
 
-async fn download(
+/// Same rows as [`show_all`], narrowed to a purchase time range, merchant and tag.
+async fn show_receipts(
     State(app_state): State<Arc<AppState>>,
-) -> Result<(axum::response::AppendHeaders<[(axum::http::header::HeaderName, &'static str); 2]>, String), AppError> {
-    let pool = &app_state.pool;
-    let data = sqlx::query_as!(AllData, "SELECT receipts.paid_at, receipts.merchant_name, prices.count, prices.unit_price, products.name FROM receipts JOIN prices ON receipts.id = prices.receipt_id JOIN products ON products.id = prices.product_id").fetch_all(pool).await?;
-    
-    let content: Vec<u8> = Vec::with_capacity(data.len() * 2);
-    let mut writer = csv::Writer::from_writer(content);
-    for row in data {
-        writer.serialize(row)?;
+    Query(page): Query<PageParams>,
+    Query(filter): Query<ReceiptsFilter>,
+) -> Result<axum::Json<Listing<AllData>>, AppError> {
+    if let (Some(from), Some(to)) = (filter.from, filter.to) {
+        if from > to {
+            return Err(AppError::bad_request("from must not be after to"));
+        }
+    }
+    let tag = filter
+        .tag
+        .map(|tag| tags::normalize_tag(&tag))
+        .transpose()?;
+    let pool = &app_state.read_pool;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM prices JOIN receipts ON receipts.id = prices.receipt_id WHERE ($1::timestamptz IS NULL OR receipts.paid_at >= $1) AND ($2::timestamptz IS NULL OR receipts.paid_at <= $2) AND ($3::text IS NULL OR receipts.merchant_name ILIKE '%' || $3 || '%') AND ($4::text IS NULL OR EXISTS (SELECT 1 FROM receipt_tags WHERE receipt_tags.receipt_id = receipts.id AND receipt_tags.tag = $4))"#,
+        filter.from,
+        filter.to,
+        filter.merchant,
+        tag
+    )
+    .fetch_one(pool)
+    .await?;
+    if page.count_only {
+        return Ok(axum::Json(Listing::Count { total }));
+    }
+    let data = sqlx::query_as!(
+        AllData,
+        "SELECT receipts.paid_at, receipts.merchant_name, prices.count, prices.quantity_unit, prices.unit_price, receipts.currency_code, receipts.items_match_total, receipts.category, products.name FROM receipts JOIN prices ON receipts.id = prices.receipt_id JOIN products ON products.id = prices.product_id WHERE ($3::timestamptz IS NULL OR receipts.paid_at >= $3) AND ($4::timestamptz IS NULL OR receipts.paid_at <= $4) AND ($5::text IS NULL OR receipts.merchant_name ILIKE '%' || $5 || '%') AND ($6::text IS NULL OR EXISTS (SELECT 1 FROM receipt_tags WHERE receipt_tags.receipt_id = receipts.id AND receipt_tags.tag = $6)) ORDER BY receipts.paid_at DESC, prices.receipt_id, prices.line_index LIMIT $1 OFFSET $2",
+        page.limit(),
+        page.offset(),
+        filter.from,
+        filter.to,
+        filter.merchant,
+        tag
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(axum::Json(Listing::Page(Page::new(data, total, page))))
+}
+
+type ExportChunk = Result<Vec<u8>, std::io::Error>;
+
+/// Sends the export over `chunks` as rows are read. An error midway ends the body with an error, so the client can't mistake the partial file for a complete one.
+async fn stream_export(
+    app_state: Arc<AppState>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    format: export::ExportFormat,
+    mut chunks: futures::channel::mpsc::Sender<ExportChunk>,
+) {
+    let mut encoder = export::ExportEncoder::new(format, &app_state.config);
+    if let Some(until) = until {
+        let mut rows = sqlx::query_as!(AllData, "SELECT receipts.paid_at, receipts.merchant_name, prices.count, prices.quantity_unit, prices.unit_price, receipts.currency_code, receipts.items_match_total, receipts.category, products.name FROM receipts JOIN prices ON receipts.id = prices.receipt_id JOIN products ON products.id = prices.product_id WHERE ($1::timestamptz IS NULL OR receipts.created_at > $1) AND receipts.created_at <= $2", since, until).fetch(&app_state.read_pool);
+        while let Some(row) = rows.next().await {
+            let chunk = match row.map_err(AppError::from).and_then(|row| encoder.row(row)) {
+                Ok(chunk) => Ok(chunk),
+                Err(err) => {
+                    tracing::error!("Export failed after it started: {}", err);
+                    Err(std::io::Error::other(err.public_message()))
+                }
+            };
+            let failed = chunk.is_err();
+            // An error sending means the client went away
+            if chunks.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
     }
-    let content = writer.into_inner()?;
-    
-    let headers: axum::response::AppendHeaders<[(axum::http::HeaderName, &str); 2]> = axum::response::AppendHeaders([
-        (axum::http::header::CONTENT_TYPE, "text/csv; charset=utf-8"),
-        (axum::http::header::CONTENT_DISPOSITION, "attachment; filename=\"data.csv\""),
-    ]);
+    let _ = chunks.send(Ok(encoder.finish())).await;
+}
+
+async fn download(
+    State(app_state): State<Arc<AppState>>,
+    Query(export_params): Query<export::ExportParams>,
+    Query(download_params): Query<export::DownloadParams>,
+) -> Result<
+    (
+        axum::http::HeaderMap,
+        axum::body::StreamBody<futures::channel::mpsc::Receiver<ExportChunk>>,
+    ),
+    AppError,
+> {
+    let since = export_params.since;
+    let until = export_until(&app_state.read_pool, since).await?;
+    let format = download_params.format;
+    let (chunks, body) = futures::channel::mpsc::channel(16);
+    tokio::spawn(stream_export(
+        app_state.clone(),
+        since,
+        until,
+        format,
+        chunks,
+    ));
+
+    let mut headers = export::cursor_headers(until, since);
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        format.content_type().parse().unwrap(),
+    );
+    headers.insert(
+        axum::http::header::CONTENT_DISPOSITION,
+        format.content_disposition().parse().unwrap(),
+    );
+
+    Ok((headers, axum::body::StreamBody::new(body)))
+}
 
-    Ok((headers, String::from_utf8(content)?))
+/// Describes the tables, their columns and the export columns, so integrators don't have to read migrations.
+async fn show_schema(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<schema::DataDictionary>, AppError> {
+    let columns = sqlx::query_as!(
+        schema::ColumnInfo,
+        r#"SELECT table_name::text AS "table_name!", column_name::text AS "column_name!", data_type::text AS "data_type!", is_nullable = 'YES' AS "is_nullable!" FROM information_schema.columns WHERE table_schema = current_schema() AND table_name <> '_sqlx_migrations' ORDER BY table_name, ordinal_position"#
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
+    Ok(axum::Json(schema::data_dictionary(columns)))
 }
 
 // TODO: Remove this dev endpoint
@@ -320,265 +898,2489 @@ async fn clear_db(State(app_state): State<Arc<AppState>>) -> Result<&'static str
     Ok(msg)
 }
 
-async fn hello_world() -> &'static str {
-    "Hello, world!"
+#[derive(Serialize)]
+struct Stats {
+    uploads: upload_stats::UploadCounts,
 }
 
-async fn show_all_parsing_results(
-    State(app_state): State<Arc<AppState>>,
-) -> Result<axum::Json<Vec<AnalyzeResultOperation>>, AppError> {
-    let parsed_results = app_state
-        .persist
-        .list()?
-        .into_iter()
-        .map(|k| {
-            app_state
-                .persist
-                .load::<String>(&k)
-                .map_err(AppError::from)
-                .and_then(|raw| serde_json::from_str(&raw).map_err(AppError::from))
-        })
-        .collect::<Result<Vec<AnalyzeResultOperation>, AppError>>()?;
-    Ok(axum::Json(parsed_results))
+/// Counters since the service started.
+async fn show_stats(State(app_state): State<Arc<AppState>>) -> axum::Json<Stats> {
+    axum::Json(Stats {
+        uploads: app_state.upload_stats.counts(),
+    })
 }
 
-#[derive(Clone)]
-struct AppState {
-    client: Client,
-    azure_form_recognizer_api_key: String,
-    pool: PgPool,
-    client_secret: String,
-    persist: PersistInstance,
+#[derive(Serialize)]
+struct Health {
+    /// `ok`, or `quota_exceeded` while new submissions are paused after an Azure quota error
+    status: &'static str,
+    analyses_waiting: usize,
+    azure_quota: Option<quota::QuotaHit>,
 }
 
-const UPLOAD_LIMIT_BYTES: usize = 1024 * 1024 * 10; // 10 MB
+async fn health(State(app_state): State<Arc<AppState>>) -> axum::Json<Health> {
+    let paused = app_state.quota.paused_for(chrono::Utc::now()).is_some();
+    axum::Json(Health {
+        status: if paused { "quota_exceeded" } else { "ok" },
+        analyses_waiting: app_state.analysis_queue.waiting(),
+        azure_quota: app_state.quota.last_hit(),
+    })
+}
 
-// Postgres maximum number of parameters in a statement
-const BIND_LIMIT: usize = 65535;
+#[derive(Serialize)]
+struct Readiness {
+    /// `ok`, or `unavailable` if any dependency failed
+    status: &'static str,
+    /// Dependencies that could not be reached: `database` and `cache`
+    failed: Vec<&'static str>,
+}
 
-#[shuttle_runtime::main]
-async fn main(
-    #[shuttle_persist::Persist] persist: PersistInstance,
-    #[shuttle_aws_rds::Postgres] pool: PgPool,
-    #[shuttle_secrets::Secrets] secret_store: SecretStore,
-) -> ShuttleAxum {
-    sqlx::migrate!()
-        .run(&pool)
+/// Readiness probe for load balancers, so it needs no token: answers `503 Service Unavailable` unless both the DB and the cache respond.
+async fn readiness(State(app_state): State<Arc<AppState>>) -> (StatusCode, axum::Json<Readiness>) {
+    let mut failed = vec![];
+    if let Err(err) = sqlx::query_scalar!(r#"SELECT 1 AS "one!""#)
+        .fetch_one(&app_state.pool)
         .await
-        .map_err(anyhow::Error::from)?;
-
-    let Some(azure_form_recognizer_api_key) = secret_store.get("AZURE_FORM_RECOGNIZER_KEY") else {
-        return Err(shuttle_runtime::Error::BuildPanic(
-            "Could not find AZURE_FORM_RECOGNIZER_KEY in secrets".into(),
-        ));
+    {
+        tracing::error!("Readiness check could not query the DB: {}", err);
+        failed.push("database");
+    }
+    let persist = app_state.persist.clone();
+    let listed = tokio::task::spawn_blocking(move || persist.list())
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|listed| Ok(listed?));
+    if let Err(err) = listed {
+        tracing::error!("Readiness check could not list the cache: {}", err);
+        failed.push("cache");
+    }
+    let (status_code, status) = if failed.is_empty() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
     };
+    (status_code, axum::Json(Readiness { status, failed }))
+}
 
-    let Some(client_secret) = secret_store.get("CLIENT_SECRET") else {
-        return Err(shuttle_runtime::Error::BuildPanic(
-            "Could not find CLIENT_SECRET in secrets".into(),
-        ));
-    };
+#[derive(Deserialize)]
+struct RecomputeDatesParams {
+    /// Only recompute receipts from this merchant (case-insensitive)
+    merchant: Option<String>,
+}
 
-    let client = Client::new();
+#[derive(Serialize)]
+struct RecomputedDates {
+    checked: usize,
+    changed: usize,
+    /// Receipts without a usable cached analysis, e.g. imported ones
+    skipped: usize,
+}
 
-    let app_state = AppState {
-        client,
-        azure_form_recognizer_api_key,
-        pool,
-        client_secret,
-        persist,
-    };
+/// `paid_at` of the analysis under the current date strategy, if it differs from what is stored.
+fn recomputed_paid_at(
+    stored: chrono::DateTime<chrono::Utc>,
+    analysis: AnalyzeResultOperation,
+    config: &Config,
+) -> Result<Option<(chrono::DateTime<chrono_tz::Tz>, extract::TimePrecision)>, AppError> {
+    let receipt = extract::extract_receipt(analysis, config)?;
+    Ok((receipt.paid_at != stored).then_some((receipt.paid_at, receipt.time_precision)))
+}
 
-    let state = Arc::new(app_state);
+/// Re-applies the date strategy to stored receipts, fixing dates saved before a fix to it.
+async fn recompute_dates(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<RecomputeDatesParams>,
+) -> Result<axum::Json<RecomputedDates>, AppError> {
+    let receipts = sqlx::query!(
+        r#"SELECT id, file_sha256 AS "file_sha256!", paid_at FROM receipts WHERE file_sha256 IS NOT NULL AND ($1::text IS NULL OR lower(merchant_name) = lower($1)) ORDER BY id"#,
+        params.merchant
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
 
-    let router = Router::new()
-        .route("/", get(hello_world))
-        .route("/dev/db/all", delete(clear_db))
-        .route("/dev/db/all", put(repopulate_db_from_cache))
-        .route("/dev/cache/all", get(show_all_parsing_results))
-        .route("/all", get(show_all))
-        .route(
-            "/upload",
-            post(upload).layer(DefaultBodyLimit::max(UPLOAD_LIMIT_BYTES)),
-        )
-        .route("/download", get(download))
-        .layer(axum::middleware::from_fn_with_state(state.clone(), auth))
-        .with_state(state);
+    let mut report = RecomputedDates {
+        checked: receipts.len(),
+        changed: 0,
+        skipped: 0,
+    };
+    for receipt in receipts {
+        let analysis = app_state
+            .persist
+            .load::<String>(&receipt.file_sha256)
+            .map_err(AppError::from)
+            .and_then(|raw| {
+                cache::parse_operation(&raw, app_state.config.api_version).map_err(AppError::from)
+            });
+        let recomputed = analysis
+            .and_then(|analysis| recomputed_paid_at(receipt.paid_at, analysis, &app_state.config));
+        match recomputed {
+            Ok(Some((paid_at, time_precision))) => {
+                tracing::info!(
+                    "Receipt {} was paid at {} rather than {}",
+                    receipt.id,
+                    paid_at,
+                    receipt.paid_at
+                );
+                let (local_paid_at, time_zone) = local_time::local_columns(paid_at);
+                sqlx::query!(
+                    "UPDATE receipts SET paid_at = $2, time_precision = $3, local_paid_at = $4, time_zone = $5 WHERE id = $1",
+                    receipt.id,
+                    paid_at,
+                    time_precision.as_str(),
+                    local_paid_at,
+                    time_zone
+                )
+                .execute(&app_state.pool)
+                .await?;
+                report.changed += 1;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!(
+                    "Could not recompute the date of receipt {}: {}",
+                    receipt.id,
+                    err
+                );
+                report.skipped += 1;
+            }
+        }
+    }
+    Ok(axum::Json(report))
+}
 
-    Ok(router.into())
-    // tracing::info!("Response: {res:?}");
+async fn hello_world() -> &'static str {
+    "Hello, world!"
 }
 
-async fn auth<B>(
-    State(app_state): State<Arc<AppState>>,
-    axum::TypedHeader(axum::headers::Authorization(bearer)): axum::TypedHeader<
-        axum::headers::Authorization<axum::headers::authorization::Bearer>,
-    >,
-    request: axum::http::Request<B>,
-    next: axum::middleware::Next<B>,
-) -> Result<axum::response::Response, StatusCode> {
-    if app_state.client_secret != bearer.token() {
-        return Err(StatusCode::FORBIDDEN);
+/// A cache entry without a result is a claimed hash whose analysis is running or never completed, e.g. because the service restarted.
+fn has_analysis_result(app_state: &AppState, file_hash: &FileHash) -> Result<bool, AppError> {
+    if !cached_hashes(app_state)?.contains(file_hash) {
+        return Ok(false);
     }
-    let response = next.run(request).await;
-    Ok(response)
+    let text = app_state.persist.load::<String>(file_hash.as_str())?;
+    Ok(!text.is_empty())
 }
 
-async fn save_analysis_data(
-    pool: &PgPool,
-    analysis_result: AnalyzeResultOperation,
-    file_hash: &str,
-) -> Result<(), AppError> {
-    let receipt_fields = analysis_result
-        .analyzeResult
-        .ok_or(anyhow!("Missing analyzeResult field"))?
-        .documents
-        .ok_or(anyhow!("Missing documents field"))?
-        .first()
-        .ok_or(anyhow!("Documents field is present but empty"))?
-        .fields
-        .clone();
-    let (product_names, (counts, unit_prices)): (Vec<_>, (Vec<_>, Vec<_>)) = receipt_fields
-        .items
-        .value_array
-        .iter()
-        .filter_map(|item| {
-            let Some(unit_price) = item
-                .value_object
-                .unit_price
-                .as_ref()
-                .or(item.value_object.total_price.as_ref())
-                .map(|obj| obj.value_number)
-            else {
-                // We throw away items where no price was detected
-                return None;
-            };
-            let name = item.value_object.description.value_string.clone();
-            let count = if let Some(q) = &item.value_object.quantity {
-                q.value_number
-            } else {
-                1.0
-            };
-            Some((name, (count, unit_price)))
-        })
+/// Keys of the cache entries. Every key is a file hash; anything else was not written by this service and is skipped.
+fn cached_hashes(app_state: &AppState) -> Result<Vec<FileHash>, AppError> {
+    Ok(app_state
+        .persist
+        .list()?
         .into_iter()
-        .take(BIND_LIMIT)
-        .unzip();
+        .filter_map(|key| match key.parse() {
+            Ok(file_hash) => Some(file_hash),
+            Err(err) => {
+                tracing::warn!("Skipping cache entry: {}", err);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Reads a cache entry off the async runtime, since the persist backend does blocking file IO.
+async fn load_cached(
+    app_state: &AppState,
+    file_hash: &FileHash,
+) -> Result<CachedAnalysis, AppError> {
+    let persist = app_state.persist.clone();
+    let key = file_hash.to_string();
+    let raw = tokio::task::spawn_blocking(move || persist.load::<String>(&key))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(cache::parse_cached(&raw, app_state.config.api_version)?)
+}
+
+#[derive(Serialize)]
+struct UnreadableEntry {
+    file_hash: FileHash,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct ParsingResults {
+    results: Vec<AnalyzeResultOperation>,
+    /// Entries that could not be loaded or parsed, e.g. ones stored under an older schema
+    unreadable: Vec<UnreadableEntry>,
+}
+
+/// Lists the analyses Azure completed; pending and failed ones are left out. Unreadable entries are listed apart instead of failing the whole listing.
+async fn show_all_parsing_results(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<ParsingResults>, AppError> {
+    let entries = cache::load_bounded(
+        cached_hashes(&app_state)?,
+        app_state.config.cache_read_concurrency,
+        |file_hash| {
+            let app_state = app_state.clone();
+            async move {
+                let entry = load_cached(&app_state, &file_hash).await;
+                (file_hash, entry)
+            }
+        },
+    )
+    .await;
+    let mut parsing_results = ParsingResults {
+        results: Vec::new(),
+        unreadable: Vec::new(),
+    };
+    for (file_hash, entry) in entries {
+        match entry {
+            Ok(CachedAnalysis::Done(operation)) => parsing_results.results.push(*operation),
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!("Skipping unreadable cache entry {}: {}", file_hash, err);
+                parsing_results.unreadable.push(UnreadableEntry {
+                    file_hash,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+    parsing_results
+        .unreadable
+        .sort_unstable_by(|a, b| a.file_hash.cmp(&b.file_hash));
+    Ok(axum::Json(parsing_results))
+}
+
+#[derive(Serialize)]
+struct CacheEntry {
+    file_hash: FileHash,
+    status: cache::EntryStatus,
+}
+
+/// Lists every cached hash with what its entry holds. Responses are only checked to be well-formed, so this stays cheap on large caches and a corrupt entry doesn't fail the listing.
+async fn list_cache_entries(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<Vec<CacheEntry>>, AppError> {
+    let mut entries = cache::load_bounded(
+        cached_hashes(&app_state)?,
+        app_state.config.cache_read_concurrency,
+        |file_hash| {
+            let persist = app_state.persist.clone();
+            async move {
+                let key = file_hash.to_string();
+                let raw = tokio::task::spawn_blocking(move || persist.load::<String>(&key))
+                    .await
+                    .map_err(anyhow::Error::from)?;
+                let status = match raw {
+                    Ok(raw) => cache::entry_status(&raw),
+                    Err(err) => {
+                        tracing::warn!("Could not load cache entry {}: {}", file_hash, err);
+                        cache::EntryStatus::Corrupt
+                    }
+                };
+                Ok::<_, AppError>(CacheEntry { file_hash, status })
+            }
+        },
+    )
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_unstable_by(|a, b| a.file_hash.cmp(&b.file_hash));
+    Ok(axum::Json(entries))
+}
+
+#[derive(Serialize)]
+struct FailedAnalysis {
+    file_hash: FileHash,
+    #[serde(flatten)]
+    failure: cache::AnalysisFailure,
+}
+
+/// Lists the files Azure could not analyze, with the error it reported.
+async fn show_failed_analyses(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<Vec<FailedAnalysis>>, AppError> {
+    let entries = cache::load_bounded(
+        cached_hashes(&app_state)?,
+        app_state.config.cache_read_concurrency,
+        |file_hash| {
+            let app_state = app_state.clone();
+            async move {
+                let entry = load_cached(&app_state, &file_hash).await;
+                (file_hash, entry)
+            }
+        },
+    )
+    .await;
+    let mut failed = Vec::new();
+    for (file_hash, entry) in entries {
+        if let CachedAnalysis::Failed(failure) = entry? {
+            failed.push(FailedAnalysis { file_hash, failure });
+        }
+    }
+    Ok(axum::Json(failed))
+}
+
+#[derive(Deserialize)]
+struct PruneCacheParams {
+    /// Overrides the configured cache TTL
+    older_than_days: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct PrunedCache {
+    checked: usize,
+    pruned: usize,
+}
+
+/// Removes cached analyses older than the cache TTL, to keep the store from growing without bound. Their receipts stay in the DB,
+/// but repopulating from the cache won't bring them back, the consistency check flags them, and uploading the file again analyzes it again.
+async fn prune_cache(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<PruneCacheParams>,
+) -> Result<axum::Json<PrunedCache>, AppError> {
+    let ttl = match params.older_than_days {
+        Some(days) => cache::ttl_from_days(days)
+            .ok_or(AppError::bad_request("older_than_days is out of range"))?,
+        None => app_state.config.cache_ttl.ok_or(AppError::bad_request(
+            "No cache TTL is configured, pass older_than_days",
+        ))?,
+    };
+    let cutoff = cache::prune_cutoff(chrono::Utc::now(), ttl)
+        .ok_or(AppError::bad_request("older_than_days is out of range"))?;
+    let entries = cache::load_bounded(
+        cached_hashes(&app_state)?,
+        app_state.config.cache_read_concurrency,
+        |file_hash| {
+            let app_state = app_state.clone();
+            async move {
+                let entry = load_cached(&app_state, &file_hash).await;
+                (file_hash, entry)
+            }
+        },
+    )
+    .await;
+    let mut pruned = PrunedCache {
+        checked: entries.len(),
+        pruned: 0,
+    };
+    for (file_hash, entry) in entries {
+        let analyzed_at = match entry {
+            Ok(entry) => cache::analyzed_at(&entry),
+            Err(err) => {
+                tracing::warn!("Not pruning unreadable cache entry {}: {}", file_hash, err);
+                continue;
+            }
+        };
+        if analyzed_at.is_some_and(|analyzed_at| analyzed_at < cutoff) {
+            let persist = app_state.persist.clone();
+            let key = file_hash.to_string();
+            tokio::task::spawn_blocking(move || persist.remove(&key))
+                .await
+                .map_err(anyhow::Error::from)??;
+            pruned.pruned += 1;
+        }
+    }
+    tracing::info!(
+        "Pruned {} of {} cached analyses older than {}",
+        pruned.pruned,
+        pruned.checked,
+        cutoff
+    );
+    Ok(axum::Json(pruned))
+}
+
+/// Imports a receipt already parsed by Google Document AI without going through Azure. Only the DB is written, since the cache holds Azure responses exclusively.
+/// The response is kept as imported, so rebuilding the DB from the cache can import it again.
+async fn import_google_receipt(
+    State(app_state): State<Arc<AppState>>,
+    body: axum::body::Bytes,
+) -> Result<String, AppError> {
+    let file_hash = FileHash::of(&body);
+    let receipt = google_receipt(&app_state.config, &body)?;
+    sqlx::query!(
+        "INSERT INTO google_imports(file_sha256, payload) VALUES ($1, $2) ON CONFLICT (file_sha256) DO NOTHING",
+        file_hash.as_str(),
+        &body[..]
+    )
+    .execute(&app_state.pool)
+    .await?;
+    save_receipt_record(&app_state, receipt, &file_hash).await?;
+    let msg = format!("Successfully imported Google Document AI receipt {file_hash}");
+    tracing::info!(msg);
+    Ok(msg)
+}
 
-    let merchant_name = &receipt_fields.merchant_name.value_string;
+fn google_receipt(config: &Config, body: &[u8]) -> Result<ReceiptRecord, AppError> {
+    let response = google::parse_response(body)?;
+    google::into_receipt_record(response, config)
+}
 
-    // Netto receipt date strings detected by analysis API are usually well formatted (YYYY-m-d), but when generating a date value from that the model tends to flip month and day;
-    // TODO: For now Netto dates will be a special case, until similar issue is encountered elsewhere
-    let date_str = if receipt_fields.transaction_date.content.contains("-")
-        && merchant_name.to_lowercase().contains("netto")
+/// Imports every kept Google Document AI response again, as the cache has nothing to rebuild those receipts from.
+async fn replay_google_imports(app_state: &AppState) -> Vec<(FileHash, Repopulated)> {
+    let file_hashes = match sqlx::query_scalar!(
+        r#"SELECT file_sha256 AS "file_sha256: FileHash" FROM google_imports ORDER BY file_sha256"#
+    )
+    .fetch_all(&app_state.pool)
+    .await
     {
-        receipt_fields.transaction_date.content
-    } else {
-        receipt_fields.transaction_date.value_date
+        Ok(file_hashes) => file_hashes,
+        Err(err) => {
+            tracing::error!("Could not load Google imports to replay: {}", err);
+            return vec![];
+        }
     };
+    let mut outcomes = vec![];
+    for file_hash in file_hashes {
+        let replayed = async {
+            let payload = sqlx::query_scalar!(
+                "SELECT payload FROM google_imports WHERE file_sha256 = $1",
+                file_hash.as_str()
+            )
+            .fetch_one(&app_state.pool)
+            .await?;
+            let receipt = google_receipt(&app_state.config, &payload)?;
+            save_receipt_record(app_state, receipt, &file_hash).await
+        }
+        .instrument(analysis_span(&file_hash))
+        .await;
+        let outcome = match replayed {
+            Ok(()) => Repopulated::Saved,
+            Err(err) => {
+                tracing::error!("Could not replay Google import {}: {}", file_hash, err);
+                analysis_errors::record(app_state, &file_hash, &err).await;
+                Repopulated::Failed
+            }
+        };
+        outcomes.push((file_hash, outcome));
+    }
+    outcomes
+}
 
-    let datetime_str = date_str + " " + &receipt_fields.transaction_time.value_time;
-    let timestamp = chrono::NaiveDateTime::parse_from_str(&datetime_str, "%Y-%m-%d %H:%M:%S")
-        .map_err(|_| anyhow!(format!("Invalid date string: {datetime_str}")))?;
-    let chrono::LocalResult::Single(timestamp_tz) = Copenhagen.from_local_datetime(&timestamp)
-    else {
-        return Err(anyhow!("Error converting naive timestamp to Copenhagen time").into());
+#[derive(Serialize)]
+struct ExtractionDebug {
+    file_hash: FileHash,
+    receipt: Option<ReceiptRecord>,
+    error: Option<String>,
+    trace: extract::ExtractionTrace,
+}
+
+/// Re-runs extraction on a cached analysis result and explains each decision; nothing is written to the DB.
+async fn debug_extraction(
+    State(app_state): State<Arc<AppState>>,
+    Path(file_hash): Path<FileHash>,
+) -> Result<axum::Json<ExtractionDebug>, AppError> {
+    if !cached_hashes(&app_state)?.contains(&file_hash) {
+        return Err(AppError::not_found(format!(
+            "File {file_hash} was never analyzed"
+        )));
+    }
+    let data = match load_cached(&app_state, &file_hash).await? {
+        CachedAnalysis::Pending => {
+            return Err(AppError::bad_request(format!(
+                "Analysis of file {file_hash} has not finished yet"
+            )))
+        }
+        CachedAnalysis::Failed(failure) => {
+            return Err(AppError::bad_request(format!(
+                "Azure could not analyze file {file_hash}: {} ({})",
+                failure.message, failure.code
+            )))
+        }
+        CachedAnalysis::Done(data) => *data,
     };
+    let mut trace = extract::ExtractionTrace::default();
+    let (receipt, error) =
+        match extract::extract_receipt_traced(data, &app_state.config, &mut trace) {
+            Ok(receipt) => (Some(receipt), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+    Ok(axum::Json(ExtractionDebug {
+        file_hash,
+        receipt,
+        error,
+        trace,
+    }))
+}
 
-    let tx = pool.begin().await?;
+#[derive(Clone)]
+struct AppState {
+    client: Client,
+    azure_form_recognizer_api_key: String,
+    pool: PgPool,
+    /// For heavy read-only queries; a replica that may lag behind `pool`, or `pool` itself
+    read_pool: PgPool,
+    client_tokens: client_tokens::ClientTokens,
+    persist: PersistInstance,
+    config: Config,
+    analysis_queue: Arc<AnalysisQueue>,
+    quota: Arc<quota::QuotaStatus>,
+    upload_stats: Arc<upload_stats::UploadStats>,
+    last_repopulation: Arc<std::sync::Mutex<Option<RepopulationReport>>>,
+    /// Runs on every uploaded file before it is submitted for analysis
+    upload_check: Arc<dyn upload_check::UploadCheck>,
+}
+
+// Time Azure usually needs to analyze a receipt, to estimate `Retry-After` when the analysis queue is full
+const TYPICAL_ANALYSIS_TIME: Duration = Duration::from_secs(30);
 
-    // TODO: Currently the entire transaction crashes if there already exists a receipt with identical timestamp; in real life it would be possible for that to happen (especially if there is a lot of users)
-    let receipt_id =
-        insert_receipt_if_not_exists(pool, merchant_name, timestamp_tz, file_hash).await?;
+// Postgres maximum number of parameters in a statement
+const BIND_LIMIT: usize = 65535;
 
-    insert_products_if_not_exist(pool, &product_names)
+#[shuttle_runtime::main]
+async fn main(
+    #[shuttle_persist::Persist] persist: PersistInstance,
+    #[shuttle_aws_rds::Postgres] pool: PgPool,
+    #[shuttle_secrets::Secrets] secret_store: SecretStore,
+) -> ShuttleAxum {
+    sqlx::migrate!()
+        .run(&pool)
         .await
-        .map_err(AppError::from)?;
+        .map_err(anyhow::Error::from)?;
+
+    let Some(azure_form_recognizer_api_key) = secret_store.get("AZURE_FORM_RECOGNIZER_KEY") else {
+        return Err(shuttle_runtime::Error::BuildPanic(
+            "Could not find AZURE_FORM_RECOGNIZER_KEY in secrets".into(),
+        ));
+    };
+
+    let Some(client_secret) = secret_store.get("CLIENT_SECRET") else {
+        return Err(shuttle_runtime::Error::BuildPanic(
+            "Could not find CLIENT_SECRET in secrets".into(),
+        ));
+    };
+    let client_tokens = client_tokens::ClientTokens::parse(&client_secret)
+        .map_err(shuttle_runtime::Error::BuildPanic)?;
+
+    let config = Config::from_secrets(&secret_store).map_err(shuttle_runtime::Error::BuildPanic)?;
+
+    let analysis_queue = Arc::new(AnalysisQueue::new(
+        config.analysis_concurrency,
+        config.analysis_queue_depth,
+        config.analysis_queue_timeout,
+        TYPICAL_ANALYSIS_TIME,
+    ));
+
+    let read_pool = read_pool(&pool, config.read_replica_url.as_deref())
+        .map_err(|err| shuttle_runtime::Error::BuildPanic(err.to_string()))?;
+
+    let client = Client::new();
+    let request_timeout = config.request_timeout;
+    let upload_limit = config.upload_limit_bytes;
+    let upload_check: Arc<dyn upload_check::UploadCheck> = match config.max_upload_file_bytes {
+        Some(max) => Arc::new(upload_check::MaxFileSize(max)),
+        None => Arc::new(upload_check::AcceptAll),
+    };
+
+    let app_state = AppState {
+        client,
+        azure_form_recognizer_api_key,
+        pool,
+        read_pool,
+        client_tokens,
+        persist,
+        config,
+        analysis_queue,
+        quota: Arc::default(),
+        upload_stats: Arc::default(),
+        last_repopulation: Arc::default(),
+        upload_check,
+    };
+
+    let state = Arc::new(app_state);
 
-    upsert_prices_for_products_and_receipt(pool, counts, unit_prices, product_names, receipt_id)
+    let router = with_request_timeout(
+        Router::new()
+            .route("/", get(hello_world))
+            .route("/health", get(health))
+            .route("/dev/db/all", delete(clear_db))
+            .route("/dev/db/all", put(repopulate_db_from_cache))
+            .route("/dev/db/repopulation", get(last_repopulation))
+            .route("/dev/db/dates", post(recompute_dates))
+            .route("/dev/cache", get(list_cache_entries))
+            .route("/dev/cache/all", get(show_all_parsing_results))
+            .route("/dev/cache/failed", get(show_failed_analyses))
+            .route("/dev/cache/prune", post(prune_cache))
+            .route("/dev/errors", get(analysis_errors::recent_errors))
+            .route("/debug/:hash", get(debug_extraction))
+            .route("/status/:file_hash", get(analysis_status::analysis_status))
+            .route("/admin/consistency", get(consistency::check_consistency))
+            .route("/admin/reconcile", post(consistency::reconcile))
+            .route("/all", get(show_all))
+            // Exports of thousands of rows compress well; clients not sending `Accept-Encoding: gzip` get them as is
+            .route(
+                "/download",
+                get(download).layer(tower_http::compression::CompressionLayer::new()),
+            )
+            .route("/schema", get(show_schema))
+            .route("/search", get(search::search))
+            .route("/search/products", get(search::search_products))
+            .route("/review", get(review::review))
+            .route("/receipts", get(show_receipts))
+            .route("/receipts/compare", get(compare::compare_receipts))
+            .route(
+                "/receipts/:id",
+                get(receipts::show_receipt).delete(receipts::delete_receipt),
+            )
+            .route("/receipts/:id/note", patch(receipts::set_note))
+            .route(
+                "/receipts/:id/reconciliation",
+                get(reconcile::receipt_reconciliation),
+            )
+            .route("/receipts/:id/tags", post(tags::add_tags))
+            .route("/receipts/:id/tags/:tag", delete(tags::remove_tag))
+            .route("/receipts/:id/taxes", get(taxes::receipt_taxes))
+            .route("/stats", get(show_stats))
+            .route("/stats/taxes", get(taxes::tax_stats))
+            .route("/categories/conflicts", get(categories::category_conflicts))
+            .route("/analytics/top-items", get(analytics::top_items))
+            .route("/analytics/timeline", get(analytics::timeline))
+            .route("/spend", get(analytics::timeline))
+            .route("/units", get(analytics::units))
+            .route("/products/:name/prices", get(analytics::product_prices))
+            .route("/merchants", get(analytics::merchants))
+            .route("/merchants/:name/report.pdf", get(report::merchant_report))
+            .route("/import/google", post(import_google_receipt)),
+        request_timeout,
+    )
+    // Uploads may legitimately wait for a free analysis slot, so they are bounded by the analysis queue timeout instead
+    .route(
+        "/upload",
+        post(upload).layer(DefaultBodyLimit::max(upload_limit)),
+    )
+    .route(
+        "/upload/base64",
+        // Base64 takes 4 bytes for every 3 of the file
+        post(upload_base64).layer(DefaultBodyLimit::max(upload_limit / 3 * 4 + 1024)),
+    )
+    .route("/analyze-urls", post(analyze_urls::analyze_urls))
+    .layer(axum::middleware::from_fn_with_state(state.clone(), auth))
+    .route("/healthz", get(readiness))
+    .with_state(state);
+
+    Ok(router.into())
+    // tracing::info!("Response: {res:?}");
+}
+
+/// Connects lazily, so an unreachable replica fails the queries using it rather than startup.
+fn read_pool(primary: &PgPool, replica_url: Option<&str>) -> Result<PgPool, sqlx::Error> {
+    match replica_url {
+        Some(url) => sqlx::postgres::PgPoolOptions::new().connect_lazy(url),
+        None => Ok(primary.clone()),
+    }
+}
+
+/// Answers `504 Gateway Timeout` when a handler takes longer than `timeout`.
+/// Work handed off to detached tasks, like analyzing an upload or repopulating the DB, is unaffected and keeps running.
+fn with_request_timeout<S>(router: Router<S>, timeout: Duration) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.layer(
+        tower::ServiceBuilder::new()
+            .layer(axum::error_handling::HandleErrorLayer::new(
+                |_: axum::BoxError| async {
+                    (
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "Request took too long to complete",
+                    )
+                },
+            ))
+            .timeout(timeout),
+    )
+}
+
+async fn auth<B>(
+    State(app_state): State<Arc<AppState>>,
+    axum::TypedHeader(axum::headers::Authorization(bearer)): axum::TypedHeader<
+        axum::headers::Authorization<axum::headers::authorization::Bearer>,
+    >,
+    mut request: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> Result<axum::response::Response, StatusCode> {
+    let Some(client) = app_state.client_tokens.client(bearer.token()).cloned() else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    // Everything logged while handling the request names the client that made it
+    let span = tracing::info_span!("request", client = %client.0);
+    request.extensions_mut().insert(client);
+    let response = next.run(request).instrument(span).await;
+    Ok(response)
+}
+
+async fn save_analysis_data(
+    app_state: &AppState,
+    analysis_result: AnalyzeResultOperation,
+    file_hash: &FileHash,
+) -> Result<(), AppError> {
+    let receipt = extract::extract_receipt(analysis_result, &app_state.config)?;
+    save_receipt_record(app_state, receipt, file_hash).await
+}
+
+/// Stores an extracted receipt regardless of which OCR provider produced it.
+async fn save_receipt_record(
+    app_state: &AppState,
+    receipt: ReceiptRecord,
+    file_hash: &FileHash,
+) -> Result<(), AppError> {
+    let pool = &app_state.pool;
+    let config = &app_state.config;
+
+    if let Some((total_tax, summed_tax)) =
+        reconcile::tax_mismatch(receipt.total_tax, &receipt.taxes)
+    {
+        tracing::warn!(
+            "Tax lines of file {} add up to {} but the receipt's total tax is {}",
+            file_hash,
+            summed_tax,
+            total_tax
+        );
+    }
+
+    let merged_into = sqlx::query_scalar!(
+        "SELECT receipt_id FROM merged_files WHERE file_sha256 = $1",
+        file_hash.as_str()
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(receipt_id) = merged_into {
+        tracing::info!(
+            "File {} is already merged into receipt {}, leaving it as it is",
+            file_hash,
+            receipt_id
+        );
+        return Ok(());
+    }
+
+    let duplicate_of =
+        find_probable_duplicate(pool, &receipt, file_hash, config.duplicate_total_tolerance)
+            .await?;
+
+    // Everything of one file is written in this transaction, so a failure halfway leaves none of it behind
+    let mut tx = pool.begin().await?;
+
+    if let Some(existing_id) =
+        duplicate_of.filter(|_| config.duplicate_policy == DuplicatePolicy::Merge)
+    {
+        tracing::info!(
+            "Receipt from file {} is a probable duplicate of receipt {}, merging it into the existing receipt",
+            file_hash,
+            existing_id
+        );
+        merge_file(
+            &mut tx,
+            file_hash,
+            receipt.items,
+            existing_id,
+            &config.category_rules,
+        )
         .await?;
-    tx.commit().await?;
-    Ok(())
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    let duplicate_of = duplicate_of.filter(|_| config.duplicate_policy == DuplicatePolicy::Flag);
+    if let Some(existing_id) = duplicate_of {
+        tracing::warn!(
+            "Receipt from file {} is a probable duplicate of receipt {}, flagging it",
+            file_hash,
+            existing_id
+        );
+    }
+    let Some(receipt) =
+        write_new_receipt(&mut tx, config, receipt, file_hash, duplicate_of).await?
+    else {
+        tx.commit().await?;
+        return Ok(());
+    };
+    let existing_id = sqlx::query_scalar!(
+        "SELECT id FROM receipts WHERE file_sha256 = $1",
+        file_hash.as_str()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tracing::info!(
+        "File {} is already stored as receipt {}, replacing its data",
+        file_hash,
+        existing_id
+    );
+    drop(tx);
+    replace_receipt_record(app_state, receipt, existing_id).await
 }
 
-async fn upsert_prices_for_products_and_receipt(
-    pool: &PgPool,
-    counts: Vec<f64>,
-    unit_prices: Vec<f64>,
-    product_names: Vec<String>,
+/// Stores the receipt with everything on it through `conn`, so it all lands in the caller's transaction or none of it does.
+/// Hands the receipt back if one from the same file is already stored.
+async fn write_new_receipt(
+    conn: &mut PgConnection,
+    config: &Config,
+    receipt: ReceiptRecord,
+    file_hash: &FileHash,
+    duplicate_of: Option<i32>,
+) -> Result<Option<ReceiptRecord>, AppError> {
+    let category =
+        categories::merchant_category(&receipt.merchant_name, &config.merchant_category_rules);
+    let Some(receipt_id) =
+        insert_receipt_if_not_exists(conn, &receipt, file_hash, duplicate_of, category).await?
+    else {
+        return Ok(Some(receipt));
+    };
+    insert_tax_lines(conn, &receipt.taxes, receipt_id).await?;
+    insert_products_if_not_exist(conn, &receipt.items, &config.category_rules).await?;
+    upsert_prices_for_products_and_receipt(conn, receipt.items, receipt_id).await?;
+    Ok(None)
+}
+
+/// Merges the file's items into the receipt and records that it did, so processing the file again is a no-op.
+/// `false` if the file was merged already.
+async fn merge_file(
+    conn: &mut PgConnection,
+    file_hash: &FileHash,
+    items: Vec<LineItem>,
+    receipt_id: i32,
+    category_rules: &[config::CategoryRule],
+) -> Result<bool, sqlx::Error> {
+    let recorded = sqlx::query!(
+        "INSERT INTO merged_files(file_sha256, receipt_id) VALUES ($1, $2) ON CONFLICT (file_sha256) DO NOTHING",
+        file_hash.as_str(),
+        receipt_id
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    if recorded == 0 {
+        return Ok(false);
+    }
+    merge_items(conn, items, receipt_id, category_rules).await?;
+    Ok(true)
+}
+
+/// A duplicate shows the same purchase, so its items never add to the receipt's lines.
+/// They replace them only when the duplicate read more lines; otherwise the receipt keeps its own.
+async fn merge_items(
+    conn: &mut PgConnection,
+    items: Vec<LineItem>,
     receipt_id: i32,
+    category_rules: &[config::CategoryRule],
 ) -> Result<(), sqlx::Error> {
-    // TODO: De-duplication means we are losing data points such as multiple discounts with the same name on one receipt; allow multiple entries of a given product on the same receipt
-    let mut data = product_names
-        .into_iter()
-        .zip(counts.into_iter().zip(unit_prices.into_iter()))
-        .unique_by(|(name, _)| name.clone())
-        .collect::<Vec<_>>();
-    data.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
-    let (product_names, (counts, unit_prices)): (Vec<String>, (Vec<f64>, Vec<f64>)) =
-        data.into_iter().unzip();
+    let existing = sqlx::query!(
+        r#"SELECT receipts.total, (SELECT COUNT(*) FROM prices WHERE prices.receipt_id = receipts.id) AS "lines!" FROM receipts WHERE id = $1"#,
+        receipt_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if items.len() as i64 <= existing.lines {
+        return Ok(());
+    }
+    let items_match_total = reconcile::reconcile(existing.total, &items, &[]).balanced;
+    sqlx::query!("DELETE FROM prices WHERE receipt_id = $1", receipt_id)
+        .execute(&mut *conn)
+        .await?;
+    insert_products_if_not_exist(conn, &items, category_rules).await?;
+    upsert_prices_for_products_and_receipt(conn, items, receipt_id).await?;
     sqlx::query!(
-        r#"INSERT INTO prices(count, unit_price, receipt_id, product_id) SELECT tmp.count, tmp.unit_price, tmp.receipt_id, products.id FROM (SELECT UNNEST($1::float[]) AS count, UNNEST($2::float[]) AS unit_price, $3::integer AS receipt_id, UNNEST($4::text[]) AS name) tmp INNER JOIN products ON tmp.name = products.name ON CONFLICT ON CONSTRAINT prices_pkey DO UPDATE SET count=excluded.count, unit_price=excluded.unit_price"#,
-        &counts,
-        &unit_prices,
+        "UPDATE receipts SET items_match_total = $2 WHERE id = $1",
         receipt_id,
-        &product_names
+        items_match_total
     )
-    .execute(pool)
+    .execute(&mut *conn)
     .await?;
     Ok(())
 }
 
-async fn insert_receipt_if_not_exists(
-    pool: &PgPool,
-    merchant_name: &str,
-    paid_at: chrono::DateTime<chrono_tz::Tz>,
-    file_hash: &str,
-) -> Result<i32, sqlx::Error> {
-    let res = sqlx::query!(
-        r#"INSERT INTO receipts(merchant_name, paid_at, file_sha256) VALUES ($1, $2, $3) RETURNING *"#,
-        merchant_name,
-        paid_at,
-        file_hash
+/// Moves the receipt over to the corrected file, so it keeps its id, tags and note rather than being stored twice.
+/// The old file's cached analysis is dropped, or repopulating the DB would bring the uncorrected receipt back.
+async fn replace_with_corrected_file(
+    app_state: &AppState,
+    receipt: ReceiptRecord,
+    file_hash: &FileHash,
+    receipt_id: i32,
+) -> Result<(), AppError> {
+    // The receipt only points at the new file once it holds its data, or neither file could be processed again cleanly
+    let mut tx = app_state.pool.begin().await?;
+    let old_hash = sqlx::query_scalar!(
+        r#"SELECT file_sha256 AS "file_sha256: FileHash" FROM receipts WHERE id = $1 FOR UPDATE"#,
+        receipt_id
     )
-    .fetch_one(pool)
-    .await?
-    .id;
-    Ok(res)
-}
-
-async fn insert_products_if_not_exist(
-    pool: &PgPool,
-    products: &[String],
-) -> Result<(), sqlx::Error> {
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(old_hash) = old_hash else {
+        drop(tx);
+        tracing::warn!(
+            "Receipt {} corrected by file {} was deleted meanwhile, storing it as a new receipt",
+            receipt_id,
+            file_hash
+        );
+        return save_receipt_record(app_state, receipt, file_hash).await;
+    };
     sqlx::query!(
-        r#"INSERT INTO products(name) SELECT UNNEST($1::text[]) ON CONFLICT DO NOTHING"#,
-        products
+        "UPDATE receipts SET file_sha256 = $2 WHERE id = $1",
+        receipt_id,
+        file_hash.as_str()
     )
-    .execute(pool)
+    .execute(&mut *tx)
     .await?;
+    write_replacement(&mut tx, &app_state.config, receipt, receipt_id).await?;
+    tx.commit().await?;
+    if let Some(old_hash) = old_hash {
+        app_state.persist.remove(old_hash.as_str())?;
+    }
+    tracing::info!(
+        "Receipt {} now holds the data of file {}, which corrects its earlier file",
+        receipt_id,
+        file_hash
+    );
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::manual;
+/// Swaps in the data of a re-analyzed file, keeping the receipt's id and what users attached to it, like tags and notes.
+async fn replace_receipt_record(
+    app_state: &AppState,
+    receipt: ReceiptRecord,
+    receipt_id: i32,
+) -> Result<(), AppError> {
+    let mut tx = app_state.pool.begin().await?;
+    write_replacement(&mut tx, &app_state.config, receipt, receipt_id).await?;
+    tx.commit().await?;
+    Ok(())
+}
 
-    #[test]
-    fn parse_receipt_analysis_results() {
-        serde_json::from_str::<manual::AnalyzeResultOperation>(include_str!("../response1.json"))
-            .unwrap();
-    }
+/// The writes of [`replace_receipt_record`], left to the caller's transaction.
+async fn write_replacement(
+    conn: &mut PgConnection,
+    config: &Config,
+    receipt: ReceiptRecord,
+    receipt_id: i32,
+) -> Result<(), AppError> {
+    sqlx::query!("DELETE FROM prices WHERE receipt_id = $1", receipt_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM tax_lines WHERE receipt_id = $1", receipt_id)
+        .execute(&mut *conn)
+        .await?;
+    let (local_paid_at, time_zone) = local_time::local_columns(receipt.paid_at);
+    sqlx::query!(
+        "UPDATE receipts SET merchant_name = $2, paid_at = $3, total = $4, currency_code = $5, needs_review = $6, model_id = $7, time_precision = $8, receipt_text = $9, selection_marks = $10, currency_inferred = $11, local_paid_at = $12, time_zone = $13, total_tax = $14, items_match_total = $15, category = $16 WHERE id = $1",
+        receipt_id,
+        receipt.merchant_name,
+        receipt.paid_at,
+        receipt.total,
+        receipt.currency_code,
+        receipt.needs_review,
+        receipt.model_id,
+        receipt.time_precision.as_str(),
+        receipt.text,
+        selection_marks_json(&receipt),
+        receipt.currency_inferred,
+        local_paid_at,
+        time_zone,
+        receipt.total_tax,
+        receipt.items_match_total(),
+        categories::merchant_category(&receipt.merchant_name, &config.merchant_category_rules)
+    )
+    .execute(&mut *conn)
+    .await?;
+    insert_tax_lines(conn, &receipt.taxes, receipt_id).await?;
+    insert_products_if_not_exist(conn, &receipt.items, &config.category_rules).await?;
+    upsert_prices_for_products_and_receipt(conn, receipt.items, receipt_id).await?;
+    Ok(())
+}
 
-    #[test]
-    fn parse_other_receipt_analysis_results() {
-        serde_json::from_str::<manual::AnalyzeResultOperation>(include_str!("../response2.json"))
-            .unwrap();
-    }
+/// Looks for an already stored receipt (from a different file) that most likely records the same purchase.
+async fn find_probable_duplicate(
+    pool: &PgPool,
+    receipt: &ReceiptRecord,
+    file_hash: &FileHash,
+    tolerance: f64,
+) -> Result<Option<i32>, sqlx::Error> {
+    let candidates = sqlx::query!(
+        r#"SELECT id, total FROM receipts WHERE lower(merchant_name) = lower($1) AND paid_at = $2 AND duplicate_of IS NULL AND file_sha256 IS DISTINCT FROM $3 ORDER BY id"#,
+        receipt.merchant_name,
+        receipt.paid_at,
+        file_hash.as_str()
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(candidates
+        .into_iter()
+        .find(|candidate| extract::is_probable_duplicate(receipt, candidate.total, tolerance))
+        .map(|candidate| candidate.id))
+}
 
-    #[test]
+async fn upsert_prices_for_products_and_receipt(
+    conn: &mut PgConnection,
+    items: Vec<LineItem>,
+    receipt_id: i32,
+) -> Result<(), sqlx::Error> {
+    let data = items;
+    // Each line keeps its position on the receipt, so repeated names like several discounts are stored separately
+    let line_indexes = (0..data.len() as i32).collect::<Vec<_>>();
+    let product_names = data
+        .iter()
+        .map(|item| item.name.clone())
+        .collect::<Vec<_>>();
+    let counts = data.iter().map(|item| item.count).collect::<Vec<_>>();
+    let unit_prices = data.iter().map(|item| item.unit_price).collect::<Vec<_>>();
+    let quantities_defaulted = data
+        .iter()
+        .map(|item| item.quantity_defaulted)
+        .collect::<Vec<_>>();
+    let prices_missing = data
+        .iter()
+        .map(|item| item.price_missing)
+        .collect::<Vec<_>>();
+    let synthesized = data.iter().map(|item| item.synthesized).collect::<Vec<_>>();
+    let unattributed = data
+        .iter()
+        .map(|item| item.unattributed)
+        .collect::<Vec<_>>();
+    let quantity_units = data
+        .iter()
+        .map(|item| item.quantity_unit.clone())
+        .collect::<Vec<_>>();
+    let product_codes = data
+        .iter()
+        .map(|item| item.product_code.clone())
+        .collect::<Vec<_>>();
+    // A line's product is the one with its code if there is one, so OCR variants of the name still land on the same product
+    sqlx::query!(
+        r#"INSERT INTO prices(count, unit_price, receipt_id, product_id, quantity_defaulted, price_missing, synthesized, quantity_unit, unattributed, line_index) SELECT tmp.count, tmp.unit_price, tmp.receipt_id, products.id, tmp.quantity_defaulted, tmp.price_missing, tmp.synthesized, tmp.quantity_unit, tmp.unattributed, tmp.line_index FROM (SELECT UNNEST($1::float[]) AS count, UNNEST($2::float[]) AS unit_price, $3::integer AS receipt_id, UNNEST($4::text[]) AS name, UNNEST($5::bool[]) AS quantity_defaulted, UNNEST($6::bool[]) AS price_missing, UNNEST($7::bool[]) AS synthesized, UNNEST($8::text[]) AS quantity_unit, UNNEST($9::bool[]) AS unattributed, UNNEST($10::int[]) AS line_index, UNNEST($11::text[]) AS product_code) tmp INNER JOIN products ON products.id = COALESCE((SELECT coded.id FROM products coded WHERE coded.product_code = tmp.product_code), (SELECT named.id FROM products named WHERE named.name = tmp.name)) ON CONFLICT ON CONSTRAINT prices_pkey DO UPDATE SET product_id=excluded.product_id, count=excluded.count, unit_price=excluded.unit_price, quantity_defaulted=excluded.quantity_defaulted, price_missing=excluded.price_missing, synthesized=excluded.synthesized, quantity_unit=excluded.quantity_unit, unattributed=excluded.unattributed"#,
+        &counts,
+        &unit_prices as &[Option<f64>],
+        receipt_id,
+        &product_names,
+        &quantities_defaulted,
+        &prices_missing,
+        &synthesized,
+        &quantity_units as &[Option<String>],
+        &unattributed,
+        &line_indexes,
+        &product_codes as &[Option<String>]
+    )
+    .execute(&mut *conn)
+    .await?;
+    insert_item_regions(conn, &data, receipt_id).await
+}
+
+/// Replaces the receipt's item regions, so a frontend can draw each line's box over the original image.
+async fn insert_item_regions(
+    conn: &mut PgConnection,
+    items: &[LineItem],
+    receipt_id: i32,
+) -> Result<(), sqlx::Error> {
+    let regions = items
+        .iter()
+        .enumerate()
+        .flat_map(|(line_index, item)| item.regions.iter().map(move |region| (line_index, region)));
+    let mut line_indexes = vec![];
+    let mut page_numbers = vec![];
+    let mut polygons = vec![];
+    for (line_index, region) in regions {
+        line_indexes.push(line_index as i32);
+        page_numbers.push(region.page_number as i32);
+        polygons.push(json!(region.polygon));
+    }
+    sqlx::query!("DELETE FROM item_regions WHERE receipt_id = $1", receipt_id)
+        .execute(&mut *conn)
+        .await?;
+    // Lines whose product couldn't be stored have no price row to attach regions to
+    sqlx::query!(
+        r#"INSERT INTO item_regions(receipt_id, line_index, page_number, polygon) SELECT prices.receipt_id, prices.line_index, new.page_number, new.polygon FROM UNNEST($2::int[], $3::int[], $4::jsonb[]) AS new(line_index, page_number, polygon) JOIN prices ON prices.receipt_id = $1 AND prices.line_index = new.line_index"#,
+        receipt_id,
+        &line_indexes,
+        &page_numbers,
+        &polygons
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Most receipts have no checkboxes, and leave the column empty rather than holding `{}`.
+fn selection_marks_json(receipt: &ReceiptRecord) -> Option<serde_json::Value> {
+    (!receipt.selection_marks.is_empty()).then(|| json!(receipt.selection_marks))
+}
+
+/// `None` when a receipt from the same file is already stored.
+async fn insert_receipt_if_not_exists(
+    conn: &mut PgConnection,
+    receipt: &ReceiptRecord,
+    file_hash: &FileHash,
+    duplicate_of: Option<i32>,
+    category: &str,
+) -> Result<Option<i32>, sqlx::Error> {
+    let (local_paid_at, time_zone) = local_time::local_columns(receipt.paid_at);
+    let res = sqlx::query!(
+        r#"INSERT INTO receipts(merchant_name, paid_at, file_sha256, total, duplicate_of, currency_code, needs_review, model_id, time_precision, receipt_text, selection_marks, currency_inferred, local_paid_at, time_zone, total_tax, items_match_total, category) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17) ON CONFLICT (file_sha256) DO NOTHING RETURNING id"#,
+        receipt.merchant_name,
+        receipt.paid_at,
+        file_hash.as_str(),
+        receipt.total,
+        duplicate_of,
+        receipt.currency_code,
+        receipt.needs_review,
+        receipt.model_id,
+        receipt.time_precision.as_str(),
+        receipt.text,
+        selection_marks_json(receipt),
+        receipt.currency_inferred,
+        local_paid_at,
+        time_zone,
+        receipt.total_tax,
+        receipt.items_match_total(),
+        category
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(res.map(|row| row.id))
+}
+
+async fn insert_tax_lines(
+    conn: &mut PgConnection,
+    taxes: &[extract::TaxLine],
+    receipt_id: i32,
+) -> Result<(), sqlx::Error> {
+    let rates = taxes.iter().map(|tax| tax.rate).collect::<Vec<_>>();
+    let net_amounts = taxes.iter().map(|tax| tax.net_amount).collect::<Vec<_>>();
+    let amounts = taxes.iter().map(|tax| tax.amount).collect::<Vec<_>>();
+    let currency_codes = taxes
+        .iter()
+        .map(|tax| tax.currency_code.clone())
+        .collect::<Vec<_>>();
+    sqlx::query!(
+        r#"INSERT INTO tax_lines(receipt_id, rate, net_amount, amount, currency_code) SELECT $1, UNNEST($2::float[]), UNNEST($3::float[]), UNNEST($4::float[]), UNNEST($5::text[])"#,
+        receipt_id,
+        &rates as &[Option<f64>],
+        &net_amounts as &[Option<f64>],
+        &amounts,
+        &currency_codes as &[Option<String>]
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Items that may need a product of their own: only the first name printed with a code, as other names with the same code are OCR variants that will match its product.
+fn new_products(items: &[LineItem]) -> Vec<&LineItem> {
+    let mut seen = HashSet::new();
+    items
+        .iter()
+        .filter(|item| {
+            item.product_code
+                .as_ref()
+                .is_none_or(|code| seen.insert(code))
+        })
+        .collect()
+}
+
+/// Known products keep their category, unless they have none yet.
+/// Items whose code is already known need no product of their own; existing products without a code get the item's.
+async fn insert_products_if_not_exist(
+    conn: &mut PgConnection,
+    items: &[LineItem],
+    category_rules: &[config::CategoryRule],
+) -> Result<(), sqlx::Error> {
+    let items = new_products(items);
+    let products = items
+        .iter()
+        .map(|item| item.name.clone())
+        .collect::<Vec<_>>();
+    let categories = items
+        .iter()
+        .map(|item| categories::categorize(&item.name, category_rules).map(String::from))
+        .collect::<Vec<_>>();
+    let product_codes = items
+        .iter()
+        .map(|item| item.product_code.clone())
+        .collect::<Vec<_>>();
+    sqlx::query!(
+        r#"INSERT INTO products(name, category, product_code) SELECT DISTINCT ON (name) new.name, new.category, new.product_code FROM UNNEST($1::text[], $2::text[], $3::text[]) AS new(name, category, product_code) WHERE NOT EXISTS (SELECT 1 FROM products coded WHERE coded.product_code = new.product_code) ORDER BY new.name, new.product_code NULLS LAST ON CONFLICT (name) DO UPDATE SET category = COALESCE(products.category, excluded.category), product_code = COALESCE(products.product_code, excluded.product_code) WHERE products.category IS NULL OR products.product_code IS NULL"#,
+        &products,
+        &categories as &[Option<String>],
+        &product_codes as &[Option<String>]
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{routing::get, Router};
+    use chrono::TimeZone;
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use crate::{
+        analyze_request, cache, compare,
+        config::{
+            AzureApiVersion, Config, DateOnlyTime, MerchantDateQuirk, MerchantFallback,
+            ZeroPricePolicy,
+        },
+        consistency,
+        extract::{self, TimePrecision},
+        google, insert_products_if_not_exist, insert_receipt_if_not_exists, manual, merge_file,
+        merge_items, new_products, read_pool, recomputed_paid_at, reconcile, tags, taxes,
+        upload_check, upsert_prices_for_products_and_receipt, with_request_timeout,
+        write_new_receipt, AnalyzeRequestBody, AppError, ReceiptsFilter, Repopulated,
+        RepopulationReport, UploadParams, ValidationError,
+    };
+
+    fn extract_fixture(json: &str) -> extract::ReceiptRecord {
+        extract::extract_receipt(serde_json::from_str(json).unwrap(), &Config::default()).unwrap()
+    }
+
+    /// Postgres for tests of the SQL itself, from `DATABASE_URL`; those tests are ignored unless run with `--include-ignored`.
+    /// Everything is written in a transaction that is never committed, so the database is left as it was.
+    async fn test_db() -> sqlx::Transaction<'static, sqlx::Postgres> {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a Postgres database");
+        let pool = sqlx::PgPool::connect(&url).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        pool.begin().await.unwrap()
+    }
+
+    /// A hash no stored receipt has, as the test database may hold real data.
+    fn unused_hash(test: &str) -> crate::file_hash::FileHash {
+        let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap();
+        crate::file_hash::FileHash::of(format!("{test}{nanos}").as_bytes())
+    }
+
+    async fn count(tx: &mut sqlx::PgConnection, sql: &str, receipt_id: i32) -> i64 {
+        sqlx::query_scalar(sql)
+            .bind(receipt_id)
+            .fetch_one(tx)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_receipt_analysis_results() {
+        serde_json::from_str::<manual::AnalyzeResultOperation>(include_str!("../response1.json"))
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_other_receipt_analysis_results() {
+        serde_json::from_str::<manual::AnalyzeResultOperation>(include_str!("../response2.json"))
+            .unwrap();
+    }
+
+    #[test]
     fn parse_another_receipt_analysis_results() {
         serde_json::from_str::<manual::AnalyzeResultOperation>(include_str!("../response3.json"))
             .unwrap();
     }
+
+    #[test]
+    fn detect_rescanned_receipt_as_probable_duplicate() {
+        let original = include_str!("../response3.json");
+        let rescan = include_str!("../response3_rescan.json");
+        assert_ne!(sha256::digest(original), sha256::digest(rescan));
+
+        let original = extract_fixture(original);
+        let rescan = extract_fixture(rescan);
+        assert_eq!(original.merchant_name, rescan.merchant_name);
+        assert_eq!(original.paid_at, rescan.paid_at);
+        assert!(extract::is_probable_duplicate(
+            &rescan,
+            Some(original.total),
+            0.01
+        ));
+        assert!(!extract::is_probable_duplicate(
+            &rescan,
+            Some(original.total + 1.0),
+            0.01
+        ));
+        assert!(!extract::is_probable_duplicate(&rescan, None, 0.01));
+    }
+
+    #[test]
+    fn extract_document_level_currency() {
+        let receipt = extract_fixture(include_str!("../response2_currency.json"));
+        assert_eq!(receipt.currency_code.as_deref(), Some("DKK"));
+
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        assert_eq!(receipt.currency_code, None);
+    }
+
+    #[test]
+    fn fall_back_to_most_common_line_currency() {
+        assert_eq!(
+            extract::most_common_currency(["dkk", "EUR", "DKK"]).as_deref(),
+            Some("DKK")
+        );
+        assert_eq!(extract::most_common_currency([]), None);
+    }
+
+    #[test]
+    fn trace_dropped_items_and_date_source() {
+        let mut trace = extract::ExtractionTrace::default();
+        let receipt = extract::extract_receipt_traced(
+            serde_json::from_str(include_str!("../response3.json")).unwrap(),
+            &Config::default(),
+            &mut trace,
+        )
+        .unwrap();
+        assert_eq!(receipt.items.len(), 1);
+        assert_eq!(
+            trace.dropped_items,
+            vec![extract::DroppedItem {
+                content: "BETALINGSKORT".into(),
+                reason: extract::DropReason::MissingPrice,
+            }]
+        );
+        assert_eq!(trace.date_source, Some(extract::DateSource::Content));
+
+        let mut trace = extract::ExtractionTrace::default();
+        extract::extract_receipt_traced(
+            serde_json::from_str(include_str!("../response1.json")).unwrap(),
+            &Config::default(),
+            &mut trace,
+        )
+        .unwrap();
+        assert_eq!(trace.date_source, Some(extract::DateSource::ValueDate));
+        assert!(trace.dropped_items.is_empty());
+    }
+
+    #[test]
+    fn convert_google_document_ai_receipt() {
+        let google = google::into_receipt_record(
+            serde_json::from_str(include_str!("../google_response3.json")).unwrap(),
+            &Config::default(),
+        )
+        .unwrap();
+        let mut azure = extract_fixture(include_str!("../response3.json"));
+        // Only Azure reports where on the page each line was read
+        for item in &mut azure.items {
+            assert!(!std::mem::take(&mut item.regions).is_empty());
+        }
+        assert_eq!(google.merchant_name, azure.merchant_name);
+        assert_eq!(google.paid_at, azure.paid_at);
+        assert_eq!(google.total, azure.total);
+        assert_eq!(google.total_tax, Some(3.6));
+        assert_eq!(google.total_tax, azure.total_tax);
+        assert_eq!(google.items, azure.items);
+        assert_eq!(google.currency_code.as_deref(), Some("DKK"));
+    }
+
+    #[test]
+    fn default_missing_quantities_and_flag_them() {
+        let receipt = extract_fixture(include_str!("../response2.json"));
+        assert_eq!(
+            receipt
+                .items
+                .iter()
+                .map(|item| (item.count, item.quantity_defaulted))
+                .collect::<Vec<_>>(),
+            vec![
+                (2.0, false),
+                (3.0, false),
+                (1.0, true),
+                (1.0, true),
+                (1.0, true)
+            ]
+        );
+
+        let config = Config {
+            default_quantity: 0.5,
+            ..Config::default()
+        };
+        let receipt = extract::extract_receipt(
+            serde_json::from_str(include_str!("../response1.json")).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert!(receipt
+            .items
+            .iter()
+            .all(|item| item.count == 0.5 && item.quantity_defaulted));
+    }
+
+    #[test]
+    fn label_nameless_merchant_by_address_or_phone() {
+        let fixture = include_str!("../response2_no_merchant_name.json");
+        let extract_with = |merchant_fallbacks| {
+            let config = Config {
+                merchant_fallbacks,
+                ..Config::default()
+            };
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap()
+        };
+
+        let receipt = extract_fixture(fixture);
+        assert_eq!(receipt.merchant_name, "Kongelundsvej 65, 2300 København");
+        assert!(!receipt.needs_review);
+
+        let receipt = extract_with(vec![MerchantFallback::Phone, MerchantFallback::Address]);
+        assert_eq!(receipt.merchant_name, "+4570101520");
+        assert!(!receipt.needs_review);
+
+        let receipt = extract_with(vec![]);
+        assert_eq!(receipt.merchant_name, "");
+        assert!(receipt.needs_review);
+    }
+
+    #[test]
+    fn send_analysis_to_chosen_model() {
+        let client = reqwest::Client::new();
+        let req = analyze_request(
+            "aGVsbG8=",
+            "danish-groceries-v2",
+            AzureApiVersion::V2023_07_31,
+            "key",
+            &client,
+        )
+        .unwrap();
+        assert_eq!(
+            req.url().path(),
+            "/formrecognizer/documentModels/danish-groceries-v2:analyze"
+        );
+        assert_eq!(req.url().query(), Some("api-version=2023-07-31"));
+
+        let req = analyze_request(
+            "aGVsbG8=",
+            "prebuilt-receipt",
+            AzureApiVersion::V2024_11_30,
+            "key",
+            &client,
+        )
+        .unwrap();
+        assert_eq!(
+            req.url().path(),
+            "/documentintelligence/documentModels/prebuilt-receipt:analyze"
+        );
+        assert_eq!(req.url().query(), Some("api-version=2024-11-30"));
+
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        assert_eq!(receipt.model_id.as_deref(), Some("prebuilt-receipt"));
+    }
+
+    #[test]
+    fn fall_back_to_date_only_when_time_is_missing() {
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        assert_eq!(receipt.time_precision, TimePrecision::Time);
+        assert_eq!(receipt.paid_at.to_rfc3339(), "2023-09-10T20:00:00+02:00");
+
+        let fixture = include_str!("../response1_no_time.json");
+        let receipt = extract_fixture(fixture);
+        assert_eq!(receipt.time_precision, TimePrecision::Date);
+        assert_eq!(receipt.paid_at.to_rfc3339(), "2023-09-10T12:00:00+02:00");
+
+        let config = Config {
+            date_only_time: DateOnlyTime::Midnight,
+            ..Config::default()
+        };
+        let receipt =
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap();
+        assert_eq!(receipt.paid_at.to_rfc3339(), "2023-09-10T00:00:00+02:00");
+    }
+
+    #[test]
+    fn extract_tax_lines_per_rate() {
+        let receipt = extract_fixture(include_str!("../response1_multi_tax.json"));
+        assert_eq!(
+            taxes::breakdown(&receipt.taxes),
+            vec![
+                taxes::RateBreakdown {
+                    rate: Some(0.0),
+                    net_amount: Some(12.52),
+                    amount: 0.0,
+                },
+                taxes::RateBreakdown {
+                    rate: Some(25.0),
+                    net_amount: Some(153.6),
+                    amount: 38.4,
+                },
+            ]
+        );
+
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        assert_eq!(receipt.taxes.len(), 1);
+        assert_eq!(receipt.taxes[0].rate, None);
+    }
+
+    #[test]
+    fn keep_currency_of_tax_lines() {
+        let receipt = extract_fixture(include_str!("../response1_tax_currency.json"));
+        let currencies = receipt
+            .taxes
+            .iter()
+            .map(|tax| (tax.amount, tax.currency_code.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            currencies,
+            [(38.4, Some("EUR")), (0.0, receipt.currency_code.as_deref())]
+        );
+        assert!(receipt.currency_code.is_some());
+    }
+
+    #[test]
+    fn recompute_dates_stored_before_the_netto_fix() {
+        let fixture = include_str!("../response3.json");
+        let flipped = chrono::Utc
+            .with_ymd_and_hms(2023, 6, 10, 14, 34, 0)
+            .unwrap();
+        let (paid_at, _) = recomputed_paid_at(
+            flipped,
+            serde_json::from_str(fixture).unwrap(),
+            &Config::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(paid_at.to_rfc3339(), "2023-10-06T16:34:00+02:00");
+
+        let unchanged = recomputed_paid_at(
+            paid_at.with_timezone(&chrono::Utc),
+            serde_json::from_str(fixture).unwrap(),
+            &Config::default(),
+        )
+        .unwrap();
+        assert_eq!(unchanged, None);
+    }
+
+    #[test]
+    fn keep_full_text_for_search() {
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        assert!(receipt.text.starts_with("Bilka\n"));
+        assert!(receipt.text.contains("UBEGRÆNSET RETURRET"));
+
+        let receipt = google::into_receipt_record(
+            serde_json::from_str(include_str!("../google_response3.json")).unwrap(),
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(!receipt.text.is_empty());
+    }
+
+    #[test]
+    fn apply_zero_price_policy() {
+        let fixture = include_str!("../response1_zero_price.json");
+        let extract = |zero_price_policy| {
+            let config = Config {
+                zero_price_policy,
+                ..Config::default()
+            };
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap()
+        };
+        let has_gift = |receipt: &extract::ReceiptRecord| {
+            receipt.items.iter().any(|item| item.name == "GAVEPOSE")
+        };
+
+        let receipt = extract(ZeroPricePolicy::Keep);
+        assert!(has_gift(&receipt));
+        assert!(!receipt.needs_review);
+
+        let receipt = extract(ZeroPricePolicy::Drop);
+        assert!(!has_gift(&receipt));
+        assert_eq!(receipt.items.len(), 9);
+        assert!(!receipt.needs_review);
+
+        let receipt = extract(ZeroPricePolicy::Flag);
+        assert!(has_gift(&receipt));
+        assert!(receipt.needs_review);
+    }
+
+    #[test]
+    fn compare_two_trips_to_the_same_store() {
+        let a = extract_fixture(include_str!("../response1.json"));
+        let b = extract_fixture(include_str!("../response1_next_trip.json"));
+        let diff = compare::diff_items(a.items, b.items);
+
+        let names = |items: &[compare::ItemPrice]| {
+            items
+                .iter()
+                .map(|item| item.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&diff.dropped), ["COCA COLA ZERO 1,5L"]);
+        assert_eq!(names(&diff.added), ["ØKO BANANER"]);
+        assert_eq!(diff.common.len(), 6);
+        let changed = diff
+            .common
+            .iter()
+            .filter(|item| item.price_change != Some(0.0))
+            .collect::<Vec<_>>();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "DGF MARMELADE");
+        assert_eq!(changed[0].price_change, Some(2.0));
+    }
+
+    #[test]
+    fn optionally_keep_items_without_price() {
+        let fixture = include_str!("../response3.json");
+        let receipt = extract_fixture(fixture);
+        assert!(!receipt
+            .items
+            .iter()
+            .any(|item| item.name == "BETALINGSKORT"));
+
+        let config = Config {
+            keep_priceless_items: true,
+            ..Config::default()
+        };
+        let receipt =
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap();
+        let card = receipt
+            .items
+            .iter()
+            .find(|item| item.name == "BETALINGSKORT")
+            .unwrap();
+        assert_eq!(card.unit_price, None);
+        assert!(card.price_missing);
+        assert!(receipt
+            .items
+            .iter()
+            .filter(|item| item.name != "BETALINGSKORT")
+            .all(|item| item.unit_price.is_some() && !item.price_missing));
+    }
+
+    #[tokio::test]
+    async fn time_out_slow_requests() {
+        let router = with_request_timeout(
+            Router::new()
+                .route(
+                    "/slow",
+                    get(|| async {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        "done"
+                    }),
+                )
+                .route("/fast", get(|| async { "done" })),
+            Duration::from_millis(50),
+        );
+        let request = |uri| {
+            axum::http::Request::builder()
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        let response = router.clone().oneshot(request("/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let response = router.oneshot(request("/fast")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn read_date_from_content_when_value_date_is_missing() {
+        for (fixture, format, paid_at) in [
+            (
+                include_str!("../response1_no_value_date.json"),
+                "%d %m %Y",
+                chrono_tz::Europe::Copenhagen
+                    .with_ymd_and_hms(2023, 9, 10, 20, 0, 0)
+                    .unwrap(),
+            ),
+            (
+                include_str!("../response2_no_value_date.json"),
+                "%Y/%m/%d",
+                chrono_tz::Europe::Copenhagen
+                    .with_ymd_and_hms(2023, 9, 5, 19, 36, 0)
+                    .unwrap(),
+            ),
+        ] {
+            let mut trace = extract::ExtractionTrace::default();
+            let receipt = extract::extract_receipt_traced(
+                serde_json::from_str(fixture).unwrap(),
+                &Config::default(),
+                &mut trace,
+            )
+            .unwrap();
+            assert_eq!(receipt.paid_at, paid_at);
+            assert_eq!(
+                trace.date_source,
+                Some(extract::DateSource::ContentFallback)
+            );
+            assert_eq!(trace.date_format.as_deref(), Some(format));
+        }
+    }
+
+    #[test]
+    fn parse_printed_date_formats() {
+        let date = chrono::NaiveDate::from_ymd_opt(2023, 10, 5).unwrap();
+        for (content, format) in [
+            ("2023-10-05", "%Y-%m-%d"),
+            ("05.10.23", "%d.%m.%y"),
+            ("05-10-23", "%d-%m-%y"),
+            ("5/10/2023", "%d/%m/%Y"),
+            (" 05 10 2023 ", "%d %m %Y"),
+        ] {
+            assert_eq!(
+                extract::parse_content_date(content),
+                Some((date, format)),
+                "{content}"
+            );
+        }
+        assert_eq!(extract::parse_content_date("KVITTERING"), None);
+        assert_eq!(extract::parse_content_date(""), None);
+    }
+
+    #[tokio::test]
+    async fn reject_uploads_failing_the_check() {
+        struct RejectPdf;
+
+        impl upload_check::UploadCheck for RejectPdf {
+            fn check(&self, offset: usize, chunk: &[u8]) -> Result<(), String> {
+                if offset == 0 && chunk.starts_with(b"%PDF") {
+                    return Err("PDFs are not accepted".into());
+                }
+                Ok(())
+            }
+        }
+
+        let check: Arc<dyn upload_check::UploadCheck> = Arc::new(RejectPdf);
+        let err = upload_check::screen_chunk(check.clone(), 0, "%PDF-1.7".into())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, AppError::Validation(ValidationError::BadRequest(message)) if message == "Upload rejected: PDFs are not accepted")
+        );
+        upload_check::screen_chunk(check.clone(), 0, "\u{ff}\u{d8}".into())
+            .await
+            .unwrap();
+        upload_check::screen_chunk(check, 8, "%PDF-1.7".into())
+            .await
+            .unwrap();
+
+        let check = upload_check::MaxFileSize(4);
+        assert!(upload_check::UploadCheck::check(&check, 0, b"12345").is_err());
+        assert!(upload_check::UploadCheck::check(&check, 0, b"1234").is_ok());
+        assert!(upload_check::UploadCheck::check(&check, 2, b"345").is_err());
+    }
+
+    #[test]
+    fn decode_base64_upload_body() {
+        let body: AnalyzeRequestBody =
+            serde_json::from_str(r#"{"base64Source": "aGVsbG8="}"#).unwrap();
+        assert_eq!(body.decode().unwrap(), "hello");
+
+        for invalid in [
+            r#"{"base64Source": "not base64!"}"#,
+            r#"{"base64Source": ""}"#,
+        ] {
+            let body: AnalyzeRequestBody = serde_json::from_str(invalid).unwrap();
+            assert!(matches!(
+                body.decode(),
+                Err(AppError::Validation(ValidationError::BadRequest(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn keep_printed_date_around_midnight() {
+        for (fixture, utc) in [
+            (
+                include_str!("../response2_2359.json"),
+                chrono::Utc.with_ymd_and_hms(2023, 9, 5, 21, 59, 0).unwrap(),
+            ),
+            (
+                include_str!("../response2_0001.json"),
+                chrono::Utc.with_ymd_and_hms(2023, 9, 4, 22, 1, 0).unwrap(),
+            ),
+        ] {
+            let receipt = extract_fixture(fixture);
+            assert_eq!(receipt.paid_at, utc);
+            assert_eq!(
+                receipt.paid_at.date_naive(),
+                chrono::NaiveDate::from_ymd_opt(2023, 9, 5).unwrap()
+            );
+            assert!(!receipt.needs_review);
+        }
+    }
+
+    #[test]
+    fn localize_across_dst_changes() {
+        let local = |date: (i32, u32, u32), time: (u32, u32)| {
+            chrono::NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+                .unwrap()
+                .and_hms_opt(time.0, time.1, 0)
+                .unwrap()
+        };
+        let utc = |date: (i32, u32, u32), time: (u32, u32)| {
+            chrono::Utc
+                .with_ymd_and_hms(date.0, date.1, date.2, time.0, time.1, 0)
+                .unwrap()
+        };
+        for (printed, expected) in [
+            // Last minute of summer time, and the first of winter time
+            (
+                local((2023, 10, 28), (23, 59)),
+                utc((2023, 10, 28), (21, 59)),
+            ),
+            (local((2023, 10, 29), (0, 1)), utc((2023, 10, 28), (22, 1))),
+            // 02:30 happens twice when DST ends, and not at all when it starts
+            (local((2023, 10, 29), (2, 30)), utc((2023, 10, 29), (0, 30))),
+            (local((2023, 3, 26), (2, 30)), utc((2023, 3, 26), (1, 30))),
+            (local((2023, 3, 25), (23, 59)), utc((2023, 3, 25), (22, 59))),
+            // Both edges of the repeated and the skipped hour in 2024
+            (local((2024, 10, 27), (2, 0)), utc((2024, 10, 27), (0, 0))),
+            (local((2024, 10, 27), (2, 59)), utc((2024, 10, 27), (0, 59))),
+            (local((2024, 10, 27), (3, 0)), utc((2024, 10, 27), (2, 0))),
+            (local((2024, 3, 31), (1, 59)), utc((2024, 3, 31), (0, 59))),
+            (local((2024, 3, 31), (2, 0)), utc((2024, 3, 31), (1, 0))),
+            (local((2024, 3, 31), (2, 59)), utc((2024, 3, 31), (1, 59))),
+            (local((2024, 3, 31), (3, 0)), utc((2024, 3, 31), (1, 0))),
+        ] {
+            let paid_at = extract::localize(printed, &Config::default()).unwrap();
+            assert_eq!(paid_at, expected, "{printed}");
+            assert_eq!(paid_at.date_naive(), printed.date(), "{printed}");
+        }
+    }
+
+    #[test]
+    fn flag_receipt_rolled_onto_the_next_day() {
+        // Nuuk moves its clocks from 23:00 to 00:00, so 23:30 that evening is rolled into the next day
+        let config = Config {
+            receipt_timezone: chrono_tz::America::Nuuk,
+            ..Config::default()
+        };
+        let extract = |fixture: &str| {
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap()
+        };
+        let fixture = include_str!("../response2_2359.json");
+        assert!(!extract(fixture).needs_review);
+        let in_gap = extract(
+            &fixture
+                .replace(
+                    r#""valueDate": "2023-09-05""#,
+                    r#""valueDate": "2024-03-30""#,
+                )
+                .replace(r#""valueTime": "23:59:00""#, r#""valueTime": "23:30:00""#),
+        );
+        assert_eq!(
+            in_gap.paid_at.date_naive(),
+            chrono::NaiveDate::from_ymd_opt(2024, 3, 31).unwrap()
+        );
+        assert!(in_gap.needs_review);
+    }
+
+    #[test]
+    fn localize_in_configured_receipt_timezone() {
+        let fixture = include_str!("../response2.json");
+        let copenhagen = extract_fixture(fixture).paid_at;
+        assert_eq!(copenhagen.timezone(), chrono_tz::Europe::Copenhagen);
+
+        let config = Config {
+            receipt_timezone: chrono_tz::America::New_York,
+            ..Config::default()
+        };
+        let new_york =
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap();
+        assert_eq!(new_york.paid_at.timezone(), chrono_tz::America::New_York);
+        assert_eq!(new_york.paid_at.naive_local(), copenhagen.naive_local());
+        assert_eq!(new_york.paid_at - copenhagen, chrono::Duration::hours(6));
+    }
+
+    #[test]
+    fn flag_receipts_dated_in_the_future() {
+        let now = chrono::Utc.with_ymd_and_hms(2023, 9, 5, 21, 50, 0).unwrap();
+        let paid_at = chrono_tz::Europe::Copenhagen
+            .with_ymd_and_hms(2023, 9, 5, 23, 59, 0)
+            .unwrap();
+        let allowance = Duration::from_secs(15 * 60);
+        assert!(!extract::is_future_dated(paid_at, now, allowance));
+        assert!(extract::is_future_dated(
+            paid_at,
+            now,
+            Duration::from_secs(60)
+        ));
+        assert!(extract::is_future_dated(
+            paid_at + chrono::Duration::days(1),
+            now,
+            allowance
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_from_replica_when_configured() {
+        let primary = sqlx::PgPool::connect_lazy("postgres://primary.internal/receipts").unwrap();
+        let pool = read_pool(&primary, Some("postgres://replica.internal/receipts")).unwrap();
+        assert!(format!("{:?}", pool.connect_options()).contains("\"replica.internal\""));
+        let pool = read_pool(&primary, None).unwrap();
+        assert!(format!("{:?}", pool.connect_options()).contains("\"primary.internal\""));
+    }
+
+    #[test]
+    fn optionally_synthesize_item_from_total() {
+        let fixture = include_str!("../response2_no_items.json");
+        let receipt = extract_fixture(fixture);
+        assert!(receipt.items.is_empty());
+
+        let config = Config {
+            synthesize_missing_items: true,
+            ..Config::default()
+        };
+        let receipt =
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap();
+        assert_eq!(
+            receipt.items,
+            vec![extract::LineItem {
+                name: "LIDL".into(),
+                count: 1.0,
+                unit_price: Some(92.35),
+                quantity_defaulted: false,
+                quantity_unit: None,
+                product_code: None,
+                price_missing: false,
+                synthesized: true,
+                unattributed: false,
+                regions: vec![],
+            }]
+        );
+
+        let receipt = extract::extract_receipt(
+            serde_json::from_str(include_str!("../response2.json")).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert!(receipt.items.iter().all(|item| !item.synthesized));
+    }
+
+    #[test]
+    fn record_selection_marks() {
+        let receipt = extract_fixture(include_str!("../response2_selection_mark.json"));
+        assert_eq!(
+            serde_json::to_value(&receipt.selection_marks).unwrap(),
+            serde_json::json!({"LoyaltyOptIn": "selected", "PaperReceipt": "unselected"})
+        );
+        assert_eq!(receipt.total, 92.35);
+
+        let receipt = extract_fixture(include_str!("../response2.json"));
+        assert!(receipt.selection_marks.is_empty());
+    }
+
+    #[test]
+    fn normalize_quantity_units() {
+        let receipt = extract_fixture(include_str!("../response2_units.json"));
+        let units = receipt
+            .items
+            .iter()
+            .map(|item| (item.name.as_str(), item.quantity_unit.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            units,
+            vec![
+                ("Tomat passata", Some("stk")),
+                ("Økologisk pebermix", Some("stk")),
+                ("Spidskommen", Some("kg")),
+                ("Rosenpaprika", None),
+                ("Kania Sort peber", None),
+            ]
+        );
+        assert_eq!(extract::normalize_unit(" . "), None);
+    }
+
+    #[test]
+    fn infer_missing_currency_from_configuration() {
+        let fixture = include_str!("../response1_swedish_address.json");
+        let receipt = extract_fixture(fixture);
+        assert_eq!(receipt.currency_code, None);
+        assert!(!receipt.currency_inferred);
+
+        let config = Config {
+            country_currencies: vec!["SWE=SEK".parse().unwrap(), "DNK=DKK".parse().unwrap()],
+            default_currency: Some("EUR".into()),
+            ..Config::default()
+        };
+        let receipt =
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap();
+        assert_eq!(receipt.currency_code.as_deref(), Some("SEK"));
+        assert!(receipt.currency_inferred);
+
+        let receipt = extract::extract_receipt(
+            serde_json::from_str(include_str!("../response1.json")).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(receipt.currency_code.as_deref(), Some("EUR"));
+        assert!(receipt.currency_inferred);
+
+        let receipt = extract::extract_receipt(
+            serde_json::from_str(include_str!("../response2_currency.json")).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(receipt.currency_code.as_deref(), Some("DKK"));
+        assert!(!receipt.currency_inferred);
+    }
+
+    #[test]
+    fn flag_receipts_without_items() {
+        let receipt = extract_fixture(include_str!("../response2_without_items.json"));
+        assert!(receipt.items.is_empty());
+        assert!(receipt.needs_review);
+        assert_eq!(receipt.total, 92.35);
+
+        // An empty item list is what Azure found, not a gap in its answer
+        let receipt = extract_fixture(include_str!("../response2_no_items.json"));
+        assert!(receipt.items.is_empty());
+        assert!(!receipt.needs_review);
+    }
+
+    #[test]
+    fn optionally_book_unattributed_amount() {
+        let fixture = include_str!("../response2_missing_line.json");
+        let receipt = extract_fixture(fixture);
+        let unbalanced = reconcile::reconcile(Some(receipt.total), &receipt.items, &receipt.taxes);
+        assert!(!unbalanced.balanced);
+
+        let config = Config {
+            add_unattributed_lines: true,
+            ..Config::default()
+        };
+        let receipt =
+            extract::extract_receipt(serde_json::from_str(fixture).unwrap(), &config).unwrap();
+        let line = receipt.items.last().unwrap();
+        assert_eq!(line.name, extract::UNATTRIBUTED_LINE);
+        assert!(line.unattributed && !line.synthesized);
+        assert_eq!(line.unit_price, unbalanced.difference);
+        assert!(reconcile::reconcile(Some(receipt.total), &receipt.items, &receipt.taxes).balanced);
+
+        let receipt = extract::extract_receipt(
+            serde_json::from_str(include_str!("../response2_unbalanced.json")).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert!(receipt.items.iter().all(|item| !item.unattributed));
+    }
+
+    #[test]
+    fn parse_optional_receipt_filters() {
+        let filter = |query: &str| {
+            let uri = format!("/receipts?{query}").parse().unwrap();
+            axum::extract::Query::<ReceiptsFilter>::try_from_uri(&uri)
+                .unwrap()
+                .0
+        };
+        let unfiltered = filter("");
+        assert!(unfiltered.from.is_none() && unfiltered.to.is_none());
+        assert!(unfiltered.merchant.is_none() && unfiltered.tag.is_none());
+
+        let filtered = filter(
+            "from=2023-10-01T00:00:00Z&to=2023-10-31T22:00:00-02:00&merchant=lid&tag=Business",
+        );
+        assert_eq!(
+            filtered.from,
+            Some(chrono::Utc.with_ymd_and_hms(2023, 10, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            filtered.to,
+            Some(chrono::Utc.with_ymd_and_hms(2023, 11, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(filtered.merchant.as_deref(), Some("lid"));
+        assert_eq!(
+            filtered
+                .tag
+                .as_deref()
+                .map(tags::normalize_tag)
+                .unwrap()
+                .unwrap(),
+            "business"
+        );
+    }
+
+    #[test]
+    fn force_reanalysis_only_on_request() {
+        let params = |query: &str| {
+            let uri = format!("/upload?{query}").parse().unwrap();
+            axum::extract::Query::<UploadParams>::try_from_uri(&uri)
+                .unwrap()
+                .0
+        };
+        assert!(!params("").force);
+        assert!(!params("model_id=prebuilt-receipt").force);
+        assert!(params("force=true").force);
+        assert_eq!(params("").replaces, None);
+        let hash = crate::file_hash::FileHash::of(b"receipt");
+        assert_eq!(params(&format!("replaces={hash}")).replaces, Some(hash));
+    }
+
+    #[test]
+    fn read_dates_of_quirky_merchants_from_printed_text() {
+        let netto = || serde_json::from_str(include_str!("../response3.json")).unwrap();
+        let paid_on = |config: &Config| {
+            extract::extract_receipt(netto(), config)
+                .unwrap()
+                .paid_at
+                .date_naive()
+        };
+        let date = |month, day| chrono::NaiveDate::from_ymd_opt(2023, month, day).unwrap();
+        assert_eq!(paid_on(&Config::default()), date(10, 6));
+        let no_quirks = Config {
+            merchant_date_quirks: vec![],
+            ..Config::default()
+        };
+        assert_eq!(paid_on(&no_quirks), date(6, 10));
+
+        // The first quirk that reads the printed date wins
+        let quirks = |raw: &str| Config {
+            merchant_date_quirks: raw.split(',').map(|quirk| quirk.parse().unwrap()).collect(),
+            ..Config::default()
+        };
+        assert_eq!(
+            paid_on(&quirks("NETTO=%Y-%d-%m,netto=%Y-%m-%d")),
+            date(6, 10)
+        );
+        assert_eq!(
+            paid_on(&quirks("netto=%d.%m.%Y,netto=%Y-%m-%d")),
+            date(10, 6)
+        );
+        assert_eq!(paid_on(&quirks("bilka=%Y-%m-%d")), date(6, 10));
+
+        assert!("netto".parse::<MerchantDateQuirk>().is_err());
+        assert!("netto=%Y-%Q".parse::<MerchantDateQuirk>().is_err());
+    }
+
+    #[test]
+    fn read_product_codes() {
+        let receipt = extract_fixture(include_str!("../response2_product_codes.json"));
+        let codes = receipt
+            .items
+            .iter()
+            .map(|item| item.product_code.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            vec![
+                Some("5701234000017"),
+                Some("5701234000024"),
+                None,
+                None,
+                None
+            ]
+        );
+
+        // An OCR variant of a name printed with the same code gets no product of its own
+        let mut items = receipt.items;
+        items[2].product_code = Some("5701234000017".into());
+        let names = new_products(&items)
+            .into_iter()
+            .map(|item| item.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), items.len() - 1);
+        assert!(!names.contains(&items[2].name.as_str()));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn store_ocr_variants_under_the_coded_product() {
+        let mut tx = test_db().await;
+        let mut receipt = extract_fixture(include_str!("../response2_product_codes.json"));
+        // Unique names and codes, as the test database may hold real data
+        let suffix = unused_hash("codes");
+        for item in &mut receipt.items {
+            item.name = format!("{} {}", item.name, &suffix.as_str()[..8]);
+            if let Some(code) = &mut item.product_code {
+                code.push_str(&suffix.as_str()[..8]);
+            }
+        }
+        let variant = format!("{} VARIANT", receipt.items[0].name);
+        let mut variant_item = receipt.items[0].clone();
+        variant_item.name = variant.clone();
+        receipt.items.push(variant_item.clone());
+        let receipt_id = insert_receipt_if_not_exists(&mut tx, &receipt, &suffix, None, "")
+            .await
+            .unwrap()
+            .unwrap();
+        insert_products_if_not_exist(&mut tx, &receipt.items, &[])
+            .await
+            .unwrap();
+        upsert_prices_for_products_and_receipt(&mut tx, receipt.items.clone(), receipt_id)
+            .await
+            .unwrap();
+        // Neither in the same batch nor in a later one does the variant get a product
+        insert_products_if_not_exist(&mut tx, &[variant_item], &[])
+            .await
+            .unwrap();
+        let variants: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM products WHERE name = $1")
+            .bind(&variant)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(variants, 0);
+        let coded_lines: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM prices JOIN products ON products.id = prices.product_id WHERE prices.receipt_id = $1 AND products.product_code = $2",
+        )
+        .bind(receipt_id)
+        .bind(&receipt.items[0].product_code)
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(coded_lines, 2);
+        assert_eq!(
+            count(
+                &mut tx,
+                "SELECT COUNT(*) FROM prices WHERE receipt_id = $1",
+                receipt_id
+            )
+            .await,
+            receipt.items.len() as i64
+        );
+    }
+
+    #[test]
+    fn extract_receipts_of_other_document_types() {
+        let hotel = extract_fixture(include_str!("../response2_hotel.json"));
+        let retail = extract_fixture(include_str!("../response2.json"));
+        // Booked on departure
+        assert_eq!(hotel.paid_at.date_naive(), retail.paid_at.date_naive());
+        assert_eq!(hotel.time_precision, TimePrecision::Date);
+        assert_eq!(hotel.total, retail.total);
+        assert_eq!(hotel.items.len(), retail.items.len());
+        assert_eq!(hotel.total_tax, None);
+        assert!(hotel.taxes.is_empty());
+
+        // Unknown types with receipt fields are read as receipts
+        let unknown = extract_fixture(
+            &include_str!("../response2.json").replace("receipt.retailMeal", "receipt.future"),
+        );
+        assert_eq!(unknown, retail);
+
+        // Others still parse, but can't be extracted
+        let invoice = include_str!("../response2.json")
+            .replace("receipt.retailMeal", "invoice")
+            .replace(r#""Total":"#, r#""InvoiceTotal":"#);
+        let operation = serde_json::from_str::<manual::AnalyzeResultOperation>(&invoice).unwrap();
+        let err = extract::extract_receipt(operation, &Config::default()).unwrap_err();
+        assert!(err.to_string().contains("invoice"), "{err}");
+    }
+
+    #[test]
+    fn check_whether_items_match_total() {
+        assert!(extract_fixture(include_str!("../response2.json")).items_match_total());
+        assert!(!extract_fixture(include_str!("../response2_unbalanced.json")).items_match_total());
+    }
+
+    #[tokio::test]
+    async fn report_files_committed_despite_failure_mid_batch() {
+        let file_hashes = (0..6)
+            .map(|i| crate::file_hash::FileHash::of(&[i]))
+            .collect::<Vec<_>>();
+        let failing = file_hashes[2].clone();
+        let pending = file_hashes[4].clone();
+        let outcomes = cache::load_bounded(file_hashes.clone(), 2, |file_hash| {
+            let outcome = if file_hash == failing {
+                Repopulated::Failed
+            } else if file_hash == pending {
+                Repopulated::Skipped
+            } else {
+                Repopulated::Saved
+            };
+            async move { (file_hash, outcome) }
+        })
+        .await;
+        let mut saved = file_hashes
+            .into_iter()
+            .filter(|file_hash| *file_hash != failing && *file_hash != pending)
+            .collect::<Vec<_>>();
+        saved.sort_unstable();
+        assert_eq!(
+            RepopulationReport::new(0, outcomes),
+            RepopulationReport {
+                offset: 0,
+                saved,
+                skipped: 1,
+                failed: vec![failing],
+            }
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn merge_duplicate_without_doubling_lines() {
+        let mut tx = test_db().await;
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        // The first scan missed the last line
+        let mut partial = receipt.clone();
+        partial.items.pop();
+        let receipt_id =
+            insert_receipt_if_not_exists(&mut tx, &partial, &unused_hash("merge"), None, "")
+                .await
+                .unwrap()
+                .unwrap();
+        insert_products_if_not_exist(&mut tx, &partial.items, &[])
+            .await
+            .unwrap();
+        upsert_prices_for_products_and_receipt(&mut tx, partial.items.clone(), receipt_id)
+            .await
+            .unwrap();
+        const LINES: &str = "SELECT COUNT(*) FROM prices WHERE receipt_id = $1";
+        const REGIONS: &str = "SELECT COUNT(*) FROM item_regions WHERE receipt_id = $1";
+        assert_eq!(
+            count(&mut tx, LINES, receipt_id).await,
+            partial.items.len() as i64
+        );
+
+        // A rescan reading more of the receipt replaces its lines
+        merge_items(&mut tx, receipt.items.clone(), receipt_id, &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            count(&mut tx, LINES, receipt_id).await,
+            receipt.items.len() as i64
+        );
+        let full_regions = count(&mut tx, REGIONS, receipt_id).await;
+        assert!(full_regions > 0);
+        let items_match_total: Option<bool> =
+            sqlx::query_scalar("SELECT items_match_total FROM receipts WHERE id = $1")
+                .bind(receipt_id)
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(items_match_total, Some(receipt.items_match_total()));
+
+        // One reading less keeps the lines as they are
+        merge_items(&mut tx, partial.items.clone(), receipt_id, &[])
+            .await
+            .unwrap();
+        merge_items(&mut tx, receipt.items.clone(), receipt_id, &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            count(&mut tx, LINES, receipt_id).await,
+            receipt.items.len() as i64
+        );
+        assert_eq!(count(&mut tx, REGIONS, receipt_id).await, full_regions);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn merge_each_file_only_once() {
+        let mut tx = test_db().await;
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        let receipt_id =
+            insert_receipt_if_not_exists(&mut tx, &receipt, &unused_hash("kept"), None, "")
+                .await
+                .unwrap()
+                .unwrap();
+        let duplicate_hash = unused_hash("duplicate");
+        for expected in [true, false] {
+            let merged = merge_file(
+                &mut tx,
+                &duplicate_hash,
+                receipt.items.clone(),
+                receipt_id,
+                &[],
+            )
+            .await
+            .unwrap();
+            assert_eq!(merged, expected);
+        }
+        assert_eq!(
+            count(
+                &mut tx,
+                "SELECT COUNT(*) FROM prices WHERE receipt_id = $1",
+                receipt_id
+            )
+            .await,
+            receipt.items.len() as i64
+        );
+        assert_eq!(
+            count(
+                &mut tx,
+                "SELECT COUNT(*) FROM merged_files WHERE receipt_id = $1",
+                receipt_id
+            )
+            .await,
+            1
+        );
+    }
+
+    #[test]
+    fn reject_malformed_google_import_as_bad_request() {
+        google::parse_response(include_bytes!("../google_response3.json")).unwrap();
+        let malformed = include_str!("../google_response3.json").replacen(
+            r#""entities": ["#,
+            r#""entities": "none", "ignored": ["#,
+            1,
+        );
+        let err = google::parse_response(malformed.as_bytes()).unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            err.public_message().contains("document.entities"),
+            "{}",
+            err.public_message()
+        );
+        let err = google::parse_response(b"{").unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn roll_back_every_write_of_a_file_failing_midway() {
+        let mut tx = test_db().await;
+        let mut receipt = extract_fixture(include_str!("../response1.json"));
+        // Postgres rejects NUL in text, so the receipt row is written before storing the items fails
+        receipt.items.last_mut().unwrap().name.push('\0');
+        let file_hash = unused_hash("midway");
+        let mut savepoint = sqlx::Connection::begin(&mut *tx).await.unwrap();
+        assert!(write_new_receipt(
+            &mut savepoint,
+            &Config::default(),
+            receipt,
+            &file_hash,
+            None
+        )
+        .await
+        .is_err());
+        drop(savepoint);
+        let stored: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM receipts WHERE file_sha256 = $1")
+                .bind(file_hash.as_str())
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(stored, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn reconcile_flags_only_receipts_whose_cache_entry_is_gone() {
+        let mut tx = test_db().await;
+        let receipt = extract_fixture(include_str!("../response1.json"));
+        let mut receipt_ids = vec![];
+        let file_hashes = ["cached", "evicted", "google"].map(unused_hash);
+        for (file_hash, offset) in file_hashes.iter().zip(1..) {
+            // Receipt timestamps are unique
+            let mut receipt = receipt.clone();
+            receipt.paid_at += chrono::Duration::seconds(offset);
+            let receipt_id = insert_receipt_if_not_exists(&mut tx, &receipt, file_hash, None, "")
+                .await
+                .unwrap()
+                .unwrap();
+            receipt_ids.push(receipt_id);
+        }
+        let [cached, evicted, google] = &file_hashes;
+        sqlx::query("INSERT INTO google_imports(file_sha256, payload) VALUES ($1, '')")
+            .bind(google.as_str())
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE receipts SET needs_review = false WHERE id = ANY($1)")
+            .bind(&receipt_ids)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let db_hashes = consistency::db_hashes(&mut tx).await.unwrap();
+        assert!(db_hashes.contains(cached) && db_hashes.contains(evicted));
+        assert!(!db_hashes.contains(google));
+        // Other receipts in the DB have no cache entry either, so only the stored ones of this test are compared
+        let db_hashes = db_hashes
+            .into_iter()
+            .filter(|file_hash| file_hashes.contains(file_hash))
+            .collect();
+        let uncached = unused_hash("uncached");
+        let found = consistency::compare(vec![cached.clone(), uncached.clone()], db_hashes);
+        assert_eq!(
+            found,
+            consistency::Consistency {
+                cache_only: vec![uncached],
+                db_only: vec![evicted.clone()],
+            }
+        );
+        consistency::flag_for_review(&mut tx, &found.db_only)
+            .await
+            .unwrap();
+        let flagged: Vec<i32> = sqlx::query_scalar(
+            "SELECT id FROM receipts WHERE id = ANY($1) AND needs_review ORDER BY id",
+        )
+        .bind(&receipt_ids)
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(flagged, vec![receipt_ids[1]]);
+    }
 }
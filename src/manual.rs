@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+// Mirrors the Azure Form Recognizer REST schema, so names follow the API rather than Rust conventions
+#![allow(non_snake_case, dead_code, clippy::upper_case_acronyms)]
+
+use std::collections::{BTreeMap, HashMap};
 
 use serde_derive::{Deserialize, Serialize};
 
@@ -25,7 +28,7 @@ pub struct DocumentSpan {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Confidence(f64);
+pub struct Confidence(pub f64);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DocumentWord {
@@ -35,7 +38,8 @@ pub struct DocumentWord {
     pub confidence: Confidence,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SelectionMarkState {
     Selected,
     Unselected,
@@ -49,7 +53,7 @@ pub struct DocumentSelectionMarkState {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DocumentSelectionMark {
-    pub state: DocumentSelectionMarkState,
+    pub state: SelectionMarkState,
     pub polygon: Option<BoundingPolygon>,
     pub span: DocumentSpan,
     pub confidence: Confidence,
@@ -242,17 +246,109 @@ pub struct DocumentLanguage {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct DocType(String);
+pub struct DocType(pub String);
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Fields of a document; which ones Azure extracts depends on its `docType`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum DocumentFields {
+    /// `receipt` and the subtypes for shops, restaurants, credit card slips, gas stations and parking
+    Receipt(Box<Receipt>),
+    /// `receipt.hotel`
+    Hotel(Box<HotelReceipt>),
+    /// A document type we don't model, kept as is so it doesn't fail parsing the whole response
+    Other(BTreeMap<String, serde_json::Value>),
+}
+
+/// Picks the schema of `fields` by the document's type.
+struct FieldsSeed<'a>(&'a DocType);
+
+impl<'de> serde::de::DeserializeSeed<'de> for FieldsSeed<'_> {
+    type Value = DocumentFields;
+
+    fn deserialize<D: serde::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<DocumentFields, D::Error> {
+        use serde::Deserialize;
+        match self.0 .0.as_str() {
+            "receipt" | "receipt.retailMeal" | "receipt.creditCard" | "receipt.gas"
+            | "receipt.parking" => Receipt::deserialize(deserializer)
+                .map(|receipt| DocumentFields::Receipt(Box::new(receipt))),
+            "receipt.hotel" => HotelReceipt::deserialize(deserializer)
+                .map(|hotel| DocumentFields::Hotel(Box::new(hotel))),
+            _ => BTreeMap::deserialize(deserializer).map(DocumentFields::Other),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Document {
     pub docType: DocType,
     pub boundingRegions: Vec<BoundingRegion>,
     pub spans: Vec<DocumentSpan>,
-    pub fields: Receipt,
+    pub fields: DocumentFields,
     pub confidence: Confidence,
 }
 
+impl<'de> serde::Deserialize<'de> for Document {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(DocumentVisitor)
+    }
+}
+
+struct DocumentVisitor;
+
+impl<'de> serde::de::Visitor<'de> for DocumentVisitor {
+    type Value = Document;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an analyzed document")
+    }
+
+    /// Azure sends `docType` before `fields`, so the fields are parsed straight into their schema and malformed ones keep their path.
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Document, A::Error> {
+        use serde::de::{DeserializeSeed, Error};
+        let mut doc_type: Option<DocType> = None;
+        let mut bounding_regions = None;
+        let mut spans = None;
+        let mut fields = None;
+        let mut untyped_fields: Option<serde_json::Value> = None;
+        let mut confidence = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "docType" => doc_type = Some(map.next_value()?),
+                "boundingRegions" => bounding_regions = Some(map.next_value()?),
+                "spans" => spans = Some(map.next_value()?),
+                "fields" => match &doc_type {
+                    Some(doc_type) => fields = Some(map.next_value_seed(FieldsSeed(doc_type))?),
+                    None => untyped_fields = Some(map.next_value()?),
+                },
+                "confidence" => confidence = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<serde::de::IgnoredAny>()?;
+                }
+            }
+        }
+        let doc_type = doc_type.ok_or_else(|| A::Error::missing_field("docType"))?;
+        let fields = match (fields, untyped_fields) {
+            (Some(fields), _) => fields,
+            (None, Some(untyped)) => FieldsSeed(&doc_type)
+                .deserialize(untyped)
+                .map_err(A::Error::custom)?,
+            (None, None) => return Err(A::Error::missing_field("fields")),
+        };
+        Ok(Document {
+            docType: doc_type,
+            boundingRegions: bounding_regions
+                .ok_or_else(|| A::Error::missing_field("boundingRegions"))?,
+            spans: spans.ok_or_else(|| A::Error::missing_field("spans"))?,
+            fields,
+            confidence: confidence.ok_or_else(|| A::Error::missing_field("confidence"))?,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AnalyzeResult {
     pub apiVersion: ApiVersion,
@@ -282,8 +378,10 @@ pub struct DocumentField {
     #[serde(rename = "type")]
     pub field_type: DocumentFieldType,
     pub value_string: Option<String>,
-    pub value_date: Option<String>, // Should be parsed to a DateTime type in Rust
-    pub value_time: Option<String>, // Should be parsed to a DateTime type in Rust
+    #[serde(default, with = "value_date")]
+    pub value_date: Option<chrono::NaiveDate>,
+    #[serde(default, with = "value_time")]
+    pub value_time: Option<chrono::NaiveTime>,
     pub value_phone_number: Option<String>,
     pub value_number: Option<f64>,
     pub value_integer: Option<i64>,
@@ -354,7 +452,7 @@ pub struct CurrencyValue {
     pub currencyCode: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AddressValue {
     pub houseNumber: Option<String>,
     pub poBox: Option<String>,
@@ -372,25 +470,91 @@ pub struct AddressValue {
     pub level: Option<String>,
 }
 
-// TODO: Hotel Receipts have different Items and additional fields
-
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Receipt {
+    /// `None` when Azure found no item list at all, as opposed to an empty one
     #[serde(rename = "Items")]
-    pub items: Items,
-    #[serde(rename = "MerchantName")]
+    pub items: Option<Items>,
+    #[serde(rename = "MerchantName", default)]
     pub merchant_name: StringObject,
+    #[serde(rename = "MerchantAddress")]
+    pub merchant_address: Option<AddressObject>,
+    #[serde(rename = "MerchantPhoneNumber")]
+    pub merchant_phone_number: Option<PhoneNumberObject>,
     #[serde(rename = "TaxDetails")]
     pub tax_details: TaxDetails,
     #[serde(rename = "Total")]
     pub total: NumberObject,
     #[serde(rename = "TotalTax")]
-    pub total_tax: NumberObject,
+    pub total_tax: Option<NumberObject>,
     #[serde(rename = "TransactionDate")]
     pub transaction_date: DateObject,
-    #[serde(rename = "TransactionTime")]
+    #[serde(rename = "TransactionTime", default)]
     pub transaction_time: TimeObject,
+    #[serde(rename = "Currency")]
+    pub currency: Option<StringObject>,
+    /// Fields we don't model, e.g. checkboxes; see [`SelectionMarkObject`]
+    #[serde(flatten)]
+    pub other_fields: BTreeMap<String, serde_json::Value>,
+}
+
+/// A `receipt.hotel` document: a stay from arrival to departure instead of a transaction time, and no tax summary.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HotelReceipt {
+    /// Charges of the stay, e.g. one per night
+    #[serde(rename = "Items")]
+    pub items: Option<Items>,
+    #[serde(rename = "MerchantName", default)]
+    pub merchant_name: StringObject,
+    #[serde(rename = "MerchantAddress")]
+    pub merchant_address: Option<AddressObject>,
+    #[serde(rename = "MerchantPhoneNumber")]
+    pub merchant_phone_number: Option<PhoneNumberObject>,
+    #[serde(rename = "ArrivalDate")]
+    pub arrival_date: Option<DateObject>,
+    #[serde(rename = "DepartureDate")]
+    pub departure_date: Option<DateObject>,
+    #[serde(rename = "Total")]
+    pub total: NumberObject,
+    #[serde(rename = "Currency")]
+    pub currency: Option<StringObject>,
+    #[serde(flatten)]
+    pub other_fields: BTreeMap<String, serde_json::Value>,
+}
+
+/// A hotel is paid on departure, so that is when the stay is booked; the arrival date stands in if no departure was read.
+impl From<HotelReceipt> for Receipt {
+    fn from(hotel: HotelReceipt) -> Self {
+        Receipt {
+            items: hotel.items,
+            merchant_name: hotel.merchant_name,
+            merchant_address: hotel.merchant_address,
+            merchant_phone_number: hotel.merchant_phone_number,
+            tax_details: TaxDetails::default(),
+            total: hotel.total,
+            total_tax: None,
+            transaction_date: hotel
+                .departure_date
+                .or(hotel.arrival_date)
+                .unwrap_or_default(),
+            transaction_time: TimeObject::default(),
+            currency: hotel.currency,
+            other_fields: hotel.other_fields,
+        }
+    }
+}
+
+/// A checkbox on the receipt, e.g. a loyalty program opt-in, as one of the document's fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectionMarkObject {
+    #[serde(rename = "type")]
+    pub type_field: String,
+    pub value_selection_mark: Option<SelectionMarkState>,
+    pub content: Option<String>,
+    pub confidence: Option<f64>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -428,6 +592,12 @@ pub struct ValueObject {
     pub product_code: Option<StringObject>,
     #[serde(rename = "QuantityUnit")]
     pub quantity_unit: Option<StringObject>,
+    /// Day of the charge; only on hotel receipts
+    #[serde(rename = "Date")]
+    pub date: Option<DateObject>,
+    /// Kind of charge, e.g. `Room` or `Breakfast`; only on hotel receipts
+    #[serde(rename = "Category")]
+    pub category: Option<StringObject>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -442,6 +612,30 @@ pub struct StringObject {
     pub spans: Vec<Span>,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddressObject {
+    #[serde(rename = "type")]
+    pub type_field: String,
+    pub value_address: Option<AddressValue>,
+    pub content: String,
+    pub bounding_regions: Vec<BoundingRegion>,
+    pub confidence: f64,
+    pub spans: Vec<Span>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhoneNumberObject {
+    #[serde(rename = "type")]
+    pub type_field: String,
+    pub value_phone_number: Option<String>,
+    pub content: String,
+    pub bounding_regions: Vec<BoundingRegion>,
+    pub confidence: f64,
+    pub spans: Vec<Span>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Span {
@@ -455,6 +649,7 @@ pub struct NumberObject {
     #[serde(rename = "type")]
     pub type_field: String,
     pub value_number: f64,
+    pub value_currency: Option<ValueCurrency>,
     pub content: String,
     pub bounding_regions: Vec<BoundingRegion>,
     pub confidence: f64,
@@ -486,6 +681,12 @@ pub struct ValueArray2 {
 pub struct ValueObject2 {
     #[serde(rename = "Amount")]
     pub amount: Amount,
+    #[serde(rename = "Rate")]
+    pub rate: Option<NumberObject>,
+    #[serde(rename = "NetAmount")]
+    pub net_amount: Option<Amount>,
+    #[serde(rename = "Description")]
+    pub description: Option<StringObject>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -504,6 +705,74 @@ pub struct Amount {
 #[serde(rename_all = "camelCase")]
 pub struct ValueCurrency {
     pub amount: f64,
+    pub currency_symbol: Option<String>,
+    pub currency_code: Option<String>,
+}
+
+/// Blank when Azure could not make a value of the printed text; anything else has to parse, so a malformed value fails with its path in the response.
+fn deserialize_blank_or<'de, D, T>(
+    deserializer: D,
+    format: &str,
+    parse: fn(&str, &str) -> chrono::ParseResult<T>,
+) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = <Option<String> as serde::Deserialize>::deserialize(deserializer)?;
+    match raw.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(raw) => parse(raw, format).map(Some).map_err(|err| {
+            serde::de::Error::custom(format!("{raw:?} does not match {format}: {err}"))
+        }),
+    }
+}
+
+/// `valueDate`, e.g. `2023-09-05`
+mod value_date {
+    use chrono::NaiveDate;
+
+    const FORMAT: &str = "%Y-%m-%d";
+
+    pub fn serialize<S: serde::Serializer>(
+        date: &Option<NaiveDate>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(
+            &date
+                .map(|date| date.format(FORMAT).to_string())
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<NaiveDate>, D::Error> {
+        super::deserialize_blank_or(deserializer, FORMAT, NaiveDate::parse_from_str)
+    }
+}
+
+/// `valueTime`, e.g. `19:36:00`
+mod value_time {
+    use chrono::NaiveTime;
+
+    const FORMAT: &str = "%H:%M:%S";
+
+    pub fn serialize<S: serde::Serializer>(
+        time: &Option<NaiveTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(
+            &time
+                .map(|time| time.format(FORMAT).to_string())
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<NaiveTime>, D::Error> {
+        super::deserialize_blank_or(deserializer, FORMAT, NaiveTime::parse_from_str)
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -511,7 +780,9 @@ pub struct ValueCurrency {
 pub struct DateObject {
     #[serde(rename = "type")]
     pub type_field: String,
-    pub value_date: String,
+    /// Left out or blank when Azure could not make a date of the printed text
+    #[serde(default, with = "value_date")]
+    pub value_date: Option<chrono::NaiveDate>,
     pub content: String,
     pub bounding_regions: Vec<BoundingRegion>,
     pub confidence: f64,
@@ -523,7 +794,8 @@ pub struct DateObject {
 pub struct TimeObject {
     #[serde(rename = "type")]
     pub type_field: String,
-    pub value_time: String,
+    #[serde(default, with = "value_time")]
+    pub value_time: Option<chrono::NaiveTime>,
     pub content: String,
     pub bounding_regions: Vec<BoundingRegion>,
     pub confidence: f64,
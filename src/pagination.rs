@@ -0,0 +1,147 @@
+use axum::http::{HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_LIMIT: i64 = 100;
+pub const MAX_LIMIT: i64 = 1000;
+/// Total number of matches, for clients paging without reading the body
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// `limit`/`offset` query parameters shared by all list endpoints.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct PageParams {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    /// Answer with only the number of matches, without fetching them
+    #[serde(default)]
+    pub count_only: bool,
+}
+
+impl PageParams {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+/// Uniform response of list endpoints; `next_offset` is `null` on the last page.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    pub next_offset: Option<i64>,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, total: i64, params: PageParams) -> Self {
+        let offset = params.offset();
+        let end = offset + items.len() as i64;
+        Self {
+            next_offset: (!items.is_empty() && end < total).then_some(end),
+            items,
+            total,
+            limit: params.limit(),
+            offset,
+        }
+    }
+}
+
+/// What list endpoints answer: a page of the matches, or with `count_only` just how many there are.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Listing<T> {
+    Page(Page<T>),
+    Count { total: i64 },
+}
+
+impl<T> Listing<T> {
+    pub fn total(&self) -> i64 {
+        match self {
+            Listing::Page(page) => page.total,
+            Listing::Count { total } => *total,
+        }
+    }
+
+    pub fn insert_total_header(&self, headers: &mut HeaderMap) {
+        headers.insert(TOTAL_COUNT_HEADER, HeaderValue::from(self.total()));
+    }
+
+    /// Pages through matches that had to be fetched in full anyway, e.g. ones not kept in the database.
+    pub fn from_all(items: Vec<T>, params: PageParams) -> Self {
+        let total = items.len() as i64;
+        if params.count_only {
+            return Self::Count { total };
+        }
+        let items = items
+            .into_iter()
+            .skip(params.offset() as usize)
+            .take(params.limit() as usize)
+            .collect();
+        Self::Page(Page::new(items, total, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Listing, Page, PageParams, MAX_LIMIT};
+
+    fn params(limit: i64, offset: i64) -> PageParams {
+        PageParams {
+            limit: Some(limit),
+            offset: Some(offset),
+            count_only: false,
+        }
+    }
+
+    #[test]
+    fn point_to_next_page_until_the_last() {
+        let page = Page::new(vec![1, 2], 5, params(2, 0));
+        assert_eq!(page.next_offset, Some(2));
+        let page = Page::new(vec![3, 4], 5, params(2, 2));
+        assert_eq!(page.next_offset, Some(4));
+        let page = Page::new(vec![5], 5, params(2, 4));
+        assert_eq!(page.next_offset, None);
+        let page = Page::<i32>::new(vec![], 5, params(2, 10));
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[test]
+    fn clamp_out_of_range_params() {
+        let params = params(MAX_LIMIT * 10, -3);
+        assert_eq!(params.limit(), MAX_LIMIT);
+        assert_eq!(params.offset(), 0);
+        assert_eq!(PageParams::default().limit(), 100);
+    }
+
+    #[test]
+    fn count_all_matches_without_items() {
+        let items: Vec<i32> = (0..250).collect();
+        let Listing::Count { total } = Listing::from_all(
+            items.clone(),
+            PageParams {
+                count_only: true,
+                ..params(10, 20)
+            },
+        ) else {
+            panic!("Expected only a count");
+        };
+        assert_eq!(total, items.len() as i64);
+
+        let Listing::Page(page) = Listing::from_all(items, params(10, 20)) else {
+            panic!("Expected a page");
+        };
+        assert_eq!(page.total, total);
+        assert_eq!(page.items, (20..30).collect::<Vec<_>>());
+        let mut headers = axum::http::HeaderMap::new();
+        Listing::Page(page).insert_total_header(&mut headers);
+        assert_eq!(headers[super::TOTAL_COUNT_HEADER], "250");
+        assert_eq!(
+            serde_json::to_value(Listing::<i32>::Count { total }).unwrap(),
+            serde_json::json!({ "total": 250 })
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Detection of Azure "out of call volume quota" responses and the cooldown that follows them.
+
+use std::{sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Azure rejected an analysis because the subscription's pricing tier ran out of calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub message: String,
+    /// How long Azure asked us to wait, if the message says
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetails,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetails {
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+/// Recognizes the quota error among Azure error responses, e.g. `403` with "Out of call volume quota for FormRecognizer F0 pricing tier. Please retry after 2 days."
+pub fn parse_quota_error(status: StatusCode, body: &str) -> Option<QuotaExceeded> {
+    if !matches!(
+        status,
+        StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+    ) {
+        return None;
+    }
+    let ErrorBody { error } = serde_json::from_str(body).ok()?;
+    let is_quota = error.code.eq_ignore_ascii_case("QuotaExceeded")
+        || error.message.to_lowercase().contains("quota");
+    is_quota.then(|| QuotaExceeded {
+        retry_after: retry_after_from_message(&error.message),
+        message: error.message,
+    })
+}
+
+/// Reads the "retry after <n> <unit>" hint Azure puts in quota messages.
+fn retry_after_from_message(message: &str) -> Option<Duration> {
+    let lowercase = message.to_lowercase();
+    let (_, rest) = lowercase.split_once("retry after ")?;
+    let mut words = rest.split_whitespace();
+    let amount = words.next()?.parse::<u64>().ok()?;
+    let unit = words.next()?.trim_end_matches(['.', ',']);
+    let seconds = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        _ => return None,
+    };
+    // A hint too large to represent is no hint, so the configured cooldown applies
+    amount.checked_mul(seconds).map(Duration::from_secs)
+}
+
+/// The last quota error and until when new submissions are held back because of it.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaHit {
+    pub message: String,
+    pub exceeded_at: DateTime<Utc>,
+    pub paused_until: Option<DateTime<Utc>>,
+}
+
+/// Shared record of Azure quota errors, consulted before each new analysis.
+#[derive(Default)]
+pub struct QuotaStatus {
+    last_hit: Mutex<Option<QuotaHit>>,
+}
+
+impl QuotaStatus {
+    /// Remembers the error; submissions are paused for Azure's own hint, or else `cooldown`. A zero cooldown pauses nothing.
+    pub fn record(&self, quota: &QuotaExceeded, cooldown: Duration, now: DateTime<Utc>) {
+        let pause = quota.retry_after.unwrap_or(cooldown);
+        // Pauses reaching past representable times last as long as they can instead of not at all
+        let paused_until = (!cooldown.is_zero()).then(|| {
+            chrono::Duration::from_std(pause)
+                .ok()
+                .and_then(|pause| now.checked_add_signed(pause))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC)
+        });
+        *self.last_hit.lock().unwrap() = Some(QuotaHit {
+            message: quota.message.clone(),
+            exceeded_at: now,
+            paused_until,
+        });
+    }
+
+    /// How long new submissions must still wait, if the cooldown is running.
+    pub fn paused_for(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.last_hit
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|hit| hit.paused_until)
+            .and_then(|until| (until - now).to_std().ok())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn last_hit(&self) -> Option<QuotaHit> {
+        self.last_hit.lock().unwrap().clone()
+    }
+
+    /// A successful submission means the quota is available again.
+    pub fn clear(&self) {
+        *self.last_hit.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use super::{parse_quota_error, QuotaStatus};
+
+    const QUOTA_BODY: &str = r#"{"error":{"code":"403","message":"Out of call volume quota for FormRecognizer F0 pricing tier. Please retry after 2 days. To increase your call volume switch to a paid tier."}}"#;
+
+    #[test]
+    fn detect_quota_error_body() {
+        let quota = parse_quota_error(StatusCode::FORBIDDEN, QUOTA_BODY).unwrap();
+        assert_eq!(
+            quota.retry_after,
+            Some(Duration::from_secs(2 * 24 * 60 * 60))
+        );
+        assert!(quota.message.starts_with("Out of call volume quota"));
+
+        let wrong_key = r#"{"error":{"code":"401","message":"Access denied due to invalid subscription key."}}"#;
+        assert!(parse_quota_error(StatusCode::UNAUTHORIZED, wrong_key).is_none());
+        assert!(parse_quota_error(StatusCode::FORBIDDEN, "Forbidden").is_none());
+        assert!(parse_quota_error(StatusCode::INTERNAL_SERVER_ERROR, QUOTA_BODY).is_none());
+
+        let overflowing = QUOTA_BODY.replace("2 days", "18446744073709551615 days");
+        let quota = parse_quota_error(StatusCode::FORBIDDEN, &overflowing).unwrap();
+        assert_eq!(quota.retry_after, None);
+    }
+
+    #[test]
+    fn pause_submissions_until_cooldown_ends() {
+        let now = chrono::Utc::now();
+        let status = QuotaStatus::default();
+        let mut quota = parse_quota_error(StatusCode::FORBIDDEN, QUOTA_BODY).unwrap();
+        quota.retry_after = None;
+        status.record(&quota, Duration::from_secs(600), now);
+        assert_eq!(status.paused_for(now), Some(Duration::from_secs(600)));
+        assert_eq!(
+            status.paused_for(now + chrono::Duration::seconds(600)),
+            None
+        );
+        assert!(status.last_hit().is_some());
+
+        // Hints and cooldowns too large to add to now pause as long as possible
+        quota.retry_after = Some(Duration::MAX);
+        status.record(&quota, Duration::from_secs(600), now);
+        assert!(status.paused_for(now).unwrap() > Duration::from_secs(100 * 365 * 24 * 60 * 60));
+
+        status.record(&quota, Duration::ZERO, now);
+        assert_eq!(status.paused_for(now), None);
+        status.clear();
+        assert!(status.last_hit().is_none());
+    }
+}
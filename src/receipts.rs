@@ -0,0 +1,205 @@
+//! Looking up a single receipt and annotating it.
+
+use std::sync::Arc;
+
+use axum::extract::{Path, Query, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{compare, extract::LineItem, local_time, AppError, AppState};
+
+const MAX_NOTE_LENGTH: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct SetNote {
+    /// `null` or blank clears the note
+    note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ShowReceiptParams {
+    /// IANA timezone to show `paid_at` in; defaults to the timezone the receipt was printed in
+    tz: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteReceiptParams {
+    /// Also delete products no longer bought on any receipt
+    #[serde(default)]
+    prune_products: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeletedReceipt {
+    id: i32,
+    prices_deleted: u64,
+    products_deleted: u64,
+}
+
+#[derive(Serialize)]
+pub struct ReceiptDetail {
+    id: i32,
+    merchant_name: String,
+    paid_at: chrono::DateTime<chrono::FixedOffset>,
+    /// Date and time as printed on the receipt
+    local_paid_at: chrono::NaiveDateTime,
+    /// IANA timezone the receipt was printed in
+    time_zone: String,
+    total: Option<f64>,
+    total_tax: Option<f64>,
+    currency_code: Option<String>,
+    currency_inferred: bool,
+    needs_review: bool,
+    duplicate_of: Option<i32>,
+    note: Option<String>,
+    tags: Vec<String>,
+    /// Line items in the order they're printed
+    items: Vec<LineItem>,
+}
+
+/// Blank notes are stored as no note at all.
+pub fn normalize_note(raw: Option<&str>) -> Result<Option<String>, AppError> {
+    let Some(note) = raw.map(str::trim).filter(|note| !note.is_empty()) else {
+        return Ok(None);
+    };
+    if note.chars().count() > MAX_NOTE_LENGTH {
+        return Err(AppError::bad_request(format!(
+            "Notes must be at most {MAX_NOTE_LENGTH} characters long"
+        )));
+    }
+    Ok(Some(note.to_string()))
+}
+
+async fn receipt_detail(
+    app_state: &AppState,
+    id: i32,
+    display_tz: Option<chrono_tz::Tz>,
+) -> Result<ReceiptDetail, AppError> {
+    let receipt = sqlx::query!(
+        "SELECT id, merchant_name, local_paid_at, time_zone, total, total_tax, currency_code, currency_inferred, needs_review, duplicate_of, note FROM receipts WHERE id = $1",
+        id
+    )
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or(AppError::not_found(format!("Receipt {id} does not exist")))?;
+    let tags = sqlx::query_scalar!(
+        "SELECT tag FROM receipt_tags WHERE receipt_id = $1 ORDER BY tag",
+        id
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
+    let display_tz = match display_tz {
+        Some(tz) => tz,
+        None => local_time::parse_time_zone(&receipt.time_zone)?,
+    };
+    let paid_at = local_time::resolve(receipt.local_paid_at, &receipt.time_zone, display_tz)?;
+    let items = compare::receipt_items(app_state, id).await?;
+    Ok(ReceiptDetail {
+        id: receipt.id,
+        merchant_name: receipt.merchant_name,
+        paid_at: paid_at.fixed_offset(),
+        local_paid_at: receipt.local_paid_at,
+        time_zone: receipt.time_zone,
+        total: receipt.total,
+        total_tax: receipt.total_tax,
+        currency_code: receipt.currency_code,
+        currency_inferred: receipt.currency_inferred,
+        needs_review: receipt.needs_review,
+        duplicate_of: receipt.duplicate_of,
+        note: receipt.note,
+        tags,
+        items,
+    })
+}
+
+/// `paid_at` is resolved from the printed time when the receipt is shown, so it follows `tz` and any fix to timezone rules.
+pub async fn show_receipt(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    Query(params): Query<ShowReceiptParams>,
+) -> Result<axum::Json<ReceiptDetail>, AppError> {
+    let display_tz = params
+        .tz
+        .as_deref()
+        .map(local_time::parse_time_zone)
+        .transpose()?;
+    Ok(axum::Json(
+        receipt_detail(&app_state, id, display_tz).await?,
+    ))
+}
+
+/// Sets or clears the receipt's note. Responds with the updated receipt.
+pub async fn set_note(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    axum::Json(body): axum::Json<SetNote>,
+) -> Result<axum::Json<ReceiptDetail>, AppError> {
+    let note = normalize_note(body.note.as_deref())?;
+    let updated = sqlx::query!("UPDATE receipts SET note = $2 WHERE id = $1", id, note)
+        .execute(&app_state.pool)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(AppError::not_found(format!("Receipt {id} does not exist")));
+    }
+    Ok(axum::Json(receipt_detail(&app_state, id, None).await?))
+}
+
+/// Deletes the receipt with its line items; tags and tax lines go with it, and receipts marked as its duplicates lose the mark.
+/// The cached analysis is kept, so repopulating the DB from the cache brings the receipt back.
+pub async fn delete_receipt(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    Query(params): Query<DeleteReceiptParams>,
+) -> Result<axum::Json<DeletedReceipt>, AppError> {
+    let mut tx = app_state.pool.begin().await?;
+    let prices_deleted = sqlx::query!("DELETE FROM prices WHERE receipt_id = $1", id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    let receipts_deleted = sqlx::query!("DELETE FROM receipts WHERE id = $1", id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if receipts_deleted == 0 {
+        return Err(AppError::not_found(format!("Receipt {id} does not exist")));
+    }
+    let products_deleted = if params.prune_products {
+        sqlx::query!(
+            "DELETE FROM products WHERE NOT EXISTS (SELECT 1 FROM prices WHERE prices.product_id = products.id)"
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+    } else {
+        0
+    };
+    tx.commit().await?;
+    tracing::info!("Deleted receipt {id} with {prices_deleted} line items and {products_deleted} orphaned products");
+    Ok(axum::Json(DeletedReceipt {
+        id,
+        prices_deleted,
+        products_deleted,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_note, SetNote};
+
+    fn note_of(body: &str) -> Option<String> {
+        let body: SetNote = serde_json::from_str(body).unwrap();
+        normalize_note(body.note.as_deref()).unwrap()
+    }
+
+    #[test]
+    fn set_and_clear_notes() {
+        assert_eq!(
+            note_of(r#"{"note": " Client dinner with Acme\n"}"#).as_deref(),
+            Some("Client dinner with Acme")
+        );
+        assert_eq!(note_of(r#"{"note": null}"#), None);
+        assert_eq!(note_of(r#"{"note": "  "}"#), None);
+        assert_eq!(note_of("{}"), None);
+        assert!(normalize_note(Some(&"x".repeat(1001))).is_err());
+    }
+}
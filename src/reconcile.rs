@@ -0,0 +1,172 @@
+//! Checks whether a receipt's lines add up to its printed total.
+
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use serde::Serialize;
+
+use crate::{
+    compare,
+    extract::{LineItem, TaxLine},
+    AppError, AppState,
+};
+
+/// Differences below half a cent are rounding noise.
+pub const TOLERANCE: f64 = 0.005;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LineContribution {
+    pub name: String,
+    pub count: f64,
+    pub unit_price: Option<f64>,
+    /// `None` when the line has no price, which keeps the receipt from balancing
+    pub line_total: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Reconciliation {
+    pub printed_total: Option<f64>,
+    /// Currency of all amounts, when the receipt's is known
+    pub currency_code: Option<String>,
+    /// Sum of all priced lines, discounts included
+    pub item_sum: f64,
+    /// Sum of the negative lines, e.g. discounts and returned deposits
+    pub discounts: f64,
+    /// Tax from the receipt's tax summary; prices already include it
+    pub tax: f64,
+    /// `printed_total - item_sum`
+    pub difference: Option<f64>,
+    pub balanced: bool,
+    pub lines: Vec<LineContribution>,
+}
+
+fn cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+pub fn reconcile(
+    printed_total: Option<f64>,
+    items: &[LineItem],
+    taxes: &[TaxLine],
+) -> Reconciliation {
+    let lines = items
+        .iter()
+        .map(|item| LineContribution {
+            name: item.name.clone(),
+            count: item.count,
+            unit_price: item.unit_price,
+            line_total: item.unit_price.map(|price| cents(item.count * price)),
+        })
+        .collect::<Vec<_>>();
+    let line_totals = || lines.iter().filter_map(|line| line.line_total);
+    let item_sum = cents(line_totals().sum());
+    let difference = printed_total.map(|total| cents(total - item_sum));
+    Reconciliation {
+        printed_total,
+        currency_code: None,
+        item_sum,
+        discounts: cents(line_totals().filter(|total| *total < 0.0).sum()),
+        tax: cents(taxes.iter().map(|tax| tax.amount).sum()),
+        balanced: difference.is_some_and(|difference| difference.abs() < TOLERANCE)
+            && lines.iter().all(|line| line.line_total.is_some()),
+        difference,
+        lines,
+    }
+}
+
+/// The printed total tax and the sum of the tax lines, when they differ; OCR dropping a tax line or misreading an amount makes them disagree.
+pub fn tax_mismatch(total_tax: Option<f64>, taxes: &[TaxLine]) -> Option<(f64, f64)> {
+    if taxes.is_empty() {
+        return None;
+    }
+    let summed = cents(taxes.iter().map(|tax| tax.amount).sum());
+    total_tax
+        .filter(|total_tax| (total_tax - summed).abs() >= TOLERANCE)
+        .map(|total_tax| (total_tax, summed))
+}
+
+/// Breaks a receipt's total down into its lines, to find the one that doesn't add up.
+pub async fn receipt_reconciliation(
+    State(app_state): State<Arc<AppState>>,
+    Path(receipt_id): Path<i32>,
+) -> Result<axum::Json<Reconciliation>, AppError> {
+    let receipt = sqlx::query!(
+        "SELECT total, currency_code FROM receipts WHERE id = $1",
+        receipt_id
+    )
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or(AppError::not_found(format!(
+        "Receipt {receipt_id} does not exist"
+    )))?;
+    let items = compare::receipt_items(&app_state, receipt_id).await?;
+    let taxes = sqlx::query_as!(
+        TaxLine,
+        "SELECT rate, net_amount, amount, currency_code FROM tax_lines WHERE receipt_id = $1 ORDER BY id",
+        receipt_id
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
+    Ok(axum::Json(Reconciliation {
+        currency_code: receipt.currency_code,
+        ..reconcile(receipt.total, &items, &taxes)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reconcile, tax_mismatch};
+    use crate::{
+        config::Config,
+        extract::{self, TaxLine},
+    };
+
+    fn reconcile_fixture(json: &str) -> super::Reconciliation {
+        let receipt =
+            extract::extract_receipt(serde_json::from_str(json).unwrap(), &Config::default())
+                .unwrap();
+        reconcile(Some(receipt.total), &receipt.items, &receipt.taxes)
+    }
+
+    #[test]
+    fn balance_receipt_whose_lines_add_up() {
+        let reconciliation = reconcile_fixture(include_str!("../response2.json"));
+        assert_eq!(reconciliation.item_sum, 92.35);
+        assert_eq!(reconciliation.difference, Some(0.0));
+        assert!(reconciliation.balanced);
+    }
+
+    #[test]
+    fn point_out_the_line_that_is_off() {
+        let reconciliation = reconcile_fixture(include_str!("../response2_unbalanced.json"));
+        assert_eq!(reconciliation.printed_total, Some(92.35));
+        assert_eq!(reconciliation.item_sum, 96.35);
+        assert_eq!(reconciliation.difference, Some(-4.0));
+        assert_eq!(reconciliation.tax, 18.47);
+        assert_eq!(reconciliation.discounts, 0.0);
+        assert!(!reconciliation.balanced);
+        let line = reconciliation
+            .lines
+            .iter()
+            .find(|line| line.name == "Spidskommen")
+            .unwrap();
+        assert_eq!(line.line_total, Some(9.95));
+    }
+
+    #[test]
+    fn notice_tax_lines_not_adding_up_to_total_tax() {
+        let line = |amount| TaxLine {
+            rate: Some(25.0),
+            net_amount: None,
+            amount,
+            currency_code: Some("DKK".into()),
+        };
+        let taxes = [line(12.5), line(5.97)];
+        assert_eq!(tax_mismatch(Some(18.47), &taxes), None);
+        assert_eq!(tax_mismatch(Some(18.474), &taxes), None);
+        assert_eq!(tax_mismatch(Some(19.47), &taxes), Some((19.47, 18.47)));
+        assert_eq!(tax_mismatch(None, &taxes), None);
+        // Nothing to compare when no tax summary was read
+        assert_eq!(tax_mismatch(Some(18.47), &[]), None);
+    }
+}
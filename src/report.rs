@@ -0,0 +1,366 @@
+//! Printable PDF expense reports.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::extract::{Path, Query, State};
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str};
+use serde::Deserialize;
+
+use crate::{
+    compare, config::Config, export::format_amount, extract::LineItem, AppError, AppState,
+};
+
+const PAGE_WIDTH: f32 = 595.0; // A4
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 56.0;
+const FONT_SIZE: f32 = 10.0;
+const LEADING: f32 = 14.0;
+const LINES_PER_PAGE: usize = ((PAGE_HEIGHT - 2.0 * MARGIN) / LEADING) as usize;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReportParams {
+    /// First day to include, in the receipt timezone
+    pub from: Option<chrono::NaiveDate>,
+    /// Last day to include, in the receipt timezone
+    pub to: Option<chrono::NaiveDate>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportReceipt {
+    pub id: i32,
+    pub paid_at: chrono::DateTime<chrono::Utc>,
+    pub total: Option<f64>,
+    pub currency_code: Option<String>,
+    pub items: Vec<LineItem>,
+}
+
+fn local_time(paid_at: chrono::DateTime<chrono::Utc>, config: &Config) -> String {
+    paid_at
+        .with_timezone(&config.receipt_timezone)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+fn amount(value: Option<f64>, currency_code: Option<&str>, config: &Config) -> String {
+    match value {
+        Some(value) => format!(
+            "{} {}",
+            format_amount(value, config),
+            currency_code.unwrap_or_default()
+        )
+        .trim_end()
+        .to_string(),
+        None => "-".into(),
+    }
+}
+
+/// The itemized lines of one receipt, as printed on its own page of a report.
+pub fn receipt_lines(merchant_name: &str, receipt: &ReportReceipt, config: &Config) -> Vec<String> {
+    let currency = receipt.currency_code.as_deref();
+    let mut lines = vec![
+        format!("{merchant_name}, receipt {}", receipt.id),
+        local_time(receipt.paid_at, config),
+        String::new(),
+    ];
+    lines.extend(receipt.items.iter().map(|item| {
+        format!(
+            "{:>8} x {:<40} {}",
+            format_amount(item.count, config),
+            item.name,
+            amount(item.unit_price, currency, config)
+        )
+    }));
+    lines.push(String::new());
+    lines.push(format!(
+        "Total: {}",
+        amount(receipt.total, currency, config)
+    ));
+    lines
+}
+
+fn period(params: &ReportParams) -> String {
+    match (params.from, params.to) {
+        (Some(from), Some(to)) => format!("{from} to {to}"),
+        (Some(from), None) => format!("from {from}"),
+        (None, Some(to)) => format!("until {to}"),
+        (None, None) => "all time".into(),
+    }
+}
+
+/// Text of each page: a summary with the spend per currency, then every receipt itemized on its own pages.
+pub fn merchant_report_pages(
+    merchant_name: &str,
+    params: &ReportParams,
+    receipts: &[ReportReceipt],
+    config: &Config,
+) -> Vec<Vec<String>> {
+    let mut summary = vec![
+        format!("Expense report: {merchant_name}"),
+        format!("Period: {}", period(params)),
+        String::new(),
+    ];
+    if receipts.is_empty() {
+        summary.push(format!("No receipts from {merchant_name} in this period."));
+        return vec![summary];
+    }
+    let mut spent = BTreeMap::<Option<&str>, f64>::new();
+    let mut without_total = 0;
+    for receipt in receipts {
+        match receipt.total {
+            Some(total) => *spent.entry(receipt.currency_code.as_deref()).or_default() += total,
+            None => without_total += 1,
+        }
+    }
+    summary.push(format!("Receipts: {}", receipts.len()));
+    summary.extend(spent.into_iter().map(|(currency, total)| {
+        format!("Total spent: {}", amount(Some(total), currency, config))
+    }));
+    // Their amounts are unknown, so the totals above leave them out rather than count them as 0
+    if without_total > 0 {
+        summary.push(format!(
+            "Receipts without a total, not included above: {without_total}"
+        ));
+    }
+    summary.push(String::new());
+    summary.extend(receipts.iter().map(|receipt| {
+        format!(
+            "{}  receipt {:<8} {}",
+            local_time(receipt.paid_at, config),
+            receipt.id,
+            amount(receipt.total, receipt.currency_code.as_deref(), config)
+        )
+    }));
+    std::iter::once(summary)
+        .chain(
+            receipts
+                .iter()
+                .map(|receipt| receipt_lines(merchant_name, receipt, config)),
+        )
+        .flat_map(|lines| {
+            lines
+                .chunks(LINES_PER_PAGE)
+                .map(<[String]>::to_vec)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The standard PDF fonts only cover WinAnsi, which includes the Danish letters; anything else becomes `?`.
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            '€' => 0x80,
+            c if (c as u32) < 0x80 || (0xA0..=0xFF).contains(&(c as u32)) => c as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Lays out pages of plain text lines in a monospaced font, so columns line up.
+pub fn render_pdf(pages: &[Vec<String>]) -> Vec<u8> {
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let font_id = Ref::new(3);
+    let font_name = Name(b"F1");
+    let page_ids = (0..pages.len() as i32)
+        .map(|i| Ref::new(4 + 2 * i))
+        .collect::<Vec<_>>();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().copied())
+        .count(pages.len() as i32);
+    pdf.type1_font(font_id)
+        .base_font(Name(b"Courier"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+    for (lines, page_id) in pages.iter().zip(page_ids) {
+        let content_id = Ref::new(page_id.get() + 1);
+        let mut page = pdf.page(page_id);
+        page.media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT))
+            .parent(page_tree_id)
+            .contents(content_id);
+        page.resources().fonts().pair(font_name, font_id);
+        page.finish();
+
+        let mut content = Content::new();
+        content
+            .begin_text()
+            .set_font(font_name, FONT_SIZE)
+            .set_leading(LEADING)
+            .next_line(MARGIN, PAGE_HEIGHT - MARGIN);
+        for line in lines {
+            content.show(Str(&win_ansi(line))).next_line_using_leading();
+        }
+        content.end_text();
+        pdf.stream(content_id, &content.finish());
+    }
+    pdf.finish()
+}
+
+/// A merchant's receipts in a period as a PDF expense report, leaving out flagged duplicates.
+pub async fn merchant_report(
+    State(app_state): State<Arc<AppState>>,
+    Path(merchant_name): Path<String>,
+    Query(params): Query<ReportParams>,
+) -> Result<
+    (
+        axum::response::AppendHeaders<[(axum::http::HeaderName, String); 2]>,
+        Vec<u8>,
+    ),
+    AppError,
+> {
+    let rows = sqlx::query!(
+        "SELECT id, paid_at, total, currency_code FROM receipts WHERE lower(merchant_name) = lower($1) AND duplicate_of IS NULL AND ($2::date IS NULL OR (paid_at AT TIME ZONE $4)::date >= $2) AND ($3::date IS NULL OR (paid_at AT TIME ZONE $4)::date <= $3) ORDER BY paid_at, id",
+        merchant_name,
+        params.from,
+        params.to,
+        app_state.config.receipt_timezone.name()
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
+    let ids = rows.iter().map(|row| row.id).collect::<Vec<_>>();
+    let mut items = compare::receipts_items(&app_state, &ids).await?;
+    let receipts = rows
+        .into_iter()
+        .map(|row| ReportReceipt {
+            items: items.remove(&row.id).unwrap_or_default(),
+            id: row.id,
+            paid_at: row.paid_at,
+            total: row.total,
+            currency_code: row.currency_code,
+        })
+        .collect::<Vec<_>>();
+    let pages = merchant_report_pages(&merchant_name, &params, &receipts, &app_state.config);
+    let file_name = merchant_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let headers = axum::response::AppendHeaders([
+        (
+            axum::http::header::CONTENT_TYPE,
+            "application/pdf".to_string(),
+        ),
+        (
+            axum::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{file_name}-report.pdf\""),
+        ),
+    ]);
+    Ok((headers, render_pdf(&pages)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::{merchant_report_pages, render_pdf, win_ansi, ReportParams, ReportReceipt};
+    use crate::{config::Config, extract};
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn report_on_a_merchants_receipts() {
+        let receipt = extract::extract_receipt(
+            serde_json::from_str(include_str!("../response2.json")).unwrap(),
+            &Config::default(),
+        )
+        .unwrap();
+        let receipts = [
+            ReportReceipt {
+                id: 1,
+                paid_at: receipt.paid_at.with_timezone(&chrono::Utc),
+                total: Some(receipt.total),
+                currency_code: Some("DKK".into()),
+                items: receipt.items.clone(),
+            },
+            ReportReceipt {
+                id: 2,
+                paid_at: chrono::Utc.with_ymd_and_hms(2023, 9, 12, 15, 0, 0).unwrap(),
+                total: Some(7.65),
+                currency_code: Some("DKK".into()),
+                items: vec![],
+            },
+        ];
+        let pages = merchant_report_pages(
+            "LIDL",
+            &ReportParams::default(),
+            &receipts,
+            &Config::default(),
+        );
+        assert_eq!(pages.len(), 3);
+        assert!(pages[0].contains(&"Total spent: 100.00 DKK".to_string()));
+        assert!(pages[1]
+            .iter()
+            .any(|line| line.contains("Økologisk pebermix")));
+        assert!(pages[0]
+            .iter()
+            .any(|line| line.starts_with("2023-09-12 17:00  receipt 2")));
+        let new_york = Config {
+            receipt_timezone: chrono_tz::America::New_York,
+            ..Config::default()
+        };
+        let pages = merchant_report_pages("LIDL", &ReportParams::default(), &receipts, &new_york);
+        assert!(pages[0]
+            .iter()
+            .any(|line| line.starts_with("2023-09-12 11:00  receipt 2")));
+
+        let pdf = render_pdf(&pages);
+        assert!(pdf.starts_with(b"%PDF"));
+        assert!(contains(&pdf, b"/Count 3"));
+        // Danish letters are kept in the font's encoding
+        assert_eq!(win_ansi("Øl 5€ ✓"), b"\xd8l 5\x80 ?");
+    }
+
+    #[test]
+    fn count_receipts_without_total_apart() {
+        let receipt = |id, total| ReportReceipt {
+            id,
+            paid_at: chrono::Utc.with_ymd_and_hms(2023, 9, 12, 15, 0, 0).unwrap(),
+            total,
+            currency_code: Some("DKK".into()),
+            items: vec![],
+        };
+        let pages = merchant_report_pages(
+            "Netto",
+            &ReportParams::default(),
+            &[receipt(1, Some(7.65)), receipt(2, None)],
+            &Config::default(),
+        );
+        assert_eq!(
+            pages[0][3..6],
+            [
+                "Receipts: 2",
+                "Total spent: 7.65 DKK",
+                "Receipts without a total, not included above: 1",
+            ]
+        );
+        assert!(pages[0]
+            .iter()
+            .any(|line| line.starts_with("2023-09-12 17:00  receipt 2") && line.ends_with(" -")));
+    }
+
+    #[test]
+    fn explain_empty_report_on_a_single_page() {
+        let params = ReportParams {
+            from: chrono::NaiveDate::from_ymd_opt(2023, 1, 1),
+            to: chrono::NaiveDate::from_ymd_opt(2023, 1, 31),
+        };
+        let pages = merchant_report_pages("Netto", &params, &[], &Config::default());
+        assert_eq!(
+            pages,
+            [[
+                "Expense report: Netto",
+                "Period: 2023-01-01 to 2023-01-31",
+                "",
+                "No receipts from Netto in this period.",
+            ]]
+        );
+        let pdf = render_pdf(&pages);
+        assert!(contains(&pdf, b"/Count 1"));
+        assert!(contains(&pdf, b"No receipts from Netto in this period."));
+    }
+}
@@ -0,0 +1,83 @@
+//! Azure analyzes asynchronously; its result is polled from the `Operation-Location` until the operation is done.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{
+    errors::IngestError, file_hash::FileHash, get_analysis_results, retry::RetryPolicy, AppError,
+    AppState,
+};
+
+const MAX_POLL_DELAY: Duration = Duration::from_secs(30);
+
+/// Just enough of `AnalyzeResultOperation` to tell whether it is done; the full response is only parsed once it is.
+#[derive(Deserialize)]
+struct OperationStatus {
+    status: String,
+}
+
+/// Waits before each poll, doubling from `first` up to [`MAX_POLL_DELAY`], for as long as they add up to at most `timeout`.
+pub fn poll_delays(first: Duration, timeout: Duration) -> impl Iterator<Item = Duration> {
+    std::iter::successors(Some(first.min(MAX_POLL_DELAY)), |delay| {
+        Some((*delay * 2).min(MAX_POLL_DELAY))
+    })
+    .scan(Duration::ZERO, move |waited, delay| {
+        *waited += delay;
+        (*waited <= timeout).then_some(delay)
+    })
+}
+
+/// Raw response of the finished operation, whether it `succeeded` or `failed`; a failure is turned into an error by [`crate::process_analysis_results`] once it is cached.
+pub async fn poll_analysis_results(
+    app_state: &AppState,
+    result_url: &str,
+    file_hash: &FileHash,
+) -> Result<String, AppError> {
+    let timeout = app_state.config.analysis_poll_timeout;
+    for delay in poll_delays(app_state.config.analysis_first_poll_delay, timeout) {
+        tokio::time::sleep(delay).await;
+        let res = get_analysis_results(
+            result_url,
+            &app_state.azure_form_recognizer_api_key,
+            &app_state.client,
+            RetryPolicy::new(app_state.config.azure_max_retries),
+        )
+        .await?
+        .error_for_status()?;
+        let text = res.text().await?;
+        let OperationStatus { status } = serde_json::from_str(&text)?;
+        match status.as_str() {
+            "succeeded" | "failed" => return Ok(text),
+            status => tracing::info!("Analysis of file {} is {}, waiting", file_hash, status),
+        }
+    }
+    Err(IngestError::Analysis(format!(
+        "Analysis of file {file_hash} did not finish within {} seconds",
+        timeout.as_secs()
+    ))
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::poll_delays;
+
+    #[test]
+    fn back_off_until_timeout() {
+        let delays = |first| {
+            poll_delays(Duration::from_secs(first), Duration::from_secs(120))
+                .map(|delay| delay.as_secs())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(2), [2, 4, 8, 16, 30, 30, 30]);
+        assert_eq!(delays(10), [10, 20, 30, 30, 30]);
+        assert_eq!(delays(45), [30, 30, 30, 30]);
+        assert_eq!(
+            poll_delays(Duration::from_secs(2), Duration::from_secs(1)).count(),
+            0
+        );
+    }
+}
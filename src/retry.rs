@@ -0,0 +1,163 @@
+//! Retries of Azure calls that failed for reasons expected to pass, like throttling or a restarting backend.
+
+use std::{future::Future, time::Duration};
+
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts after the first one
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one
+    pub base_delay: Duration,
+    /// Longest wait between attempts. A longer `Retry-After`, like the days a quota error asks for, is not waited out; the response is returned instead.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    /// `None` means the wait asked for is too long to be worth it.
+    fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        match retry_after {
+            Some(retry_after) => (retry_after <= self.max_delay).then_some(retry_after),
+            None => Some(
+                self.base_delay
+                    .saturating_mul(2u32.saturating_pow(retry))
+                    .min(self.max_delay),
+            ),
+        }
+    }
+}
+
+pub fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+/// Only the delay-seconds form; Azure doesn't send HTTP dates.
+fn retry_after(res: &Response) -> Option<Duration> {
+    let seconds = res.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Sends the request built by `send` until it gets a response that isn't worth retrying, or retries run out. Responses are returned whatever their status, so callers still see e.g. quota errors.
+pub async fn send_with_retry<F, Fut>(
+    policy: RetryPolicy,
+    mut send: F,
+) -> Result<Response, reqwest::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Response, reqwest::Error>>,
+{
+    let mut retry = 0;
+    loop {
+        let result = send().await;
+        let delay = match &result {
+            Ok(res) if is_retryable(res.status()) => policy.delay(retry, retry_after(res)),
+            Err(err) if err.is_timeout() || err.is_connect() => policy.delay(retry, None),
+            _ => None,
+        };
+        let Some(delay) = delay.filter(|_| retry < policy.max_retries) else {
+            return result;
+        };
+        retry += 1;
+        match &result {
+            Ok(res) => tracing::warn!(
+                "Azure responded with {}, retrying in {} ms (retry {} of {})",
+                res.status(),
+                delay.as_millis(),
+                retry,
+                policy.max_retries
+            ),
+            Err(err) => tracing::warn!(
+                "Azure request failed: {}, retrying in {} ms (retry {} of {})",
+                err,
+                delay.as_millis(),
+                retry,
+                policy.max_retries
+            ),
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    fn response(status: u16, retry_after: Option<&str>) -> Response {
+        let mut res = axum::http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            res = res.header(RETRY_AFTER, retry_after);
+        }
+        Response::from(res.body(String::new()).unwrap())
+    }
+
+    fn instant_policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::from_millis(10),
+        }
+    }
+
+    async fn statuses_sent(policy: RetryPolicy, statuses: &[(u16, Option<&str>)]) -> (u16, u32) {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let res = send_with_retry(policy, || {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst) as usize;
+            let (status, retry_after) = statuses[attempt.min(statuses.len() - 1)];
+            async move { Ok(response(status, retry_after)) }
+        })
+        .await
+        .unwrap();
+        (res.status().as_u16(), attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn retry_transient_statuses_only() {
+        let policy = instant_policy(3);
+        assert_eq!(
+            statuses_sent(policy, &[(503, None), (429, Some("0")), (202, None)]).await,
+            (202, 3)
+        );
+        assert_eq!(statuses_sent(policy, &[(500, None)]).await, (500, 4));
+        assert_eq!(statuses_sent(policy, &[(400, None)]).await, (400, 1));
+        assert_eq!(statuses_sent(policy, &[(403, None)]).await, (403, 1));
+        // Waiting out a quota reset is left to the quota cooldown
+        assert_eq!(
+            statuses_sent(policy, &[(429, Some("86400"))]).await,
+            (429, 1)
+        );
+    }
+
+    #[test]
+    fn back_off_exponentially_or_as_asked() {
+        let policy = RetryPolicy::new(5);
+        let delays = (0..7)
+            .map(|retry| policy.delay(retry, None).unwrap().as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60]);
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(5))),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(120))), None);
+    }
+}
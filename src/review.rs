@@ -0,0 +1,281 @@
+//! One triage screen for everything an operator may need to look at.
+
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{self, CachedAnalysis},
+    cached_hashes, load_cached,
+    pagination::{Listing, Page, PageParams},
+    AppError, AppState,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewState {
+    /// Receipts where something essential could not be extracted reliably
+    #[default]
+    NeedsReview,
+    /// Receipts flagged as probable duplicates of another receipt
+    Duplicate,
+    /// Files Azure could not analyze
+    Failed,
+    /// Azure responses that did not match the expected schema
+    ParseFailed,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReviewFilter {
+    #[serde(default)]
+    pub state: ReviewState,
+    /// Case-insensitive merchant name; items without a merchant never match
+    pub merchant: Option<String>,
+    /// First day to include, in the receipt timezone
+    pub from: Option<chrono::NaiveDate>,
+    /// Last day to include, in the receipt timezone
+    pub to: Option<chrono::NaiveDate>,
+    /// Azure error code of failed analyses, or part of the field path of parse failures
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReviewItem {
+    pub state: ReviewState,
+    pub receipt_id: Option<i32>,
+    pub file_hash: Option<String>,
+    pub merchant_name: Option<String>,
+    /// When the purchase was made, or when processing failed
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub detail: String,
+}
+
+impl ReviewFilter {
+    /// Same rules the SQL queries apply, for queues that don't live in the database.
+    pub fn matches(&self, item: &ReviewItem, error: &str, time_zone: chrono_tz::Tz) -> bool {
+        let day = item.at.with_timezone(&time_zone).date_naive();
+        let merchant_matches = match (&self.merchant, &item.merchant_name) {
+            (None, _) => true,
+            (Some(wanted), Some(merchant)) => wanted.to_lowercase() == merchant.to_lowercase(),
+            (Some(_), None) => false,
+        };
+        merchant_matches
+            && self.from.is_none_or(|from| day >= from)
+            && self.to.is_none_or(|to| day <= to)
+            && self
+                .error
+                .as_ref()
+                .is_none_or(|wanted| error.eq_ignore_ascii_case(wanted))
+    }
+}
+
+async fn failed_analyses(
+    app_state: &Arc<AppState>,
+    filter: &ReviewFilter,
+) -> Result<Vec<ReviewItem>, AppError> {
+    let entries = cache::load_bounded(
+        cached_hashes(app_state)?,
+        app_state.config.cache_read_concurrency,
+        |file_hash| {
+            let app_state = app_state.clone();
+            async move {
+                let entry = load_cached(&app_state, &file_hash).await;
+                (file_hash, entry)
+            }
+        },
+    )
+    .await;
+    let mut items = Vec::new();
+    for (file_hash, entry) in entries {
+        if let CachedAnalysis::Failed(failure) = entry? {
+            let item = ReviewItem {
+                state: ReviewState::Failed,
+                receipt_id: None,
+                file_hash: Some(file_hash.to_string()),
+                merchant_name: None,
+                at: failure.failed_at.into(),
+                detail: format!("{}: {}", failure.code, failure.message),
+            };
+            if filter.matches(&item, &failure.code, app_state.config.receipt_timezone) {
+                items.push(item);
+            }
+        }
+    }
+    items.sort_by_key(|item| std::cmp::Reverse(item.at));
+    Ok(items)
+}
+
+/// Lists the items in one triage queue, newest first.
+pub async fn review(
+    State(app_state): State<Arc<AppState>>,
+    Query(page): Query<PageParams>,
+    Query(filter): Query<ReviewFilter>,
+) -> Result<axum::Json<Listing<ReviewItem>>, AppError> {
+    let pool = &app_state.pool;
+    let time_zone = app_state.config.receipt_timezone.name();
+    let (items, total) = match filter.state {
+        ReviewState::NeedsReview | ReviewState::Duplicate => {
+            let duplicates = filter.state == ReviewState::Duplicate;
+            if page.count_only {
+                let total = sqlx::query_scalar!(
+                    r#"SELECT COUNT(*) AS "count!" FROM receipts WHERE (CASE WHEN $1 THEN duplicate_of IS NOT NULL ELSE needs_review END) AND ($2::text IS NULL OR lower(merchant_name) = lower($2)) AND ($3::date IS NULL OR (paid_at AT TIME ZONE $5)::date >= $3) AND ($4::date IS NULL OR (paid_at AT TIME ZONE $5)::date <= $4)"#,
+                    duplicates,
+                    filter.merchant,
+                    filter.from,
+                    filter.to,
+                    time_zone
+                )
+                .fetch_one(pool)
+                .await?;
+                return Ok(axum::Json(Listing::Count { total }));
+            }
+            let rows = sqlx::query!(
+                r#"SELECT id, merchant_name, paid_at, file_sha256, duplicate_of, COUNT(*) OVER () AS "total!" FROM receipts WHERE (CASE WHEN $1 THEN duplicate_of IS NOT NULL ELSE needs_review END) AND ($2::text IS NULL OR lower(merchant_name) = lower($2)) AND ($3::date IS NULL OR (paid_at AT TIME ZONE $7)::date >= $3) AND ($4::date IS NULL OR (paid_at AT TIME ZONE $7)::date <= $4) ORDER BY paid_at DESC, id LIMIT $5 OFFSET $6"#,
+                duplicates,
+                filter.merchant,
+                filter.from,
+                filter.to,
+                page.limit(),
+                page.offset(),
+                time_zone
+            )
+            .fetch_all(pool)
+            .await?;
+            let total = rows.first().map_or(0, |row| row.total);
+            let items = rows
+                .into_iter()
+                .map(|row| ReviewItem {
+                    state: filter.state,
+                    receipt_id: Some(row.id),
+                    file_hash: row.file_sha256,
+                    merchant_name: Some(row.merchant_name),
+                    at: row.paid_at,
+                    detail: match row.duplicate_of {
+                        Some(original) if duplicates => {
+                            format!("Probable duplicate of receipt {original}")
+                        }
+                        _ => "Needs review".into(),
+                    },
+                })
+                .collect();
+            (items, total)
+        }
+        ReviewState::ParseFailed => {
+            // Parse failures have no merchant, so asking for one leaves nothing
+            if page.count_only {
+                let total = sqlx::query_scalar!(
+                    r#"SELECT COUNT(*) AS "count!" FROM parse_failures WHERE NOT $1 AND ($2::date IS NULL OR (failed_at AT TIME ZONE $5)::date >= $2) AND ($3::date IS NULL OR (failed_at AT TIME ZONE $5)::date <= $3) AND ($4::text IS NULL OR field_path ILIKE '%' || $4 || '%')"#,
+                    filter.merchant.is_some(),
+                    filter.from,
+                    filter.to,
+                    filter.error,
+                    time_zone
+                )
+                .fetch_one(pool)
+                .await?;
+                return Ok(axum::Json(Listing::Count { total }));
+            }
+            let rows = sqlx::query!(
+                r#"SELECT file_sha256, field_path, error, failed_at, COUNT(*) OVER () AS "total!" FROM parse_failures WHERE NOT $1 AND ($2::date IS NULL OR (failed_at AT TIME ZONE $7)::date >= $2) AND ($3::date IS NULL OR (failed_at AT TIME ZONE $7)::date <= $3) AND ($4::text IS NULL OR field_path ILIKE '%' || $4 || '%') ORDER BY failed_at DESC, file_sha256 LIMIT $5 OFFSET $6"#,
+                filter.merchant.is_some(),
+                filter.from,
+                filter.to,
+                filter.error,
+                page.limit(),
+                page.offset(),
+                time_zone
+            )
+            .fetch_all(pool)
+            .await?;
+            let total = rows.first().map_or(0, |row| row.total);
+            let items = rows
+                .into_iter()
+                .map(|row| ReviewItem {
+                    state: ReviewState::ParseFailed,
+                    receipt_id: None,
+                    file_hash: Some(row.file_sha256),
+                    merchant_name: None,
+                    at: row.failed_at,
+                    detail: format!("{}: {}", row.field_path, row.error),
+                })
+                .collect();
+            (items, total)
+        }
+        ReviewState::Failed => {
+            // Failures only live in the cache, so they are read in full either way
+            let items = failed_analyses(&app_state, &filter).await?;
+            return Ok(axum::Json(Listing::from_all(items, page)));
+        }
+    };
+    Ok(axum::Json(Listing::Page(Page::new(items, total, page))))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{rejection::QueryRejection, Query};
+    use chrono::TimeZone;
+    use chrono_tz::{America::New_York, Europe::Copenhagen};
+
+    use super::{ReviewFilter, ReviewItem, ReviewState};
+
+    fn failed_item() -> ReviewItem {
+        ReviewItem {
+            state: ReviewState::Failed,
+            receipt_id: None,
+            file_hash: Some("abc".into()),
+            merchant_name: None,
+            // Already the 11th in Copenhagen
+            at: chrono::Utc
+                .with_ymd_and_hms(2023, 10, 10, 22, 30, 0)
+                .unwrap(),
+            detail: "InvalidContent: The file is corrupted".into(),
+        }
+    }
+
+    fn parse(query: &str) -> Result<ReviewFilter, QueryRejection> {
+        let uri = format!("/review?{query}").parse().unwrap();
+        Query::<ReviewFilter>::try_from_uri(&uri).map(|Query(filter)| filter)
+    }
+
+    fn filter(query: &str) -> ReviewFilter {
+        parse(query).unwrap()
+    }
+
+    #[test]
+    fn parse_state_filter() {
+        assert_eq!(filter("").state, ReviewState::NeedsReview);
+        assert_eq!(filter("state=needs_review").state, ReviewState::NeedsReview);
+        assert_eq!(filter("state=duplicate").state, ReviewState::Duplicate);
+        assert_eq!(filter("state=failed").state, ReviewState::Failed);
+        assert_eq!(filter("state=parse_failed").state, ReviewState::ParseFailed);
+        assert!(parse("state=unknown").is_err());
+    }
+
+    #[test]
+    fn filter_failed_analyses() {
+        let item = failed_item();
+        assert!(filter("state=failed").matches(&item, "InvalidContent", Copenhagen));
+        assert!(filter("state=failed&error=invalidcontent").matches(
+            &item,
+            "InvalidContent",
+            Copenhagen
+        ));
+        assert!(!filter("state=failed&error=Timeout").matches(&item, "InvalidContent", Copenhagen));
+        assert!(!filter("state=failed&merchant=Netto").matches(
+            &item,
+            "InvalidContent",
+            Copenhagen
+        ));
+        assert!(
+            filter("state=failed&from=2023-10-11&to=2023-10-11").matches(
+                &item,
+                "InvalidContent",
+                Copenhagen
+            )
+        );
+        assert!(!filter("state=failed&to=2023-10-10").matches(&item, "InvalidContent", Copenhagen));
+        // Still the 10th in New York
+        assert!(filter("state=failed&to=2023-10-10").matches(&item, "InvalidContent", New_York));
+    }
+}
@@ -0,0 +1,357 @@
+use serde::Serialize;
+
+/// What each table holds, keyed by table name.
+const TABLE_DESCRIPTIONS: &[(&str, &str)] = &[
+    ("receipts", "One row per analyzed receipt"),
+    (
+        "products",
+        "Distinct product names seen across all receipts",
+    ),
+    (
+        "prices",
+        "Line items: what was paid for a product on a receipt",
+    ),
+    ("receipt_tags", "User-defined labels on receipts"),
+    (
+        "tax_lines",
+        "Tax summary of a receipt, one row per tax rate",
+    ),
+    (
+        "merged_files",
+        "Files whose receipt was merged into a probable duplicate instead of being stored",
+    ),
+    (
+        "google_imports",
+        "Google Document AI responses as imported, replayed when the DB is rebuilt from the cache",
+    ),
+    (
+        "analysis_errors",
+        "Failures of processing uploaded files in the background, one row per failure",
+    ),
+];
+
+/// Meaning of each column, keyed by (table, column). Columns missing here are still listed, just without a description.
+const COLUMN_DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    ("receipts", "id", "Receipt identifier"),
+    (
+        "receipts",
+        "merchant_name",
+        "Store name as printed on the receipt",
+    ),
+    ("receipts", "paid_at", "Moment of purchase"),
+    (
+        "receipts",
+        "file_sha256",
+        "SHA-256 of the uploaded file the receipt was read from",
+    ),
+    ("receipts", "total", "Total printed on the receipt"),
+    ("receipts", "total_tax", "Tax included in the total, as printed"),
+    (
+        "receipts",
+        "items_match_total",
+        "The priced lines add up to `total` within half a cent; null if there is no total",
+    ),
+    (
+        "receipts",
+        "category",
+        "Budgeting category from the first merchant rule matching `merchant_name`, `uncategorized` if none does",
+    ),
+    ("receipts", "note", "Free-text memo added by the user"),
+    (
+        "receipts",
+        "duplicate_of",
+        "Receipt this one most likely duplicates (same merchant, time and total), if any",
+    ),
+    (
+        "receipts",
+        "currency_code",
+        "ISO 4217 currency of the receipt amounts, if detected",
+    ),
+    (
+        "receipts",
+        "needs_review",
+        "Something essential, like the merchant, could not be extracted reliably",
+    ),
+    (
+        "receipts",
+        "model_id",
+        "Azure model the receipt was analyzed with",
+    ),
+    (
+        "receipts",
+        "time_precision",
+        "`time` if the time of purchase was read, `date` if only the date was and `paid_at` holds an assumed time",
+    ),
+    (
+        "receipts",
+        "receipt_text",
+        "Full text printed on the receipt, as read by OCR",
+    ),
+    (
+        "receipts",
+        "receipt_text_search",
+        "Search index of `receipt_text`, generated",
+    ),
+    (
+        "receipts",
+        "selection_marks",
+        "Checkboxes on the receipt by field name, e.g. `{\"LoyaltyOptIn\": \"selected\"}`; empty if there were none",
+    ),
+    (
+        "receipts",
+        "local_paid_at",
+        "Date and time of purchase as printed, in `time_zone`",
+    ),
+    (
+        "receipts",
+        "time_zone",
+        "IANA timezone the receipt was printed in, e.g. `Europe/Copenhagen`",
+    ),
+    (
+        "receipts",
+        "created_at",
+        "When the receipt was stored; `since` of the exports filters on it",
+    ),
+    (
+        "receipts",
+        "currency_inferred",
+        "No currency was printed and `currency_code` is the configured default",
+    ),
+    ("products", "id", "Product identifier"),
+    (
+        "products",
+        "name",
+        "Product description as printed on receipts",
+    ),
+    (
+        "products",
+        "category",
+        "Category from the first matching rule when the product was first categorized",
+    ),
+    (
+        "products",
+        "product_code",
+        "Article number printed next to the product, e.g. an EAN; lines with it are matched to this product whatever their name",
+    ),
+    ("prices", "product_id", "Product the line refers to"),
+    ("prices", "receipt_id", "Receipt the line appears on"),
+    (
+        "prices",
+        "count",
+        "Quantity bought; may be fractional for weighed goods",
+    ),
+    (
+        "prices",
+        "unit_price",
+        "Price per unit; negative for discounts, empty if none was detected",
+    ),
+    (
+        "prices",
+        "quantity_defaulted",
+        "No quantity was detected and `count` holds the configured default",
+    ),
+    (
+        "prices",
+        "quantity_unit",
+        "Unit of `count` as printed, lowercased, e.g. `kg` or `stk`; empty if none was detected",
+    ),
+    (
+        "prices",
+        "price_missing",
+        "No price was detected; a set `unit_price` was filled in by hand",
+    ),
+    (
+        "prices",
+        "synthesized",
+        "Nothing was itemized, so this line books the receipt's total",
+    ),
+    (
+        "prices",
+        "line_index",
+        "Position of the line on the receipt, so a product listed several times keeps every line",
+    ),
+    (
+        "prices",
+        "unattributed",
+        "The line books the part of the receipt's total its other lines don't account for",
+    ),
+    ("receipt_tags", "receipt_id", "Tagged receipt"),
+    ("receipt_tags", "tag", "Lowercased label"),
+    ("tax_lines", "id", "Tax line identifier"),
+    ("tax_lines", "receipt_id", "Receipt the tax was charged on"),
+    (
+        "tax_lines",
+        "rate",
+        "Tax rate in percent, as printed or derived from the amounts",
+    ),
+    ("tax_lines", "net_amount", "Amount the tax was charged on"),
+    ("tax_lines", "amount", "Tax charged"),
+    (
+        "tax_lines",
+        "currency_code",
+        "ISO 4217 code of the tax amount, the receipt's currency unless printed otherwise",
+    ),
+    ("merged_files", "file_sha256", "SHA-256 of the merged file"),
+    (
+        "merged_files",
+        "receipt_id",
+        "Receipt the file's items were merged into",
+    ),
+    ("merged_files", "merged_at", "When the file was merged"),
+    (
+        "google_imports",
+        "file_sha256",
+        "SHA-256 of the imported response",
+    ),
+    ("google_imports", "payload", "Response body as it was imported"),
+    ("google_imports", "imported_at", "When the response was imported"),
+    ("analysis_errors", "id", "Failure identifier"),
+    (
+        "analysis_errors",
+        "file_sha256",
+        "SHA-256 of the file whose background processing failed",
+    ),
+    ("analysis_errors", "error", "Why processing failed"),
+    ("analysis_errors", "occurred_at", "When processing failed"),
+    ("parse_failures", "file_sha256", "SHA-256 of the analyzed file"),
+    (
+        "parse_failures",
+        "field_path",
+        "Path of the first field that could not be deserialized",
+    ),
+    ("parse_failures", "error", "Deserialization error"),
+    ("parse_failures", "response", "Raw Azure response"),
+    ("parse_failures", "failed_at", "When the response was received"),
+];
+
+/// Columns of the `/all` and `/download` exports.
+const EXPORT_COLUMNS: &[(&str, &str)] = &[
+    ("name", "Product description (products.name)"),
+    ("unit_price", "Price per unit (prices.unit_price)"),
+    ("count", "Quantity bought (prices.count)"),
+    (
+        "quantity_unit",
+        "Unit of `count`, e.g. `kg` for weighed goods; empty if none was printed (prices.quantity_unit)",
+    ),
+    (
+        "currency_code",
+        "ISO 4217 code of `unit_price`; empty if the receipt's currency is unknown (receipts.currency_code)",
+    ),
+    (
+        "items_match_total",
+        "The receipt's lines add up to its total; empty if it has no total (receipts.items_match_total)",
+    ),
+    (
+        "category",
+        "Budgeting category of the receipt, e.g. `groceries`; `uncategorized` if no rule matched its merchant (receipts.category)",
+    ),
+    ("merchant_name", "Store name (receipts.merchant_name)"),
+    (
+        "paid_at",
+        "Moment of purchase in UTC, RFC 3339 (receipts.paid_at)",
+    ),
+];
+
+/// One column as reported by the database.
+pub struct ColumnInfo {
+    pub table_name: String,
+    pub column_name: String,
+    pub data_type: String,
+    pub is_nullable: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DataDictionary {
+    pub tables: Vec<TableEntry>,
+    pub export_columns: Vec<ExportColumn>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableEntry {
+    pub name: String,
+    pub description: Option<&'static str>,
+    pub columns: Vec<ColumnEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ColumnEntry {
+    pub name: String,
+    pub data_type: String,
+    pub nullable: bool,
+    pub description: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportColumn {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// Builds the dictionary from live column metadata (expected ordered by table), attaching the documented meaning where known.
+pub fn data_dictionary(columns: Vec<ColumnInfo>) -> DataDictionary {
+    let mut tables: Vec<TableEntry> = Vec::new();
+    for column in columns {
+        let description = COLUMN_DESCRIPTIONS
+            .iter()
+            .find(|(table, name, _)| *table == column.table_name && *name == column.column_name)
+            .map(|(_, _, description)| *description);
+        let entry = ColumnEntry {
+            name: column.column_name,
+            data_type: column.data_type,
+            nullable: column.is_nullable,
+            description,
+        };
+        match tables.last_mut() {
+            Some(table) if table.name == column.table_name => table.columns.push(entry),
+            _ => tables.push(TableEntry {
+                description: TABLE_DESCRIPTIONS
+                    .iter()
+                    .find(|(table, _)| *table == column.table_name)
+                    .map(|(_, description)| *description),
+                name: column.table_name,
+                columns: vec![entry],
+            }),
+        }
+    }
+    DataDictionary {
+        tables,
+        export_columns: EXPORT_COLUMNS
+            .iter()
+            .map(|(name, description)| ExportColumn { name, description })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{data_dictionary, ColumnInfo};
+
+    fn column(table_name: &str, column_name: &str) -> ColumnInfo {
+        ColumnInfo {
+            table_name: table_name.into(),
+            column_name: column_name.into(),
+            data_type: "text".into(),
+            is_nullable: false,
+        }
+    }
+
+    #[test]
+    fn group_columns_by_table_and_attach_descriptions() {
+        let dictionary = data_dictionary(vec![
+            column("products", "id"),
+            column("products", "name"),
+            column("receipts", "merchant_name"),
+            column("receipts", "something_new"),
+        ]);
+        assert_eq!(dictionary.tables.len(), 2);
+        assert_eq!(
+            dictionary.tables[0].description,
+            Some("Distinct product names seen across all receipts")
+        );
+        let receipts = &dictionary.tables[1];
+        assert_eq!(receipts.columns.len(), 2);
+        assert!(receipts.columns[0].description.is_some());
+        assert!(receipts.columns[1].description.is_none());
+        assert_eq!(dictionary.export_columns.len(), 9);
+    }
+}
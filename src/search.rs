@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    pagination::{Listing, Page, PageParams},
+    AllData, AppError, AppState,
+};
+
+const MAX_QUERY_LENGTH: usize = 200;
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    /// Words to look for, in web search syntax: `"exact phrase"`, `or`, `-excluded`
+    q: String,
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    id: i32,
+    merchant_name: String,
+    paid_at: chrono::DateTime<chrono::Utc>,
+    /// Printed text around the matched words, which are wrapped in `**`
+    snippet: String,
+}
+
+pub fn search_query(raw: &str) -> Result<&str, AppError> {
+    let query = raw.trim();
+    if query.is_empty() {
+        return Err(AppError::bad_request("Search query must not be empty"));
+    }
+    if query.chars().count() > MAX_QUERY_LENGTH {
+        return Err(AppError::bad_request(format!(
+            "Search query must be at most {MAX_QUERY_LENGTH} characters long"
+        )));
+    }
+    Ok(query)
+}
+
+/// `ILIKE` pattern matching names that contain `query` literally, so `%` and `_` typed by users aren't wildcards.
+pub fn contains_pattern(query: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in query.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Finds receipts by any word printed on them, best matches first.
+pub async fn search(
+    State(app_state): State<Arc<AppState>>,
+    Query(page): Query<PageParams>,
+    Query(params): Query<SearchParams>,
+) -> Result<axum::Json<Listing<SearchHit>>, AppError> {
+    let query = search_query(&params.q)?;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM receipts WHERE receipt_text_search @@ websearch_to_tsquery('simple', $1)"#,
+        query
+    )
+    .fetch_one(&app_state.pool)
+    .await?;
+    if page.count_only {
+        return Ok(axum::Json(Listing::Count { total }));
+    }
+    let hits = sqlx::query_as!(
+        SearchHit,
+        r#"SELECT id, merchant_name, paid_at, ts_headline('simple', coalesce(receipt_text, ''), websearch_to_tsquery('simple', $1), 'StartSel=**, StopSel=**, MaxFragments=2') AS "snippet!" FROM receipts WHERE receipt_text_search @@ websearch_to_tsquery('simple', $1) ORDER BY ts_rank(receipt_text_search, websearch_to_tsquery('simple', $1)) DESC, paid_at DESC LIMIT $2 OFFSET $3"#,
+        query,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
+    Ok(axum::Json(Listing::Page(Page::new(hits, total, page))))
+}
+
+#[derive(Deserialize)]
+pub struct ProductSearchParams {
+    /// Part of the product name, matched case-insensitively
+    q: String,
+}
+
+/// Lists every purchase of products whose name contains the query, across merchants, newest first.
+pub async fn search_products(
+    State(app_state): State<Arc<AppState>>,
+    Query(page): Query<PageParams>,
+    Query(params): Query<ProductSearchParams>,
+) -> Result<axum::Json<Listing<AllData>>, AppError> {
+    let pattern = contains_pattern(search_query(&params.q)?);
+    let pool = &app_state.read_pool;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM prices JOIN products ON products.id = prices.product_id WHERE products.name ILIKE $1"#,
+        pattern
+    )
+    .fetch_one(pool)
+    .await?;
+    if page.count_only {
+        return Ok(axum::Json(Listing::Count { total }));
+    }
+    let data = sqlx::query_as!(
+        AllData,
+        "SELECT receipts.paid_at, receipts.merchant_name, prices.count, prices.quantity_unit, prices.unit_price, receipts.currency_code, receipts.items_match_total, receipts.category, products.name FROM receipts JOIN prices ON receipts.id = prices.receipt_id JOIN products ON products.id = prices.product_id WHERE products.name ILIKE $3 ORDER BY receipts.paid_at DESC, prices.receipt_id, prices.line_index LIMIT $1 OFFSET $2",
+        page.limit(),
+        page.offset(),
+        pattern
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(axum::Json(Listing::Page(Page::new(data, total, page))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contains_pattern, search_query};
+
+    #[test]
+    fn validate_search_queries() {
+        assert_eq!(search_query("  returret ").unwrap(), "returret");
+        assert!(search_query(" ").is_err());
+        assert!(search_query(&"a".repeat(201)).is_err());
+    }
+
+    #[test]
+    fn match_product_names_literally() {
+        assert_eq!(contains_pattern("mælk"), "%mælk%");
+        assert_eq!(contains_pattern("100%"), r"%100\%%");
+        assert_eq!(contains_pattern(r"a_b\c"), r"%a\_b\\c%");
+    }
+}
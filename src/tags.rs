@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use serde::Deserialize;
+
+use crate::{AppError, AppState};
+
+const MAX_TAG_LENGTH: usize = 50;
+
+#[derive(Deserialize)]
+pub struct AddTags {
+    tags: Vec<String>,
+}
+
+/// Tags are compared case-insensitively, so "Business" and "business " are the same tag.
+pub fn normalize_tag(raw: &str) -> Result<String, AppError> {
+    let tag = raw.trim().to_lowercase();
+    if tag.is_empty() {
+        return Err(AppError::bad_request("Tags must not be empty"));
+    }
+    if tag.chars().count() > MAX_TAG_LENGTH {
+        return Err(AppError::bad_request(format!(
+            "Tags must be at most {MAX_TAG_LENGTH} characters long"
+        )));
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+    {
+        return Err(AppError::bad_request(format!(
+            "Tag {tag:?} may only contain letters, digits, spaces, '-' and '_'"
+        )));
+    }
+    Ok(tag)
+}
+
+async fn ensure_receipt_exists(app_state: &AppState, receipt_id: i32) -> Result<(), AppError> {
+    sqlx::query_scalar!("SELECT id FROM receipts WHERE id = $1", receipt_id)
+        .fetch_optional(&app_state.pool)
+        .await?
+        .ok_or(AppError::not_found(format!(
+            "Receipt {receipt_id} does not exist"
+        )))?;
+    Ok(())
+}
+
+async fn receipt_tags(app_state: &AppState, receipt_id: i32) -> Result<Vec<String>, AppError> {
+    Ok(sqlx::query_scalar!(
+        "SELECT tag FROM receipt_tags WHERE receipt_id = $1 ORDER BY tag",
+        receipt_id
+    )
+    .fetch_all(&app_state.pool)
+    .await?)
+}
+
+/// Adds tags to a receipt, ignoring ones it already has. Responds with all of the receipt's tags.
+pub async fn add_tags(
+    State(app_state): State<Arc<AppState>>,
+    Path(receipt_id): Path<i32>,
+    axum::Json(body): axum::Json<AddTags>,
+) -> Result<axum::Json<Vec<String>>, AppError> {
+    let tags = body
+        .tags
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .collect::<Result<Vec<_>, _>>()?;
+    ensure_receipt_exists(&app_state, receipt_id).await?;
+    sqlx::query!(
+        "INSERT INTO receipt_tags(receipt_id, tag) SELECT $1, UNNEST($2::text[]) ON CONFLICT DO NOTHING",
+        receipt_id,
+        &tags
+    )
+    .execute(&app_state.pool)
+    .await?;
+    Ok(axum::Json(receipt_tags(&app_state, receipt_id).await?))
+}
+
+/// Responds with the receipt's remaining tags.
+pub async fn remove_tag(
+    State(app_state): State<Arc<AppState>>,
+    Path((receipt_id, tag)): Path<(i32, String)>,
+) -> Result<axum::Json<Vec<String>>, AppError> {
+    let tag = normalize_tag(&tag)?;
+    ensure_receipt_exists(&app_state, receipt_id).await?;
+    let removed = sqlx::query!(
+        "DELETE FROM receipt_tags WHERE receipt_id = $1 AND tag = $2",
+        receipt_id,
+        tag
+    )
+    .execute(&app_state.pool)
+    .await?
+    .rows_affected();
+    if removed == 0 {
+        return Err(AppError::not_found(format!(
+            "Receipt {receipt_id} is not tagged {tag:?}"
+        )));
+    }
+    Ok(axum::Json(receipt_tags(&app_state, receipt_id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_tag;
+
+    #[test]
+    fn normalize_tags_case_insensitively() {
+        assert_eq!(normalize_tag("  Business ").unwrap(), "business");
+        assert_eq!(normalize_tag("Ferie 2023").unwrap(), "ferie 2023");
+        assert!(normalize_tag("   ").is_err());
+        assert!(normalize_tag("a;drop").is_err());
+        assert!(normalize_tag(&"x".repeat(51)).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{extract::TaxLine, AppError, AppState};
+
+/// Tax charged at one rate; `net_amount` is `None` when it isn't known for every line at that rate.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RateBreakdown {
+    pub rate: Option<f64>,
+    pub net_amount: Option<f64>,
+    pub amount: f64,
+}
+
+/// Sums tax lines per rate, ordered by rate with unknown rates last.
+pub fn breakdown(lines: &[TaxLine]) -> Vec<RateBreakdown> {
+    lines
+        .iter()
+        .sorted_by(|a, b| match (a.rate, b.rate) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (a, b) => a.is_none().cmp(&b.is_none()),
+        })
+        .group_by(|line| line.rate)
+        .into_iter()
+        .map(|(rate, lines)| {
+            let lines = lines.collect::<Vec<_>>();
+            RateBreakdown {
+                rate,
+                net_amount: lines.iter().map(|line| line.net_amount).sum(),
+                amount: lines.iter().map(|line| line.amount).sum(),
+            }
+        })
+        .collect()
+}
+
+/// Per-rate tax breakdown of a single receipt.
+pub async fn receipt_taxes(
+    State(app_state): State<Arc<AppState>>,
+    Path(receipt_id): Path<i32>,
+) -> Result<axum::Json<Vec<RateBreakdown>>, AppError> {
+    sqlx::query_scalar!("SELECT id FROM receipts WHERE id = $1", receipt_id)
+        .fetch_optional(&app_state.pool)
+        .await?
+        .ok_or(AppError::not_found(format!(
+            "Receipt {receipt_id} does not exist"
+        )))?;
+    let lines = sqlx::query_as!(
+        TaxLine,
+        "SELECT rate, net_amount, amount, currency_code FROM tax_lines WHERE receipt_id = $1 ORDER BY id",
+        receipt_id
+    )
+    .fetch_all(&app_state.pool)
+    .await?;
+    Ok(axum::Json(breakdown(&lines)))
+}
+
+#[derive(Serialize)]
+pub struct RateStats {
+    pub currency_code: Option<String>,
+    pub rate: Option<f64>,
+    pub receipts: i64,
+    /// Sum over the lines where the net amount is known
+    pub net_amount: f64,
+    pub amount: f64,
+}
+
+/// Tax paid per currency and rate across all receipts, leaving out flagged duplicates.
+pub async fn tax_stats(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<axum::Json<Vec<RateStats>>, AppError> {
+    let stats = sqlx::query_as!(
+        RateStats,
+        r#"SELECT tax_lines.currency_code, tax_lines.rate, COUNT(DISTINCT receipts.id) AS "receipts!", COALESCE(SUM(tax_lines.net_amount), 0) AS "net_amount!", SUM(tax_lines.amount) AS "amount!" FROM tax_lines JOIN receipts ON receipts.id = tax_lines.receipt_id WHERE receipts.duplicate_of IS NULL GROUP BY tax_lines.currency_code, tax_lines.rate ORDER BY tax_lines.currency_code, tax_lines.rate"#
+    )
+    .fetch_all(&app_state.read_pool)
+    .await?;
+    Ok(axum::Json(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{breakdown, RateBreakdown};
+    use crate::extract::TaxLine;
+
+    #[test]
+    fn sum_tax_lines_per_rate() {
+        let line = |rate, net_amount, amount| TaxLine {
+            rate,
+            net_amount,
+            amount,
+            currency_code: None,
+        };
+        let lines = [
+            line(Some(25.0), Some(100.0), 25.0),
+            line(None, None, 1.0),
+            line(Some(0.0), Some(30.0), 0.0),
+            line(Some(25.0), Some(20.0), 5.0),
+        ];
+        assert_eq!(
+            breakdown(&lines),
+            vec![
+                RateBreakdown {
+                    rate: Some(0.0),
+                    net_amount: Some(30.0),
+                    amount: 0.0
+                },
+                RateBreakdown {
+                    rate: Some(25.0),
+                    net_amount: Some(120.0),
+                    amount: 30.0
+                },
+                RateBreakdown {
+                    rate: None,
+                    net_amount: None,
+                    amount: 1.0
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! Extension point for inspecting uploads before anything is sent to Azure, e.g. to scan public uploads for malware.
+
+use std::sync::Arc;
+
+use axum::body::Bytes;
+
+use crate::AppError;
+
+/// Inspects an uploaded file as it streams in; `Err` rejects the upload with the given reason.
+/// `chunk` starts at byte `offset` of the file, so a check never needs the whole file in memory.
+/// Checks run on a blocking thread, so they may do blocking IO like talking to a local virus scanner.
+pub trait UploadCheck: Send + Sync {
+    fn check(&self, offset: usize, chunk: &[u8]) -> Result<(), String>;
+}
+
+/// Accepts every upload; the default.
+pub struct AcceptAll;
+
+impl UploadCheck for AcceptAll {
+    fn check(&self, _offset: usize, _chunk: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Rejects files larger than the limit, for deployments wanting less than the request body limit.
+pub struct MaxFileSize(pub usize);
+
+impl UploadCheck for MaxFileSize {
+    fn check(&self, offset: usize, chunk: &[u8]) -> Result<(), String> {
+        if offset + chunk.len() > self.0 {
+            return Err(format!("file is larger than the {} bytes accepted", self.0));
+        }
+        Ok(())
+    }
+}
+
+pub async fn screen_chunk(
+    check: Arc<dyn UploadCheck>,
+    offset: usize,
+    chunk: Bytes,
+) -> Result<(), AppError> {
+    tokio::task::spawn_blocking(move || check.check(offset, &chunk))
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(|reason| AppError::bad_request(format!("Upload rejected: {reason}")))
+}
@@ -0,0 +1,79 @@
+//! Counts of uploads skipped as duplicates versus sent for analysis, showing how much Azure usage the cache saves.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::file_hash::FileHash;
+
+#[derive(Default)]
+pub struct UploadStats {
+    new_files: AtomicU64,
+    duplicates: AtomicU64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UploadCounts {
+    pub new_files: u64,
+    pub duplicates: u64,
+    /// Share of uploads that were duplicates; `None` before the first upload
+    pub duplicate_ratio: Option<f64>,
+}
+
+impl UploadStats {
+    /// Counts and logs the outcome of checking an upload against the cache.
+    pub fn record(&self, file_hash: &FileHash, is_duplicate: bool) {
+        if is_duplicate {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            tracing::info!(
+                dedup = "duplicate",
+                "File {} is already cached, skipping analysis",
+                file_hash
+            );
+        } else {
+            self.new_files.fetch_add(1, Ordering::Relaxed);
+            tracing::info!(
+                dedup = "new",
+                "File {} is not cached yet, analyzing it",
+                file_hash
+            );
+        }
+    }
+
+    pub fn counts(&self) -> UploadCounts {
+        let new_files = self.new_files.load(Ordering::Relaxed);
+        let duplicates = self.duplicates.load(Ordering::Relaxed);
+        let total = new_files + duplicates;
+        UploadCounts {
+            new_files,
+            duplicates,
+            duplicate_ratio: (total > 0).then(|| duplicates as f64 / total as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UploadCounts, UploadStats};
+    use crate::file_hash::FileHash;
+
+    #[test]
+    fn count_duplicate_and_new_uploads() {
+        let stats = UploadStats::default();
+        assert_eq!(stats.counts().duplicate_ratio, None);
+
+        let (a, b) = (FileHash::of(b"a"), FileHash::of(b"b"));
+        stats.record(&a, false);
+        stats.record(&a, true);
+        stats.record(&a, true);
+        stats.record(&b, false);
+        assert_eq!(
+            stats.counts(),
+            UploadCounts {
+                new_files: 2,
+                duplicates: 2,
+                duplicate_ratio: Some(0.5),
+            }
+        );
+    }
+}
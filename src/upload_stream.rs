@@ -0,0 +1,104 @@
+//! Uploads are screened, hashed and base64-encoded chunk by chunk, so the raw file is never buffered next to its encoding.
+
+use std::{io::Write, sync::Arc};
+
+use axum::body::Bytes;
+use base64::{engine::GeneralPurpose, prelude::BASE64_STANDARD, write::EncoderStringWriter};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    file_hash::FileHash,
+    file_type::{self, FileType},
+    upload_check::{self, UploadCheck},
+    AppError,
+};
+
+/// A fully received upload, ready to be sent for analysis.
+pub struct StreamedUpload {
+    /// The key of the file's cache entry
+    pub file_hash: FileHash,
+    pub base64: String,
+    pub file_type: FileType,
+}
+
+pub struct UploadStream {
+    check: Arc<dyn UploadCheck>,
+    hasher: Sha256,
+    encoder: EncoderStringWriter<'static, GeneralPurpose, String>,
+    /// Start of the file, to tell its type
+    head: Vec<u8>,
+    len: usize,
+}
+
+impl UploadStream {
+    pub fn new(check: Arc<dyn UploadCheck>) -> Self {
+        Self {
+            check,
+            hasher: Sha256::new(),
+            encoder: EncoderStringWriter::new(&BASE64_STANDARD),
+            head: Vec::with_capacity(file_type::SIGNATURE_LEN),
+            len: 0,
+        }
+    }
+
+    pub async fn push(&mut self, chunk: Bytes) -> Result<(), AppError> {
+        upload_check::screen_chunk(self.check.clone(), self.len, chunk.clone()).await?;
+        let missing = file_type::SIGNATURE_LEN - self.head.len();
+        self.head
+            .extend_from_slice(&chunk[..missing.min(chunk.len())]);
+        self.hasher.update(&chunk);
+        self.encoder
+            .write_all(&chunk)
+            .map_err(anyhow::Error::from)?;
+        self.len += chunk.len();
+        Ok(())
+    }
+
+    /// `content_type` is what the client declared the file to be, checked against what it actually is.
+    pub fn finish(self, content_type: Option<&str>) -> Result<StreamedUpload, AppError> {
+        if self.len == 0 {
+            return Err(AppError::bad_request("Submitted file is empty"));
+        }
+        let file_type = file_type::verify(&self.head, content_type)?;
+        Ok(StreamedUpload {
+            file_hash: FileHash::finish(self.hasher),
+            base64: self.encoder.into_inner(),
+            file_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use super::*;
+    use crate::upload_check::AcceptAll;
+
+    #[tokio::test]
+    async fn match_hash_and_encoding_of_the_whole_file() {
+        // 5 MB of bytes that don't repeat in step with the chunk or base64 block sizes
+        let file = b"%PDF-1.7\n"
+            .iter()
+            .copied()
+            .chain((0..5 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8))
+            .collect::<Vec<_>>();
+        let mut stream = UploadStream::new(Arc::new(AcceptAll));
+        for chunk in file.chunks(64 * 1024 + 1) {
+            stream.push(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        let upload = stream.finish(Some("application/pdf")).unwrap();
+        assert_eq!(upload.file_hash, FileHash::of(&file));
+        assert_eq!(upload.base64, BASE64_STANDARD.encode(&file));
+        assert_eq!(upload.file_type, FileType::Pdf);
+    }
+
+    #[tokio::test]
+    async fn tell_file_type_from_first_bytes_split_across_chunks() {
+        let mut stream = UploadStream::new(Arc::new(AcceptAll));
+        for chunk in [&b"\x89P"[..], b"NG", b"\r\n\x1A\n\0\0\0\rIHDR"] {
+            stream.push(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        assert_eq!(stream.finish(None).unwrap().file_type, FileType::Png);
+    }
+}
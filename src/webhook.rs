@@ -0,0 +1,146 @@
+//! Tells a configured URL when an analysis finishes, so clients don't have to poll for uploaded files.
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::{file_hash::FileHash, AppError, AppState};
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the first client token
+pub const SIGNATURE_HEADER: &str = "x-signature-256";
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Completion {
+    Processed {
+        file_hash: FileHash,
+        merchant_name: String,
+        total: Option<f64>,
+        item_count: i64,
+    },
+    Failed {
+        file_hash: FileHash,
+        error: String,
+    },
+}
+
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+async fn completion(
+    app_state: &AppState,
+    file_hash: &FileHash,
+    outcome: Result<(), AppError>,
+) -> Completion {
+    let failed = |error: String| Completion::Failed {
+        file_hash: file_hash.clone(),
+        error,
+    };
+    if let Err(err) = outcome {
+        return failed(err.public_message());
+    }
+    let receipt = sqlx::query!(
+        r#"SELECT merchant_name, total, (SELECT COUNT(*) FROM prices WHERE prices.receipt_id = receipts.id) AS "item_count!" FROM receipts WHERE file_sha256 = $1 OR id = (SELECT receipt_id FROM merged_files WHERE file_sha256 = $1)"#,
+        file_hash.as_str()
+    )
+    .fetch_optional(&app_state.pool)
+    .await;
+    match receipt {
+        Ok(Some(receipt)) => Completion::Processed {
+            file_hash: file_hash.clone(),
+            merchant_name: receipt.merchant_name,
+            total: receipt.total,
+            item_count: receipt.item_count,
+        },
+        Ok(None) => failed("The analyzed receipt was not stored".into()),
+        Err(err) => failed(AppError::from(err).public_message()),
+    }
+}
+
+/// Posts the outcome of processing a file's analysis to the webhook, if one is configured. Delivery failures are only logged.
+pub async fn notify(app_state: &AppState, file_hash: &FileHash, outcome: Result<(), AppError>) {
+    let Some(url) = &app_state.config.completion_webhook_url else {
+        return;
+    };
+    let body = match serde_json::to_vec(&completion(app_state, file_hash, outcome).await) {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!(
+                "Could not serialize completion of file {}: {}",
+                file_hash,
+                err
+            );
+            return;
+        }
+    };
+    let res = app_state
+        .client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(
+            SIGNATURE_HEADER,
+            signature(app_state.client_tokens.signing_key(), &body),
+        )
+        .body(body)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    if let Err(err) = res {
+        tracing::error!(
+            "Could not deliver completion of file {} to the webhook: {}",
+            file_hash,
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_body_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn tag_payload_with_outcome() {
+        let file_hash = FileHash::of(b"receipt");
+        assert_eq!(
+            serde_json::to_value(Completion::Processed {
+                file_hash: file_hash.clone(),
+                merchant_name: "Netto".into(),
+                total: Some(92.35),
+                item_count: 7,
+            })
+            .unwrap(),
+            serde_json::json!({
+                "status": "processed",
+                "file_hash": file_hash.as_str(),
+                "merchant_name": "Netto",
+                "total": 92.35,
+                "item_count": 7,
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Completion::Failed {
+                file_hash: file_hash.clone(),
+                error: "The receipt could not be analyzed".into(),
+            })
+            .unwrap(),
+            serde_json::json!({
+                "status": "failed",
+                "file_hash": file_hash.as_str(),
+                "error": "The receipt could not be analyzed",
+            })
+        );
+    }
+}